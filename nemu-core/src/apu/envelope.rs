@@ -0,0 +1,79 @@
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+pub(super) struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+
+    pub(super) volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub(super) fn new() -> Self {
+        Self {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.initial_volume);
+        w.bool(self.increase);
+        w.u8(self.period);
+        w.u8(self.volume);
+        w.u8(self.timer);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.initial_volume = r.u8()?;
+        self.increase = r.bool()?;
+        self.period = r.u8()?;
+        self.volume = r.u8()?;
+        self.timer = r.u8()?;
+        Ok(())
+    }
+
+    pub(super) fn read(&self) -> u8 {
+        (self.initial_volume << 4) | ((self.increase as u8) << 3) | self.period
+    }
+
+    #[inline(always)]
+    pub(super) fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = (value & 0x08) != 0;
+        self.period = value & 0x07;
+    }
+
+    /// DAC is powered as long as any of the upper 5 bits of NRx2 are set
+    #[inline(always)]
+    pub(super) fn dac_enabled(&self) -> bool {
+        (self.read() & 0xF8) != 0
+    }
+
+    pub(super) fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    pub(super) fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
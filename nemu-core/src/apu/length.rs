@@ -0,0 +1,67 @@
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+pub(super) struct LengthCounter {
+    max: u16,
+    counter: u16,
+    pub(super) enabled: bool,
+}
+
+impl LengthCounter {
+    pub(super) fn new(max: u16) -> Self {
+        Self {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.counter);
+        w.bool(self.enabled);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.counter = r.u16()?;
+        self.enabled = r.bool()?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub(super) fn load(&mut self, value: u8) {
+        self.counter = self.max - (value as u16 & (self.max - 1));
+    }
+
+    /// Returns true if the counter just ran out and the channel should be disabled
+    pub(super) fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    /// Handles an NRx4 write. `length_step_next` is true when the next frame sequencer step
+    /// clocks length, in which case enabling the counter does not get the extra clock.
+    /// Returns true if the channel should be disabled.
+    pub(super) fn write_nrx4(&mut self, enable: bool, trigger: bool, length_step_next: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enable;
+        let mut disable = false;
+
+        // enabling the counter in the first half of a length period clocks it once more
+        if !was_enabled && enable && !length_step_next && self.counter > 0 {
+            self.counter -= 1;
+            disable = self.counter == 0 && !trigger;
+        }
+
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if enable && !length_step_next {
+                self.counter -= 1;
+            }
+        }
+
+        disable
+    }
+}
@@ -0,0 +1,289 @@
+mod envelope;
+mod length;
+mod noise;
+mod pulse;
+mod wave;
+
+use noise::NoiseChannel;
+use pulse::PulseChannel;
+use wave::WaveChannel;
+
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+const CPU_CLOCK_HZ: u32 = 4_194_304;
+
+// the frame sequencer is clocked by the falling edge of DIV bit 4 (bit 12 of the internal counter)
+const FRAME_SEQUENCER_DIV_BIT: u16 = 1 << 12;
+
+pub(crate) struct Apu {
+    enabled: bool,
+    nr50: u8,
+    nr51: u8,
+
+    ch1: PulseChannel,
+    ch2: PulseChannel,
+    ch3: WaveChannel,
+    ch4: NoiseChannel,
+
+    frame_step: u8,
+    prev_div_bit: bool,
+
+    sample_rate: u32,
+    sample_counter: u32,
+    capacitor: (f32, f32),
+    charge_factor: f32,
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub(crate) fn new() -> Self {
+        Self {
+            enabled: false,
+            nr50: 0,
+            nr51: 0,
+            ch1: PulseChannel::new(true),
+            ch2: PulseChannel::new(false),
+            ch3: WaveChannel::new(),
+            ch4: NoiseChannel::new(),
+            frame_step: 0,
+            prev_div_bit: false,
+            sample_rate: 0,
+            sample_counter: 0,
+            capacitor: (0.0, 0.0),
+            charge_factor: 1.0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        let sample_rate = self.sample_rate;
+        *self = Self::new();
+        self.set_sample_rate(sample_rate);
+    }
+
+    /// A sample rate of 0 disables sample generation entirely
+    pub(crate) fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_counter = 0;
+        self.samples.clear();
+
+        if sample_rate != 0 {
+            self.charge_factor = 0.999958_f32.powf(CPU_CLOCK_HZ as f32 / sample_rate as f32);
+        }
+    }
+
+    /// Interleaved stereo (left, right) samples generated since the last drain
+    pub(crate) fn drain_samples(&mut self) -> std::vec::Drain<'_, f32> {
+        self.samples.drain(..)
+    }
+
+    /// Only the emulated hardware is saved, the host side sample output carries on as it is
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.nr50);
+        w.u8(self.nr51);
+
+        self.ch1.save_state(w);
+        self.ch2.save_state(w);
+        self.ch3.save_state(w);
+        self.ch4.save_state(w);
+
+        w.u8(self.frame_step);
+        w.bool(self.prev_div_bit);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.enabled = r.bool()?;
+        self.nr50 = r.u8()?;
+        self.nr51 = r.u8()?;
+
+        self.ch1.load_state(r)?;
+        self.ch2.load_state(r)?;
+        self.ch3.load_state(r)?;
+        self.ch4.load_state(r)?;
+
+        self.frame_step = r.u8()?;
+        self.prev_div_bit = r.bool()?;
+        Ok(())
+    }
+
+    pub(crate) fn update(&mut self, cycles: u8, div: u16) {
+        let div_bit = (div & FRAME_SEQUENCER_DIV_BIT) != 0;
+        if self.prev_div_bit && !div_bit && self.enabled {
+            self.clock_frame_sequencer();
+        }
+        self.prev_div_bit = div_bit;
+
+        for _ in 0..cycles {
+            if self.enabled {
+                self.ch1.step(4);
+                self.ch2.step(4);
+                self.ch3.step(4);
+                self.ch4.step(4);
+            }
+
+            if self.sample_rate != 0 {
+                self.sample_counter += self.sample_rate * 4;
+                if self.sample_counter >= CPU_CLOCK_HZ {
+                    self.sample_counter -= CPU_CLOCK_HZ;
+                    self.push_sample();
+                }
+            }
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        match self.frame_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.ch1.clock_sweep();
+            }
+            7 => {
+                self.ch1.clock_envelope();
+                self.ch2.clock_envelope();
+                self.ch4.clock_envelope();
+            }
+            _ => {}
+        }
+
+        self.frame_step = (self.frame_step + 1) & 0x07;
+    }
+
+    fn clock_lengths(&mut self) {
+        self.ch1.clock_length();
+        self.ch2.clock_length();
+        self.ch3.clock_length();
+        self.ch4.clock_length();
+    }
+
+    fn push_sample(&mut self) {
+        let (left, right) = self.mix();
+
+        let (cap_l, cap_r) = &mut self.capacitor;
+        let out_l = left - *cap_l;
+        let out_r = right - *cap_r;
+        *cap_l = left - out_l * self.charge_factor;
+        *cap_r = right - out_r * self.charge_factor;
+
+        self.samples.push(out_l);
+        self.samples.push(out_r);
+    }
+
+    fn mix(&self) -> (f32, f32) {
+        if !self.enabled {
+            return (0.0, 0.0);
+        }
+
+        let dac = |output: u8, dac_enabled: bool| {
+            if dac_enabled { output as f32 / 7.5 - 1.0 } else { 0.0 }
+        };
+
+        let channels = [
+            dac(self.ch1.output(), self.ch1.dac_enabled()),
+            dac(self.ch2.output(), self.ch2.dac_enabled()),
+            dac(self.ch3.output(), self.ch3.dac_enabled()),
+            dac(self.ch4.output(), self.ch4.dac_enabled()),
+        ];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+
+        for (i, sample) in channels.iter().enumerate() {
+            if (self.nr51 & (0x10 << i)) != 0 {
+                left += sample;
+            }
+            if (self.nr51 & (0x01 << i)) != 0 {
+                right += sample;
+            }
+        }
+
+        let left_volume = (((self.nr50 >> 4) & 0x07) + 1) as f32 / 8.0;
+        let right_volume = ((self.nr50 & 0x07) + 1) as f32 / 8.0;
+
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+
+    /// True when the next frame sequencer step clocks the length counters
+    #[inline(always)]
+    fn length_step_next(&self) -> bool {
+        (self.frame_step & 0x01) == 0
+    }
+
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF10..=0xFF14 => self.ch1.read(addr - 0xFF10),
+            0xFF15..=0xFF19 => self.ch2.read(addr - 0xFF15),
+            0xFF1A..=0xFF1E => self.ch3.read(addr - 0xFF1A),
+            0xFF1F..=0xFF23 => self.ch4.read(addr - 0xFF1F),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                ((self.enabled as u8) << 7)
+                    | 0x70
+                    | ((self.ch4.enabled as u8) << 3)
+                    | ((self.ch3.enabled as u8) << 2)
+                    | ((self.ch2.enabled as u8) << 1)
+                    | (self.ch1.enabled as u8)
+            }
+            0xFF30..=0xFF3F => self.ch3.read_wave_ram((addr - 0xFF30) as usize),
+            _ => 0xFF,
+        }
+    }
+
+    pub(crate) fn write(&mut self, addr: u16, value: u8) {
+        if addr == 0xFF26 {
+            self.set_power((value & 0x80) != 0);
+            return;
+        }
+
+        if let 0xFF30..=0xFF3F = addr {
+            self.ch3.write_wave_ram((addr - 0xFF30) as usize, value);
+            return;
+        }
+
+        if !self.enabled {
+            // on the DMG the length counters stay writable while the APU is powered off
+            match addr {
+                0xFF11 => self.ch1.length.load(value & 0x3F),
+                0xFF16 => self.ch2.length.load(value & 0x3F),
+                0xFF1B => self.ch3.length.load(value),
+                0xFF20 => self.ch4.length.load(value & 0x3F),
+                _ => {}
+            }
+            return;
+        }
+
+        let length_step_next = self.length_step_next();
+
+        match addr {
+            0xFF10..=0xFF14 => self.ch1.write(addr - 0xFF10, value, length_step_next),
+            0xFF15..=0xFF19 => self.ch2.write(addr - 0xFF15, value, length_step_next),
+            0xFF1A..=0xFF1E => self.ch3.write(addr - 0xFF1A, value, length_step_next),
+            0xFF1F..=0xFF23 => self.ch4.write(addr - 0xFF1F, value, length_step_next),
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+            _ => {}
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if self.enabled && !on {
+            self.ch1.power_off();
+            self.ch2.power_off();
+            self.ch3.power_off();
+            self.ch4.power_off();
+            self.nr50 = 0;
+            self.nr51 = 0;
+        }
+
+        if !self.enabled && on {
+            self.frame_step = 0;
+            self.ch1.reset_duty();
+            self.ch2.reset_duty();
+        }
+
+        self.enabled = on;
+    }
+}
@@ -0,0 +1,155 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+const DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub(super) struct NoiseChannel {
+    pub(super) length: LengthCounter,
+    envelope: Envelope,
+
+    pub(super) enabled: bool,
+    clock_shift: u8,
+    width_mode: bool,
+    divisor_code: u8,
+    timer: i32,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    pub(super) fn new() -> Self {
+        Self {
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            enabled: false,
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+
+        w.bool(self.enabled);
+        w.u8(self.clock_shift);
+        w.bool(self.width_mode);
+        w.u8(self.divisor_code);
+        w.i32(self.timer);
+        w.u16(self.lfsr);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+
+        self.enabled = r.bool()?;
+        self.clock_shift = r.u8()?;
+        self.width_mode = r.bool()?;
+        self.divisor_code = r.u8()?;
+        self.timer = r.i32()?;
+        self.lfsr = r.u16()?;
+        Ok(())
+    }
+
+    pub(super) fn read(&self, offset: u16) -> u8 {
+        match offset {
+            0 | 1 => 0xFF,
+            2 => self.envelope.read(),
+            3 => (self.clock_shift << 4) | ((self.width_mode as u8) << 3) | self.divisor_code,
+            4 => ((self.length.enabled as u8) << 6) | 0xBF,
+            _ => 0xFF,
+        }
+    }
+
+    pub(super) fn write(&mut self, offset: u16, value: u8, length_step_next: bool) {
+        match offset {
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.width_mode = (value & 0x08) != 0;
+                self.divisor_code = value & 0x07;
+            }
+            4 => {
+                let trigger = (value & 0x80) != 0;
+
+                if self.length.write_nrx4((value & 0x40) != 0, trigger, length_step_next) {
+                    self.enabled = false;
+                }
+
+                if trigger {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7FFF;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub(super) fn power_off(&mut self) {
+        let length = std::mem::replace(&mut self.length, LengthCounter::new(64));
+        *self = Self::new();
+        self.length = length;
+        self.length.enabled = false;
+    }
+
+    #[inline(always)]
+    fn period(&self) -> i32 {
+        DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    #[inline(always)]
+    pub(super) fn step(&mut self, t_cycles: i32) {
+        self.timer -= t_cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+
+            // shift codes 14 and 15 stop the LFSR from being clocked at all
+            if self.clock_shift >= 14 {
+                continue;
+            }
+
+            let xor = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+            }
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    #[inline(always)]
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    #[inline(always)]
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        (!self.lfsr & 0x01) as u8 * self.envelope.volume
+    }
+}
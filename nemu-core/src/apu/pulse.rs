@@ -0,0 +1,278 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+    negate_used: bool,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Self {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            shadow: 0,
+            enabled: false,
+            negate_used: false,
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.period);
+        w.bool(self.negate);
+        w.u8(self.shift);
+        w.u8(self.timer);
+        w.u16(self.shadow);
+        w.bool(self.enabled);
+        w.bool(self.negate_used);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.period = r.u8()?;
+        self.negate = r.bool()?;
+        self.shift = r.u8()?;
+        self.timer = r.u8()?;
+        self.shadow = r.u16()?;
+        self.enabled = r.bool()?;
+        self.negate_used = r.bool()?;
+        Ok(())
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+
+        if self.negate {
+            self.negate_used = true;
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+pub(super) struct PulseChannel {
+    sweep: Option<Sweep>,
+    pub(super) length: LengthCounter,
+    envelope: Envelope,
+
+    pub(super) enabled: bool,
+    duty: u8,
+    duty_pos: u8,
+    frequency: u16,
+    timer: i32,
+}
+
+impl PulseChannel {
+    pub(super) fn new(with_sweep: bool) -> Self {
+        Self {
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            enabled: false,
+            duty: 0,
+            duty_pos: 0,
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(w);
+        }
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+
+        w.bool(self.enabled);
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        w.u16(self.frequency);
+        w.i32(self.timer);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(r)?;
+        }
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+
+        self.enabled = r.bool()?;
+        self.duty = r.u8()?;
+        self.duty_pos = r.u8()?;
+        self.frequency = r.u16()?;
+        self.timer = r.i32()?;
+        Ok(())
+    }
+
+    /// Offset is 0 for NRx0 through 4 for NRx4
+    pub(super) fn read(&self, offset: u16) -> u8 {
+        match offset {
+            0 => match &self.sweep {
+                Some(sweep) => 0x80 | (sweep.period << 4) | ((sweep.negate as u8) << 3) | sweep.shift,
+                None => 0xFF,
+            },
+            1 => (self.duty << 6) | 0x3F,
+            2 => self.envelope.read(),
+            3 => 0xFF,
+            4 => ((self.length.enabled as u8) << 6) | 0xBF,
+            _ => 0xFF,
+        }
+    }
+
+    pub(super) fn write(&mut self, offset: u16, value: u8, length_step_next: bool) {
+        match offset {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.period = (value >> 4) & 0x07;
+                    sweep.negate = (value & 0x08) != 0;
+                    sweep.shift = value & 0x07;
+
+                    // leaving negate mode after a negate calculation disables the channel
+                    if !sweep.negate && sweep.negate_used {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | (((value & 0x07) as u16) << 8);
+                let trigger = (value & 0x80) != 0;
+
+                if self.length.write_nrx4((value & 0x40) != 0, trigger, length_step_next) {
+                    self.enabled = false;
+                }
+
+                if trigger {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Only the length counter survives an APU power cycle on the DMG
+    pub(super) fn power_off(&mut self) {
+        let length = std::mem::replace(&mut self.length, LengthCounter::new(64));
+        *self = Self::new(self.sweep.is_some());
+        self.length = length;
+        self.length.enabled = false;
+    }
+
+    pub(super) fn reset_duty(&mut self) {
+        self.duty_pos = 0;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        let frequency = self.frequency;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = frequency;
+            sweep.negate_used = false;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+
+            if sweep.shift != 0 && sweep.calculate() > 0x7FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    #[inline(always)]
+    pub(super) fn step(&mut self, t_cycles: i32) {
+        self.timer -= t_cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_pos = (self.duty_pos + 1) & 0x07;
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub(super) fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else { return };
+
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        let new_freq = sweep.calculate();
+        if new_freq > 0x7FF {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow = new_freq;
+            self.frequency = new_freq;
+
+            // second overflow check with the new frequency, result is discarded
+            if sweep.calculate() > 0x7FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    #[inline(always)]
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_TABLE[self.duty as usize][self.duty_pos as usize] * self.envelope.volume
+    }
+}
@@ -0,0 +1,162 @@
+use super::length::LengthCounter;
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+pub(super) struct WaveChannel {
+    pub(super) length: LengthCounter,
+    pub(super) wave_ram: [u8; 0x10],
+
+    pub(super) enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: i32,
+    position: u8,
+    sample_buffer: u8,
+}
+
+impl WaveChannel {
+    pub(super) fn new() -> Self {
+        Self {
+            length: LengthCounter::new(256),
+            wave_ram: [0; 0x10],
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+        }
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        self.length.save_state(w);
+        w.bytes(&self.wave_ram);
+
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        w.u8(self.volume_code);
+        w.u16(self.frequency);
+        w.i32(self.timer);
+        w.u8(self.position);
+        w.u8(self.sample_buffer);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.length.load_state(r)?;
+        r.bytes(&mut self.wave_ram)?;
+
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.volume_code = r.u8()?;
+        self.frequency = r.u16()?;
+        self.timer = r.i32()?;
+        self.position = r.u8()?;
+        self.sample_buffer = r.u8()?;
+        Ok(())
+    }
+
+    pub(super) fn read(&self, offset: u16) -> u8 {
+        match offset {
+            0 => ((self.dac_enabled as u8) << 7) | 0x7F,
+            1 => 0xFF,
+            2 => (self.volume_code << 5) | 0x9F,
+            3 => 0xFF,
+            4 => ((self.length.enabled as u8) << 6) | 0xBF,
+            _ => 0xFF,
+        }
+    }
+
+    pub(super) fn write(&mut self, offset: u16, value: u8, length_step_next: bool) {
+        match offset {
+            0 => {
+                self.dac_enabled = (value & 0x80) != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | (((value & 0x07) as u16) << 8);
+                let trigger = (value & 0x80) != 0;
+
+                if self.length.write_nrx4((value & 0x40) != 0, trigger, length_step_next) {
+                    self.enabled = false;
+                }
+
+                if trigger {
+                    self.enabled = self.dac_enabled;
+                    // the first sample is fetched after an extra 6 cycle delay
+                    self.timer = self.period() + 6;
+                    self.position = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// While the channel is playing, wave RAM accesses go to the byte currently being played
+    pub(super) fn read_wave_ram(&self, index: usize) -> u8 {
+        if self.enabled {
+            self.wave_ram[(self.position / 2) as usize]
+        } else {
+            self.wave_ram[index]
+        }
+    }
+
+    pub(super) fn write_wave_ram(&mut self, index: usize, value: u8) {
+        if self.enabled {
+            self.wave_ram[(self.position / 2) as usize] = value;
+        } else {
+            self.wave_ram[index] = value;
+        }
+    }
+
+    pub(super) fn power_off(&mut self) {
+        let length = std::mem::replace(&mut self.length, LengthCounter::new(256));
+        let wave_ram = self.wave_ram;
+        *self = Self::new();
+        self.length = length;
+        self.length.enabled = false;
+        self.wave_ram = wave_ram;
+    }
+
+    #[inline(always)]
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    #[inline(always)]
+    pub(super) fn step(&mut self, t_cycles: i32) {
+        self.timer -= t_cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) & 0x1F;
+
+            let byte = self.wave_ram[(self.position / 2) as usize];
+            self.sample_buffer = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    #[inline(always)]
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    #[inline(always)]
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled || self.volume_code == 0 {
+            return 0;
+        }
+        self.sample_buffer >> (self.volume_code - 1)
+    }
+}
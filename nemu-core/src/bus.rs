@@ -1,63 +1,299 @@
-use crate::ppu::Ppu;
+use crate::apu::Apu;
+use crate::interrupts::INT_VBLANK;
+use crate::ppu::{OamCorruption, Ppu};
 use crate::timer::Timer;
 use crate::joypad::Joypad;
 use crate::mbc::MbcType;
+use crate::serial::Serial;
+use crate::state::{StateReader, StateWriter};
+use crate::NemuError;
 
 const BOOT_ROM: &[u8; 0x100] = include_bytes!("../bootrom/build/dmg_boot.bin");
 
 pub(crate) struct Bus {
     pub(crate) mbc: MbcType,
-    pub(crate) wram: [u8; 0x2000],      // 8KB Work RAM
+    pub(crate) wram: [u8; 0x8000],      // 32KB Work RAM, DMG only uses the first 8KB
     pub(crate) io: [u8; 0x80],          // I/O Registers
     pub(crate) hram: [u8; 0x7F],        // High RAM
     pub(crate) ie: u8,                  // Interrupt Enable Register
     pub(crate) timer: Timer,
     pub(crate) ppu: Ppu,
+    pub(crate) apu: Apu,
     pub(crate) joypad: Joypad,
+    pub(crate) serial: Serial,
     pub(crate) boot_rom_enabled: bool,
 
-    #[cfg(test)]
-    pub(crate) serial_output: String,
+    // CGB
+    pub(crate) cgb: bool,
+    wram_bank: u8,
+    pub(crate) double_speed: bool,
+    speed_switch_armed: bool,
+    // double speed runs the PPU and APU on every other M-cycle, this holds the odd one
+    speed_carry: u8,
+    hdma_src: u16,
+    hdma_dst: u16,
+    hdma_remaining: u8,
+    hdma_active: bool,
+
+    // OAM DMA, one byte per M-cycle. A new transfer waits out its startup cycle in `dma_start`
+    // while the old one keeps going.
+    dma_source: u16,
+    dma_index: u8, // next byte to copy, 0xA0 when nothing is running
+    dma_byte: u8,  // last byte the DMA read, which is what conflicting CPU reads see
+    dma_start: Option<(u16, u8)>,
 }
 
 impl Bus {
     pub(crate) fn new() -> Self {
         Self {
             mbc: MbcType::default(),
-            wram: [0; 0x2000],
+            wram: [0; 0x8000],
             io: [0; 0x80],
             hram: [0; 0x7F],
             ie: 0,
             timer: Timer::new(),
             ppu: Ppu::new(),
+            apu: Apu::new(),
             joypad: Joypad::new(),
+            serial: Serial::new(),
             boot_rom_enabled: true,
-
-            #[cfg(test)]
-            serial_output: String::new(),
+            cgb: false,
+            wram_bank: 1,
+            double_speed: false,
+            speed_switch_armed: false,
+            speed_carry: 0,
+            hdma_src: 0,
+            hdma_dst: 0,
+            hdma_remaining: 0x7F,
+            hdma_active: false,
+            dma_source: 0,
+            dma_index: 0xA0,
+            dma_byte: 0xFF,
+            dma_start: None,
         }
     }
 
+    /// Only changed together with a reset, the mode stays across resets like the console itself
+    pub(crate) fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        self.ppu.set_cgb(cgb);
+    }
+
     pub(crate) fn reset(&mut self) {
-        self.wram = [0; 0x2000];
+        self.wram = [0; 0x8000];
         self.io = [0; 0x80];
         self.hram = [0; 0x7F];
         self.ie = 0;
         self.boot_rom_enabled = true;
+        self.wram_bank = 1;
+        self.double_speed = false;
+        self.speed_switch_armed = false;
+        self.speed_carry = 0;
+        self.hdma_src = 0;
+        self.hdma_dst = 0;
+        self.hdma_remaining = 0x7F;
+        self.hdma_active = false;
+        self.dma_source = 0;
+        self.dma_index = 0xA0;
+        self.dma_byte = 0xFF;
+        self.dma_start = None;
 
         self.timer.reset();
         self.ppu.reset();
+        self.apu.reset();
+        self.joypad.reset();
+        self.serial.reset();
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.wram);
+        w.bytes(&self.io);
+        w.bytes(&self.hram);
+        w.u8(self.ie);
+        w.bool(self.boot_rom_enabled);
+
+        w.bool(self.cgb);
+        w.u8(self.wram_bank);
+        w.bool(self.double_speed);
+        w.bool(self.speed_switch_armed);
+        w.u8(self.speed_carry);
+        w.u16(self.hdma_src);
+        w.u16(self.hdma_dst);
+        w.u8(self.hdma_remaining);
+        w.bool(self.hdma_active);
+
+        w.u16(self.dma_source);
+        w.u8(self.dma_index);
+        w.u8(self.dma_byte);
+        w.bool(self.dma_start.is_some());
+        let (start_source, start_delay) = self.dma_start.unwrap_or_default();
+        w.u16(start_source);
+        w.u8(start_delay);
+
+        self.timer.save_state(w);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        self.joypad.save_state(w);
+        self.serial.save_state(w);
+        self.mbc.save_state(w);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        // version 1 states predate CGB support and only have the 8KB of DMG WRAM
+        if r.version() < 2 {
+            r.bytes(&mut self.wram[..0x2000])?;
+            self.wram[0x2000..].fill(0);
+        } else {
+            r.bytes(&mut self.wram)?;
+        }
+
+        r.bytes(&mut self.io)?;
+        r.bytes(&mut self.hram)?;
+        self.ie = r.u8()?;
+        self.boot_rom_enabled = r.bool()?;
+
+        if r.version() < 2 {
+            self.cgb = false;
+            self.wram_bank = 1;
+            self.double_speed = false;
+            self.speed_switch_armed = false;
+            self.speed_carry = 0;
+            self.hdma_active = false;
+            self.hdma_remaining = 0x7F;
+        } else {
+            self.cgb = r.bool()?;
+            self.wram_bank = (r.u8()? & 0x07).max(1);
+            self.double_speed = r.bool()?;
+            self.speed_switch_armed = r.bool()?;
+            self.speed_carry = r.u8()? & 0x01;
+            self.hdma_src = r.u16()?;
+            self.hdma_dst = r.u16()? & 0x1FF0;
+            self.hdma_remaining = r.u8()? & 0x7F;
+            self.hdma_active = r.bool()?;
+        }
+
+        // version 6 states keep a running OAM DMA, older ones finished it on the spot
+        if r.version() < 6 {
+            self.dma_index = 0xA0;
+            self.dma_byte = 0xFF;
+            self.dma_start = None;
+        } else {
+            self.dma_source = r.u16()?;
+            self.dma_index = r.u8()?.min(0xA0);
+            self.dma_byte = r.u8()?;
+            let pending = r.bool()?;
+            let start = (r.u16()?, r.u8()?);
+            self.dma_start = pending.then_some(start);
+        }
 
-        #[cfg(test)]
-        self.serial_output.clear();
+        self.timer.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.serial.load_state(r)?;
+        self.mbc.load_state(r)
     }
 
     pub(crate) fn tick(&mut self, cycles: u8) {
-        let ppu_irq_mask = self.ppu.update(cycles);
+        // `cycles` are CPU M-cycles, in double speed the rest of the system sees half as many
+        let (slow_cycles, div) = if self.double_speed {
+            let total = cycles + self.speed_carry;
+            self.speed_carry = total & 0x01;
+            // the frame sequencer watches one DIV bit higher to keep its rate
+            (total >> 1, self.timer.div() >> 1)
+        } else {
+            (cycles, self.timer.div())
+        };
+
+        if self.dma_start.is_some() || self.dma_index < 0xA0 {
+            for _ in 0..cycles {
+                self.dma_step();
+            }
+        }
+
+        let ppu_irq_mask = self.ppu.update(slow_cycles);
         let timer_irq_mask = self.timer.update(cycles);
+        self.apu.update(slow_cycles, div);
+        let serial_irq_mask = self.serial.update(self.timer.div());
         let joypad_irq_mask = self.joypad.poll_interrupt();
+        self.mbc.tick(slow_cycles);
 
-        self.io[0x0F] |= ppu_irq_mask | timer_irq_mask | joypad_irq_mask;
+        self.io[0x0F] |= ppu_irq_mask | timer_irq_mask | serial_irq_mask | joypad_irq_mask;
+
+        if (ppu_irq_mask & INT_VBLANK) != 0 && let Some(sgb) = &mut self.joypad.sgb {
+            sgb.vblank(self.ppu.bg_tile_data(), &self.ppu.framebuffer);
+        }
+
+        if self.ppu.hblank_started {
+            self.ppu.hblank_started = false;
+
+            if self.hdma_active {
+                self.hdma_block();
+            }
+        }
+    }
+
+    /// STOP with KEY1 bit 0 set switches CPU speed on CGB, returns whether it did
+    pub(crate) fn try_switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        self.speed_carry = 0;
+        // STOP resets DIV
+        self.timer.write(0xFF04, 0);
+        true
+    }
+
+    #[inline(always)]
+    fn wram_index(&self, addr: u16) -> usize {
+        let offset = (addr & 0x1FFF) as usize; // covers echo RAM too
+        if offset < 0x1000 {
+            offset
+        } else {
+            self.wram_bank as usize * 0x1000 + (offset - 0x1000)
+        }
+    }
+
+    /// Copies one 16 byte HDMA block into VRAM, the CPU is stalled while it runs
+    fn hdma_block(&mut self) {
+        for i in 0..0x10 {
+            let data = self.peek(self.hdma_src.wrapping_add(i));
+            self.ppu.write(0x8000 | ((self.hdma_dst + i) & 0x1FFF), data);
+        }
+
+        self.hdma_src = self.hdma_src.wrapping_add(0x10);
+        self.hdma_dst = (self.hdma_dst + 0x10) & 0x1FF0;
+        self.hdma_remaining = self.hdma_remaining.wrapping_sub(1) & 0x7F;
+        if self.hdma_remaining == 0x7F {
+            self.hdma_active = false;
+        }
+
+        self.tick(if self.double_speed { 16 } else { 8 });
+    }
+
+    fn write_hdma5(&mut self, value: u8) {
+        // writing with bit 7 clear during an HBlank transfer cancels it
+        if self.hdma_active && (value & 0x80) == 0 {
+            self.hdma_active = false;
+            return;
+        }
+
+        self.hdma_remaining = value & 0x7F;
+
+        if (value & 0x80) != 0 {
+            self.hdma_active = true;
+        } else {
+            // general purpose DMA copies everything at once
+            loop {
+                self.hdma_block();
+                if self.hdma_remaining == 0x7F {
+                    break;
+                }
+            }
+        }
     }
 
     #[inline(always)]
@@ -72,14 +308,21 @@ impl Bus {
             0x0000..=0x7FFF => self.mbc.read(addr),
             0x8000..=0x9FFF => self.ppu.read(addr),
             0xA000..=0xBFFF => self.mbc.read(addr),
-            0xC000..=0xDFFF => unsafe { *self.wram.get_unchecked((addr - 0xC000) as usize) },
-            0xE000..=0xFDFF => unsafe { *self.wram.get_unchecked((addr - 0xE000) as usize) }, // Echo RAM
+            0xC000..=0xFDFF => unsafe { *self.wram.get_unchecked(self.wram_index(addr)) }, // including echo RAM
             0xFE00..=0xFE9F => self.ppu.read(addr),
             0xFEA0..=0xFEFF => 0, // unusable
             0xFF00 => self.joypad.read(),
+            0xFF01..=0xFF02 => self.serial.read(addr),
             0xFF04..=0xFF07 => self.timer.read(addr),
+            0xFF10..=0xFF3F => self.apu.read(addr),
             0xFF40..=0xFF45 => self.ppu.read(addr),
             0xFF47..=0xFF4B => self.ppu.read(addr),
+            0xFF4D if self.cgb => 0x7E | ((self.double_speed as u8) << 7) | self.speed_switch_armed as u8,
+            0xFF4F if self.cgb => self.ppu.read(addr),
+            0xFF51..=0xFF54 if self.cgb => 0xFF,
+            0xFF55 if self.cgb => ((!self.hdma_active as u8) << 7) | self.hdma_remaining,
+            0xFF68..=0xFF6B if self.cgb => self.ppu.read(addr),
+            0xFF70 if self.cgb => 0xF8 | self.wram_bank,
             0xFF80..=0xFFFE => unsafe { *self.hram.get_unchecked((addr - 0xFF80) as usize) },
             0xFFFF => self.ie,
             _ => unsafe { *self.io.get_unchecked((addr - 0xFF00) as usize) }, // Fallback for unimplemented I/O
@@ -88,38 +331,100 @@ impl Bus {
 
     #[inline(always)]
     pub(crate) fn read(&mut self, addr: u16) -> u8 {
+        self.read_corrupting(addr, OamCorruption::Read)
+    }
+
+    /// Read where the IDU increments or decrements the same address in that M-cycle, as in POP
+    /// or LD A, (HL+)
+    #[inline(always)]
+    pub(crate) fn read_idu(&mut self, addr: u16) -> u8 {
+        self.read_corrupting(addr, OamCorruption::ReadIdu)
+    }
+
+    #[inline(always)]
+    pub(crate) fn read_u16_idu(&mut self, addr: u16) -> u16 {
+        let low = self.read_idu(addr) as u16;
+        let high = self.read_idu(addr.wrapping_add(1)) as u16;
+        (high << 8) | low
+    }
+
+    /// Internal M-cycle in which the IDU increments or decrements `addr`, as in INC rr or the
+    /// first cycle of a push
+    #[inline(always)]
+    pub(crate) fn tick_idu(&mut self, addr: u16) {
+        if (0xFE00..=0xFEFF).contains(&addr) {
+            self.ppu.corrupt_oam(OamCorruption::Write);
+        }
         self.tick(1);
+    }
+
+    #[inline(always)]
+    fn read_corrupting(&mut self, addr: u16, corruption: OamCorruption) -> u8 {
+        // the DMG OAM bug, any access to 0xFE00-0xFEFF during the OAM scan garbles a row
+        if (0xFE00..=0xFEFF).contains(&addr) {
+            self.ppu.corrupt_oam(corruption);
+        }
+
+        self.tick(1);
+
+        if self.dma_index < 0xA0 {
+            match addr {
+                0xFE00..=0xFEFF => return 0xFF,
+                _ if self.dma_conflict(addr) => return self.dma_byte,
+                _ => {}
+            }
+        }
+
         self.peek(addr)
     }
 
     #[inline(always)]
     pub(crate) fn write(&mut self, addr: u16, data: u8) {
+        if (0xFE00..=0xFEFF).contains(&addr) {
+            self.ppu.corrupt_oam(OamCorruption::Write);
+        }
+
         self.tick(1);
 
+        // OAM and the bus the DMA is reading from are out of reach while it runs
+        if self.dma_index < 0xA0 && (matches!(addr, 0xFE00..=0xFEFF) || self.dma_conflict(addr)) {
+            return;
+        }
+
         match addr {
             0x0000..=0x7FFF => self.mbc.write(addr, data),
             0x8000..=0x9FFF => self.ppu.write(addr, data),
             0xA000..=0xBFFF => self.mbc.write(addr, data),
-            0xC000..=0xDFFF => unsafe { *self.wram.get_unchecked_mut((addr - 0xC000) as usize) = data },
-            0xE000..=0xFDFF => unsafe { *self.wram.get_unchecked_mut((addr - 0xE000) as usize) = data }, // Echo RAM
+            0xC000..=0xFDFF => {
+                let index = self.wram_index(addr);
+                unsafe { *self.wram.get_unchecked_mut(index) = data } // including echo RAM
+            }
             0xFE00..=0xFE9F => self.ppu.write(addr, data),
             0xFEA0..=0xFEFF => { /* unusable */ }
             0xFF00 => self.joypad.write(data),
-            0xFF02 => {
-                unsafe { *self.io.get_unchecked_mut((addr - 0xFF00) as usize) = data };
-                if data == 0x81 {
-                    #[cfg(test)]
-                    {
-                        self.serial_output.push(self.io[0x01] as char);
-                    }
-                    self.io[0x02] = 0;
-                }
-            }
+            0xFF01..=0xFF02 => self.serial.write(addr, data),
             0xFF04..=0xFF07 => self.timer.write(addr, data),
-            0xFF40..=0xFF45 => self.ppu.write(addr, data),
-            0xFF46 => self.transfer_dma(data),
+            0xFF10..=0xFF3F => self.apu.write(addr, data),
+            0xFF40..=0xFF45 => {
+                self.ppu.write(addr, data);
+                // LCDC, STAT and LYC writes can raise the STAT line
+                self.io[0x0F] |= self.ppu.take_write_irq();
+            }
+            0xFF46 => {
+                self.io[0x46] = data;
+                self.dma_start = Some(((data as u16) << 8, 1));
+            }
             0xFF47..=0xFF4B => self.ppu.write(addr, data),
+            0xFF4D if self.cgb => self.speed_switch_armed = (data & 0x01) != 0,
+            0xFF4F if self.cgb => self.ppu.write(addr, data),
             0xFF50 => self.boot_rom_enabled = false,
+            0xFF51 if self.cgb => self.hdma_src = (self.hdma_src & 0x00FF) | ((data as u16) << 8),
+            0xFF52 if self.cgb => self.hdma_src = (self.hdma_src & 0xFF00) | (data & 0xF0) as u16,
+            0xFF53 if self.cgb => self.hdma_dst = (self.hdma_dst & 0x00F0) | (((data & 0x1F) as u16) << 8),
+            0xFF54 if self.cgb => self.hdma_dst = (self.hdma_dst & 0x1F00) | (data & 0xF0) as u16,
+            0xFF55 if self.cgb => self.write_hdma5(data),
+            0xFF68..=0xFF6B if self.cgb => self.ppu.write(addr, data),
+            0xFF70 if self.cgb => self.wram_bank = (data & 0x07).max(1),
             0xFF80..=0xFFFE => unsafe { *self.hram.get_unchecked_mut((addr - 0xFF80) as usize) = data },
             0xFFFF => self.ie = data,
             _ => unsafe { *self.io.get_unchecked_mut((addr - 0xFF00) as usize) = data } // Fallback for unimplemented I/O
@@ -140,11 +445,37 @@ impl Bus {
         self.write(addr + 1, hi);
     }
 
-    fn transfer_dma(&mut self, start_addr: u8) {
-        let base_addr = (start_addr as u16) << 8;
-        for i in 0..0xA0 {
-            let data = self.peek(base_addr + i);
-            self.ppu.write(0xFE00 + i, data);
+    /// One M-cycle of OAM DMA, a requested transfer starts copying on the second cycle after the
+    /// FF46 write
+    fn dma_step(&mut self) {
+        if let Some((source, delay)) = self.dma_start {
+            if delay == 0 {
+                // sources past WRAM read its echo
+                self.dma_source = if source >= 0xE000 { source - 0x2000 } else { source };
+                self.dma_index = 0;
+                self.dma_start = None;
+            } else {
+                self.dma_start = Some((source, delay - 1));
+            }
+        }
+
+        if self.dma_index < 0xA0 {
+            self.dma_byte = self.peek(self.dma_source + self.dma_index as u16);
+            self.ppu.write_oam_dma(self.dma_index, self.dma_byte);
+            self.dma_index += 1;
         }
     }
+
+    /// Whether the CPU accessing `addr` collides with the running DMA on the same bus. VRAM has its
+    /// own bus, and on CGB so does WRAM, everything else below OAM shares the external bus.
+    fn dma_conflict(&self, addr: u16) -> bool {
+        let bus = |addr: u16| match addr {
+            0x8000..=0x9FFF => Some(1),
+            0xC000..=0xFDFF if self.cgb => Some(2),
+            0xFE00..=0xFFFF => None,
+            _ => Some(0),
+        };
+
+        bus(addr).is_some() && bus(addr) == bus(self.dma_source)
+    }
 }
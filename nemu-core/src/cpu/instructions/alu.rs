@@ -17,15 +17,15 @@ pub(in crate::cpu) fn inc_r8(cpu: &mut Cpu, reg: Reg8) -> u8 {
 /// INC r16 - Increment 16-bit register
 pub(in crate::cpu) fn inc_r16(cpu: &mut Cpu, bus: &mut Bus, reg: Reg16) -> u8 {
     let value = cpu.regs.read_reg16(reg);
-    bus.tick(1);
+    bus.tick_idu(value);
     cpu.regs.write_reg16(reg, value.wrapping_add(1));
     8
 }
 
 /// INC SP - Increment Stack Pointer
 pub(in crate::cpu) fn inc_sp(cpu: &mut Cpu, bus: &mut Bus) -> u8 {
+    bus.tick_idu(cpu.regs.sp());
     cpu.regs.inc_sp(1);
-    bus.tick(1);
     8
 }
 
@@ -57,15 +57,15 @@ pub(in crate::cpu) fn dec_r8(cpu: &mut Cpu, reg: Reg8) -> u8 {
 /// DEC r16 - Decrement 16-bit register
 pub(in crate::cpu) fn dec_r16(cpu: &mut Cpu, bus: &mut Bus, reg: Reg16) -> u8 {
     let value = cpu.regs.read_reg16(reg);
-    bus.tick(1);
+    bus.tick_idu(value);
     cpu.regs.write_reg16(reg, value.wrapping_sub(1));
     8
 }
 
 /// DEC SP - Decrement Stack Pointer
 pub(in crate::cpu) fn dec_sp(cpu: &mut Cpu, bus: &mut Bus) -> u8 {
+    bus.tick_idu(cpu.regs.sp());
     cpu.regs.dec_sp(1);
-    bus.tick(1);
     8
 }
 
@@ -77,7 +77,7 @@ pub(in crate::cpu) fn call_imm16(cpu: &mut Cpu, bus: &mut Bus) -> u8 {
     let addr = bus.read_u16(cpu.regs.pc());
     cpu.regs.inc_pc(2);
     let ret_addr = cpu.regs.pc();
-    bus.tick(1);
+    bus.tick_idu(cpu.regs.sp());
     let sp = cpu.regs.sp().wrapping_sub(2);
     bus.write_u16(sp, ret_addr);
     cpu.regs.set_sp(sp);
@@ -92,7 +92,7 @@ pub(in crate::cpu) fn call_cond_imm16(cpu: &mut Cpu, bus: &mut Bus, cond: JumpCo
 
     if cond.check(&cpu.regs) {
         let ret_addr = cpu.regs.pc();
-        bus.tick(1);
+        bus.tick_idu(cpu.regs.sp());
         let sp = cpu.regs.sp().wrapping_sub(2);
         bus.write_u16(sp, ret_addr);
         cpu.regs.set_sp(sp);
@@ -104,7 +104,7 @@ pub(in crate::cpu) fn call_cond_imm16(cpu: &mut Cpu, bus: &mut Bus, cond: JumpCo
 
 /// RET - Return from subroutine
 pub(in crate::cpu) fn ret(cpu: &mut Cpu, bus: &mut Bus) -> u8 {
-    let ret_addr = bus.read_u16(cpu.regs.sp());
+    let ret_addr = bus.read_u16_idu(cpu.regs.sp());
     cpu.regs.inc_sp(2);
     cpu.regs.set_pc(ret_addr);
     bus.tick(1);
@@ -116,7 +116,7 @@ pub(in crate::cpu) fn ret_cond(cpu: &mut Cpu, bus: &mut Bus, cond: JumpCond) ->
     bus.tick(1);
 
     if cond.check(&cpu.regs) {
-        let ret_addr = bus.read_u16(cpu.regs.sp());
+        let ret_addr = bus.read_u16_idu(cpu.regs.sp());
         cpu.regs.inc_sp(2);
         bus.tick(1);
         cpu.regs.set_pc(ret_addr);
@@ -136,7 +136,7 @@ pub(in crate::cpu) fn reti(cpu: &mut Cpu, bus: &mut Bus) -> u8 {
 pub(in crate::cpu) fn rst(cpu: &mut Cpu, bus: &mut Bus, vec: u8) -> u8 {
     let ret_addr = cpu.regs.pc();
     let sp = cpu.regs.sp().wrapping_sub(2);
-    bus.tick(1);
+    bus.tick_idu(cpu.regs.sp());
     bus.write_u16(sp, ret_addr);
     cpu.regs.set_sp(sp);
     cpu.regs.set_pc(vec as u16);
@@ -71,7 +71,7 @@ pub(in crate::cpu) fn ld_mem_hld_a(cpu: &mut Cpu, bus: &mut Bus) -> u8 {
 /// LD A, (HL+) - Load A from address in HL, then increment HL
 pub(in crate::cpu) fn ld_a_mem_hli(cpu: &mut Cpu, bus: &mut Bus) -> u8 {
     let addr = cpu.regs.hl();
-    let value = bus.read(addr);
+    let value = bus.read_idu(addr);
     cpu.regs.set_a(value);
     cpu.regs.set_hl(addr.wrapping_add(1));
     8
@@ -80,7 +80,7 @@ pub(in crate::cpu) fn ld_a_mem_hli(cpu: &mut Cpu, bus: &mut Bus) -> u8 {
 /// LD A, (HL-) - Load A from address in HL, then decrement HL
 pub(in crate::cpu) fn ld_a_mem_hld(cpu: &mut Cpu, bus: &mut Bus) -> u8 {
     let addr = cpu.regs.hl();
-    let value = bus.read(addr);
+    let value = bus.read_idu(addr);
     cpu.regs.set_a(value);
     cpu.regs.set_hl(addr.wrapping_sub(1));
     8
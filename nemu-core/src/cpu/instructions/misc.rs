@@ -1,15 +1,26 @@
+use crate::bus::Bus;
 use crate::cpu::{Cpu, InterruptMode};
 
 /// STOP - Enter low power mode (halts CPU until an interrupt occurs)
-pub(in crate::cpu) fn stop(cpu: &mut Cpu) -> u8 {
-    // stub implementation until i add it properly
+pub(in crate::cpu) fn stop(cpu: &mut Cpu, bus: &mut Bus) -> u8 {
     cpu.regs.inc_pc(1);
+
+    // on CGB this is also how the speed switch prepared in KEY1 happens, otherwise still a stub
+    bus.try_switch_speed();
     4
 }
 
 /// HALT - Halt CPU until an interrupt occurs
-pub(in crate::cpu) fn halt(cpu: &mut Cpu) -> u8 {
-    cpu.halted = true;
+pub(in crate::cpu) fn halt(cpu: &mut Cpu, bus: &Bus) -> u8 {
+    let (ie, _if) = bus.get_ie_if();
+
+    if (ie & _if & 0x1F) == 0 {
+        cpu.halted = true;
+    } else if cpu.ime != InterruptMode::Enabled {
+        // an interrupt is already pending, so HALT returns at once and, with IME off, trips the
+        // HALT bug. With IME on the interrupt is simply taken next.
+        cpu.halt_bug = true;
+    }
     4
 }
 
@@ -4,7 +4,7 @@ use crate::cpu::registers::Reg16;
 
 /// POP r16 - Pop 16-bit value from stack into 16-bit register
 pub(in crate::cpu) fn pop_r16(cpu: &mut Cpu, bus: &mut Bus, reg: Reg16) -> u8 {
-    let value = bus.read_u16(cpu.regs.sp());
+    let value = bus.read_u16_idu(cpu.regs.sp());
     cpu.regs.write_reg16(reg, value);
     cpu.regs.inc_sp(2);
     12
@@ -13,7 +13,7 @@ pub(in crate::cpu) fn pop_r16(cpu: &mut Cpu, bus: &mut Bus, reg: Reg16) -> u8 {
 /// PUSH r16 - Push 16-bit register value onto stack
 pub(in crate::cpu) fn push_r16(cpu: &mut Cpu, bus: &mut Bus, reg: Reg16) -> u8 {
     let sp = cpu.regs.sp().wrapping_sub(2);
-    bus.tick(1);
+    bus.tick_idu(cpu.regs.sp());
     let value = cpu.regs.read_reg16(reg);
     bus.write_u16(sp, value);
     cpu.regs.set_sp(sp);
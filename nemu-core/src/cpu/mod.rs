@@ -2,7 +2,9 @@ mod instructions;
 mod registers;
 mod utils;
 
+use crate::NemuError;
 use crate::bus::Bus;
+use crate::state::{StateReader, StateWriter};
 use instructions::*;
 use registers::{Reg8, Reg16, Registers};
 pub(crate) use utils::*;
@@ -11,6 +13,8 @@ pub struct Cpu {
     pub(crate) regs: Registers,
     pub(crate) ime: InterruptMode,
     pub(crate) halted: bool,
+    // set by HALT when it fails to halt, the next opcode fetch does not increment PC
+    pub(crate) halt_bug: bool,
 }
 
 impl Cpu {
@@ -19,6 +23,7 @@ impl Cpu {
             regs: Registers::new(),
             ime: InterruptMode::Disabled,
             halted: false,
+            halt_bug: false,
         }
     }
 
@@ -26,6 +31,47 @@ impl Cpu {
         self.regs.reset();
         self.ime = InterruptMode::Disabled;
         self.halted = false;
+        self.halt_bug = false;
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        let regs = &self.regs;
+        for value in [regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l] {
+            w.u8(value);
+        }
+        w.u16(regs.sp);
+        w.u16(regs.pc);
+
+        w.u8(match self.ime {
+            InterruptMode::Disabled => 0,
+            InterruptMode::Enabled => 1,
+            InterruptMode::Pending => 2,
+        });
+        w.bool(self.halted);
+        w.bool(self.halt_bug);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        let regs = &mut self.regs;
+        let values = [
+            &mut regs.a, &mut regs.f, &mut regs.b, &mut regs.c,
+            &mut regs.d, &mut regs.e, &mut regs.h, &mut regs.l,
+        ];
+        for value in values {
+            *value = r.u8()?;
+        }
+        regs.sp = r.u16()?;
+        regs.pc = r.u16()?;
+
+        self.ime = match r.u8()? {
+            0 => InterruptMode::Disabled,
+            1 => InterruptMode::Enabled,
+            2 => InterruptMode::Pending,
+            ime => return Err(NemuError::InvalidState(format!("Invalid interrupt mode: {}", ime))),
+        };
+        self.halted = r.bool()?;
+        self.halt_bug = r.version() >= 7 && r.bool()?;
+        Ok(())
     }
 
     pub fn step(&mut self, bus: &mut Bus) -> u8 {
@@ -33,6 +79,8 @@ impl Cpu {
         let int_pending = (ie & _if) & 0x1F;
 
         if self.halted {
+            // an interrupt raised during the last M-cycle is seen here, leaving HALT takes one more
+            // M-cycle before the dispatch or the next instruction, whatever IME is
             bus.tick(1);
 
             if int_pending != 0 {
@@ -48,12 +96,21 @@ impl Cpu {
             }
         }
 
-        if let InterruptMode::Pending = self.ime {
+        // EI takes effect after the instruction that follows it, which is how EI followed by HALT
+        // still sees IME as 0 and runs into the HALT bug
+        let enable_ime = self.ime == InterruptMode::Pending;
+
+        let cycles = self.fetch_execute(bus);
+
+        if enable_ime && self.ime == InterruptMode::Pending {
             self.ime = InterruptMode::Enabled;
         }
+        cycles
+    }
 
+    fn fetch_execute(&mut self, bus: &mut Bus) -> u8 {
         let opcode = bus.read(self.regs.pc());
-        self.regs.inc_pc(1);
+        self.inc_pc_after_fetch();
 
         if opcode == 0xCB {
             let cb_opcode = bus.read(self.regs.pc());
@@ -64,6 +121,16 @@ impl Cpu {
         self.execute(opcode, bus)
     }
 
+    /// The HALT bug, the byte after HALT is fetched without moving PC so it gets read twice
+    #[inline(always)]
+    fn inc_pc_after_fetch(&mut self) {
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.regs.inc_pc(1);
+        }
+    }
+
     fn service_interrupt(&mut self, int_pending: u8, _if: u8, bus: &mut Bus) -> u8 {
         for (bit, addr) in [
             (0, 0x40), // V-Blank
@@ -76,8 +143,15 @@ impl Cpu {
                 self.ime = InterruptMode::Disabled;
                 bus.write(0xFF0F, _if & !(1 << bit));
 
+                // with EI right before a HALT that hit the bug, the handler returns to the HALT
+                let mut ret_addr = self.regs.pc();
+                if self.halt_bug {
+                    self.halt_bug = false;
+                    ret_addr = ret_addr.wrapping_sub(1);
+                }
+
                 let sp = self.regs.sp().wrapping_sub(2);
-                bus.write_u16(sp, self.regs.pc());
+                bus.write_u16(sp, ret_addr);
 
                 self.regs.set_sp(sp);
                 self.regs.set_pc(addr);
@@ -109,7 +183,7 @@ impl Cpu {
             0x0D => dec_r8(self, Reg8::C),
             0x0E => ld_r8_imm8(self, bus, Reg8::C),
             0x0F => rrca(self),
-            0x10 => stop(self),
+            0x10 => stop(self, bus),
             0x11 => ld_r16_imm16(self, bus, Reg16::DE),
             0x12 => ld_mem_r16_r8(self, bus, Reg16::DE, Reg8::A),
             0x13 => inc_r16(self, bus, Reg16::DE),
@@ -211,7 +285,7 @@ impl Cpu {
             0x73 => ld_mem_r16_r8(self, bus, Reg16::HL, Reg8::E),
             0x74 => ld_mem_r16_r8(self, bus, Reg16::HL, Reg8::H),
             0x75 => ld_mem_r16_r8(self, bus, Reg16::HL, Reg8::L),
-            0x76 => halt(self),
+            0x76 => halt(self, bus),
             0x77 => ld_mem_r16_r8(self, bus, Reg16::HL, Reg8::A),
             0x78 => ld_r8_r8(self, Reg8::A, Reg8::B),
             0x79 => ld_r8_r8(self, Reg8::A, Reg8::C),
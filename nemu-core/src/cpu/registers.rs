@@ -61,6 +61,20 @@ impl Registers {
         self.pc = 0x0100;
     }
     
+    /// What the CGB boot ROM leaves behind for CGB games, A = 0x11 is how they detect the CGB
+    pub(crate) fn skip_boot_cgb(&mut self) {
+        self.a = 0x11;
+        self.f = 0x80;
+        self.b = 0x00;
+        self.c = 0x00;
+        self.d = 0xFF;
+        self.e = 0x56;
+        self.h = 0x00;
+        self.l = 0x0D;
+        self.sp = 0xFFFE;
+        self.pc = 0x0100;
+    }
+
     pub(super) fn reset(&mut self) {
         self.a = 0;
         self.f = 0;
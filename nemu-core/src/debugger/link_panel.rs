@@ -0,0 +1,113 @@
+use eframe::egui;
+use std::time::Duration;
+
+use crate::{Nemu, Printer, PrinterOutput, TcpLink};
+
+// the emulator waits this long on a silent peer before the link gives up
+const LINK_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub(super) struct LinkPanel {
+    host_input: String,
+    port_input: String,
+    status: String,
+    printer: Option<PrinterOutput>,
+    print_count: u32,
+}
+
+impl LinkPanel {
+    pub(super) fn new() -> Self {
+        Self {
+            host_input: String::from("127.0.0.1"),
+            port_input: String::from("5738"),
+            status: String::from("Disconnected"),
+            printer: None,
+            print_count: 0,
+        }
+    }
+
+    pub(super) fn set_status(&mut self, status: String) {
+        self.status = status;
+    }
+
+    fn save_prints(&mut self) {
+        let Some(printer) = &self.printer else { return };
+
+        while let Some(image) = printer.pop() {
+            self.print_count += 1;
+            let path = format!("nemu_print_{}.png", self.print_count);
+
+            self.status = match std::fs::write(&path, image.to_png()) {
+                Ok(()) => format!("Printed {}", path),
+                Err(e) => format!("Failed to save {}: {}", path, e),
+            };
+        }
+    }
+
+    pub(super) fn render(&mut self, ui: &mut egui::Ui, nemu: &mut Nemu) {
+        self.save_prints();
+
+        ui.horizontal(|ui| {
+            ui.label("Host:");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.host_input)
+                    .desired_width(100.0)
+                    .font(egui::TextStyle::Monospace),
+            );
+
+            ui.label("Port:");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.port_input)
+                    .desired_width(50.0)
+                    .font(egui::TextStyle::Monospace),
+            );
+        });
+
+        ui.horizontal(|ui| {
+            let port = self.port_input.parse::<u16>();
+
+            if ui.button("Host").clicked() {
+                self.status = match port {
+                    Ok(port) => match TcpLink::host((self.host_input.as_str(), port), LINK_TIMEOUT) {
+                        Ok(link) => {
+                            nemu.connect_serial(Box::new(link));
+                            self.printer = None;
+                            format!("Hosting on {}:{}", self.host_input, port)
+                        }
+                        Err(e) => e.to_string(),
+                    },
+                    Err(_) => String::from("Invalid port"),
+                };
+            }
+
+            if ui.button("Connect").clicked() {
+                self.status = match port {
+                    Ok(port) => match TcpLink::connect((self.host_input.as_str(), port), LINK_TIMEOUT) {
+                        Ok(link) => {
+                            nemu.connect_serial(Box::new(link));
+                            self.printer = None;
+                            format!("Connected to {}:{}", self.host_input, port)
+                        }
+                        Err(e) => e.to_string(),
+                    },
+                    Err(_) => String::from("Invalid port"),
+                };
+            }
+
+            if ui.button("Printer").clicked() {
+                let printer = Printer::new();
+                self.printer = Some(printer.output());
+                nemu.connect_serial(Box::new(printer));
+                self.status = String::from("Game Boy Printer connected");
+            }
+
+            if ui.button("Disconnect").clicked() {
+                nemu.disconnect_serial();
+                self.printer = None;
+                self.status = String::from("Disconnected");
+            }
+        });
+
+        ui.separator();
+        ui.label(&self.status);
+    }
+}
@@ -2,14 +2,16 @@ mod disassembler;
 mod fps_tracker;
 mod memory_viewer;
 mod breakpoints;
+mod link_panel;
 
 use eframe::egui;
 use std::time::Instant;
 
-use crate::Nemu;
+use crate::{Model, Nemu, Rewind};
 use fps_tracker::FpsTracker;
 use breakpoints::Breakpoints;
 use disassembler::Disassembler;
+use link_panel::LinkPanel;
 use memory_viewer::MemoryViewer;
 
 const WIDTH: usize = 160;
@@ -17,6 +19,10 @@ const HEIGHT: usize = 144;
 
 const GB_CYCLES_PER_SEC: f64 = 4_194_304.0;
 
+// a snapshot every frame so stepping back never has to replay anything
+const REWIND_INTERVAL: u32 = 1;
+const REWIND_BUDGET: usize = 64 * 1024 * 1024;
+
 const PALETTE: [u32; 4] = [
     u32::from_le_bytes([0xE0, 0xF8, 0xD0, 0xFF]),
     u32::from_le_bytes([0x88, 0xC0, 0x70, 0xFF]),
@@ -39,6 +45,10 @@ pub struct Debugger {
     disassembler: Disassembler,
     fps_tracker: FpsTracker,
     breakpoints: Breakpoints,
+    link_panel: LinkPanel,
+    rewind: Rewind,
+    ppu_lockouts: bool,
+    model: Model,
 }
 
 impl Debugger {
@@ -65,6 +75,10 @@ impl Debugger {
             disassembler: Disassembler::new(),
             fps_tracker: FpsTracker::new(),
             breakpoints: Breakpoints::new(),
+            link_panel: LinkPanel::new(),
+            rewind: Rewind::new(REWIND_INTERVAL, REWIND_BUDGET),
+            ppu_lockouts: true,
+            model: Model::default(),
         };
 
         debugger.memory_viewer.refresh_memory_view(&debugger.nemu.bus);
@@ -73,8 +87,6 @@ impl Debugger {
     }
 
     fn update_screen_texture(&mut self) {
-        let fb = self.nemu.get_framebuffer();
-
         let pixels_u32 = unsafe {
             std::slice::from_raw_parts_mut(
                 self.screen_pixels.as_mut_ptr() as *mut u32,
@@ -82,10 +94,25 @@ impl Debugger {
             )
         };
 
-        for i in 0..fb.len() {
-            unsafe {
-                *pixels_u32.get_unchecked_mut(i) =
-                    *PALETTE.get_unchecked(*fb.get_unchecked(i) as usize);
+        if self.nemu.is_cgb() {
+            let fb = self.nemu.get_rgb_framebuffer();
+
+            for (pixel, &color) in pixels_u32.iter_mut().zip(fb.iter()) {
+                // scale each 5-bit channel up to 8 bits
+                let channel = |shift: u16| {
+                    let value = ((color >> shift) & 0x1F) as u8;
+                    (value << 3) | (value >> 2)
+                };
+                *pixel = u32::from_le_bytes([channel(0), channel(5), channel(10), 0xFF]);
+            }
+        } else {
+            let fb = self.nemu.get_framebuffer();
+
+            for i in 0..fb.len() {
+                unsafe {
+                    *pixels_u32.get_unchecked_mut(i) =
+                        *PALETTE.get_unchecked(*fb.get_unchecked(i) as usize);
+                }
             }
         }
 
@@ -120,6 +147,7 @@ impl Debugger {
                             .unwrap_or("Unknown")
                             .to_string();
 
+                        self.rewind.clear();
                         self.update_screen_texture();
                         self.fps_tracker.reset();
                         self.disassembler.invalidate_cache();
@@ -141,8 +169,37 @@ impl Debugger {
 
                 ui.separator();
 
+                if ui
+                    .checkbox(&mut self.ppu_lockouts, "Lockouts")
+                    .on_hover_text("Block CPU access to VRAM and OAM while the PPU is using them")
+                    .changed()
+                {
+                    self.nemu.set_ppu_lockouts(self.ppu_lockouts);
+                }
+
+                let model = self.model;
+                egui::ComboBox::from_id_salt("model")
+                    .selected_text(match self.model {
+                        Model::Dmg => "DMG",
+                        Model::Cgb => "CGB",
+                        Model::Sgb => "SGB",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.model, Model::Dmg, "DMG");
+                        ui.selectable_value(&mut self.model, Model::Cgb, "CGB");
+                        ui.selectable_value(&mut self.model, Model::Sgb, "SGB");
+                    })
+                    .response
+                    .on_hover_text("Console to emulate, takes effect when the next ROM is opened");
+                if self.model != model {
+                    self.nemu.set_model(self.model);
+                }
+
+                ui.separator();
+
                 if ui.button("🔄 Reset").clicked() {
                     self.nemu.reset();
+                    self.rewind.clear();
                     self.running = false;
                     self.update_screen_texture();
                     self.fps_tracker.reset();
@@ -150,6 +207,15 @@ impl Debugger {
                     self.memory_viewer.refresh_memory_view(&self.nemu.bus);
                 }
 
+                if ui
+                    .add_enabled(!self.rewind.is_empty(), egui::Button::new("⏮ Back"))
+                    .on_hover_text("Step back one frame, hold Backspace while running to rewind")
+                    .clicked()
+                {
+                    self.running = false;
+                    self.step_back();
+                }
+
                 if ui.button("⏭ Step").clicked() {
                     self.running = false;
                     self.nemu.step();
@@ -243,6 +309,14 @@ impl Debugger {
         });
     }
 
+    fn step_back(&mut self) {
+        if self.rewind.step_back(&mut self.nemu) {
+            self.update_screen_texture();
+            self.disassembler.invalidate_cache();
+            self.memory_viewer.refresh_memory_view(&self.nemu.bus);
+        }
+    }
+
     fn handle_input(&mut self, ctx: &egui::Context) {
         if ctx.wants_keyboard_input() {
             return;
@@ -266,7 +340,16 @@ impl Debugger {
 
 impl eframe::App for Debugger {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        if self.running {
+        let rewinding = self.running
+            && !ctx.wants_keyboard_input()
+            && ctx.input(|i| i.key_down(egui::Key::Backspace));
+
+        if rewinding {
+            self.step_back();
+            self.last_update = Instant::now();
+            self.tick_accumulator = 0.0;
+            ctx.request_repaint_after(std::time::Duration::from_millis(16));
+        } else if self.running {
             self.handle_input(ctx);
 
             let now = Instant::now();
@@ -280,6 +363,8 @@ impl eframe::App for Debugger {
                 self.tick_accumulator = MAX_ACCUM;
             }
 
+            let mut new_frame = false;
+
             while self.tick_accumulator > 0.0 {
                 if self.breakpoints.is_breakpoint(self.nemu.cpu.regs.pc) {
                     self.running = false;
@@ -288,13 +373,22 @@ impl eframe::App for Debugger {
 
                 let cycles = self.nemu.step();
                 self.tick_accumulator -= cycles as f64;
+
+                if self.nemu.has_frame() {
+                    self.rewind.push_frame(&mut self.nemu);
+                    new_frame = true;
+                }
             }
 
-            if self.nemu.has_frame() {
+            if new_frame {
                 self.update_screen_texture();
                 self.fps_tracker.update();
             }
 
+            if let Some(e) = self.nemu.take_serial_error() {
+                self.link_panel.set_status(e.to_string());
+            }
+
             ctx.request_repaint_after(std::time::Duration::from_millis(16));
         } else {
             self.last_update = Instant::now();
@@ -337,6 +431,13 @@ impl eframe::App for Debugger {
                 self.breakpoints.render(ui);
             });
 
+        egui::Window::new("Link Cable")
+            .default_pos([625.0, 480.0])
+            .default_size([300.0, 100.0])
+            .show(ctx, |ui| {
+                self.link_panel.render(ui, &mut self.nemu);
+            });
+
         egui::Window::new("Memory Viewer")
             .default_pos([625.0, 55.0])
             .default_size([300.0, 400.0])
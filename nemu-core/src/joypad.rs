@@ -1,3 +1,7 @@
+use crate::NemuError;
+use crate::sgb::Sgb;
+use crate::state::{StateReader, StateWriter};
+
 pub(crate) struct Joypad {
     buttons: u8,
     directions: u8,
@@ -5,6 +9,9 @@ pub(crate) struct Joypad {
 
     prev_buttons: u8,
     prev_directions: u8,
+
+    // the SGB listens to P14/P15 for command packets
+    pub(crate) sgb: Option<Box<Sgb>>,
 }
 
 impl Joypad {
@@ -16,9 +23,21 @@ impl Joypad {
 
             prev_buttons: 0x0F,
             prev_directions: 0x0F,
+
+            sgb: None,
         }
     }
 
+    pub(crate) fn reset(&mut self) {
+        let sgb = self.sgb.is_some();
+        *self = Self::new();
+        self.set_sgb(sgb);
+    }
+
+    pub(crate) fn set_sgb(&mut self, sgb: bool) {
+        self.sgb = sgb.then(|| Box::new(Sgb::new()));
+    }
+
     pub(crate) fn set_joypad(&mut self, input: JoypadButton, pressed: bool, is_direction: bool) {
         let target = if is_direction {
             &mut self.directions
@@ -33,9 +52,24 @@ impl Joypad {
         }
     }
 
+    /// Held buttons, A/B/Select/Start in the low nibble and Right/Left/Up/Down in the high one
+    pub(crate) fn pressed(&self) -> u8 {
+        !((self.directions << 4) | self.buttons)
+    }
+
     pub(crate) fn read(&self) -> u8 {
         let mut result = 0xC0 | self.select | 0x0F;
 
+        if let Some(sgb) = &self.sgb {
+            if self.select == 0x30 && let Some(id) = sgb.joypad_id() {
+                return 0xC0 | self.select | id;
+            }
+            // only player 1 has a controller
+            if !sgb.reading_player1() {
+                return result;
+            }
+        }
+
         if self.select & 0x20 == 0 {
             result &= 0xF0 | self.buttons;
         }
@@ -49,6 +83,39 @@ impl Joypad {
 
     pub(crate) fn write(&mut self, value: u8) {
         self.select = value & 0x30;
+
+        if let Some(sgb) = &mut self.sgb {
+            sgb.write(self.select);
+        }
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.buttons);
+        w.u8(self.directions);
+        w.u8(self.select);
+        w.u8(self.prev_buttons);
+        w.u8(self.prev_directions);
+
+        w.bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(w);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.buttons = r.u8()?;
+        self.directions = r.u8()?;
+        self.select = r.u8()?;
+        self.prev_buttons = r.u8()?;
+        self.prev_directions = r.u8()?;
+
+        // version 3 added the SGB
+        let sgb = r.version() >= 3 && r.bool()?;
+        self.set_sgb(sgb);
+        if let Some(sgb) = &mut self.sgb {
+            sgb.load_state(r)?;
+        }
+        Ok(())
     }
 
     pub(crate) fn poll_interrupt(&mut self) -> u8 {
@@ -70,6 +137,7 @@ impl Joypad {
 }
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum JoypadButton {
     RightOrA = 0x01,
     LeftOrB = 0x02,
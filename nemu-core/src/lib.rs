@@ -1,11 +1,19 @@
+mod apu;
 mod bus;
 mod cpu;
+mod crc;
 // mod traits;
 mod timer;
 mod ppu;
 mod interrupts;
 mod joypad;
+mod link;
 mod mbc;
+mod movie;
+mod rewind;
+mod serial;
+mod sgb;
+mod state;
 
 #[cfg(feature = "debugger")]
 pub mod debugger;
@@ -13,16 +21,66 @@ pub mod debugger;
 #[cfg(feature = "debugger")]
 pub use debugger::Debugger;
 pub use joypad::JoypadButton;
+pub use link::LinkedPair;
+pub use mbc::{CartridgeHeader, RtcClock};
+pub use movie::{Movie, MoviePlayer, MovieRecorder};
+pub use rewind::Rewind;
+pub use serial::{PrintedImage, Printer, PrinterOutput, SerialDevice, TcpLink};
+
+// T-cycles in one frame, 154 lines of 456 dots
+const CYCLES_PER_FRAME: u32 = 70224;
+
+/// Which console to emulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    #[default]
+    Dmg,
+    /// Game Boy Color, cartridges without the CGB flag still run in DMG mode
+    Cgb,
+    /// Super Game Boy, only cartridges with the SGB flag get palettes and borders
+    Sgb,
+}
 
 #[derive(Debug)]
 pub enum NemuError {
     InvalidRom(String),
+    TruncatedRom { len: usize },
+    RomSizeMismatch { expected: usize, actual: usize },
+    BadHeaderChecksum { expected: u8, actual: u8 },
+    InvalidSave(String),
+    InvalidState(String),
+    UnsupportedStateVersion(u16),
+    InvalidMovie(String),
+    MovieDesync { frame: usize },
+    Link(String),
 }
 
 impl std::fmt::Display for NemuError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             NemuError::InvalidRom(msg) => write!(f, "Invalid ROM: {}", msg),
+            NemuError::TruncatedRom { len } => {
+                write!(f, "Invalid ROM: {} bytes is too short to hold a cartridge header", len)
+            }
+            NemuError::RomSizeMismatch { expected, actual } => {
+                write!(f, "Invalid ROM: header declares {} bytes but the ROM is {} bytes", expected, actual)
+            }
+            NemuError::BadHeaderChecksum { expected, actual } => {
+                write!(f, "Invalid ROM: header checksum is {:#04X} but the header sums to {:#04X}", expected, actual)
+            }
+            NemuError::InvalidSave(msg) => write!(f, "Invalid save: {}", msg),
+            NemuError::InvalidState(msg) => write!(f, "Invalid save state: {}", msg),
+            NemuError::UnsupportedStateVersion(version) => write!(
+                f,
+                "Invalid save state: format version {} is not supported (expected {})",
+                version,
+                state::STATE_VERSION
+            ),
+            NemuError::InvalidMovie(msg) => write!(f, "Invalid movie: {}", msg),
+            NemuError::MovieDesync { frame } => {
+                write!(f, "Movie desynced at frame {}, the state no longer matches the recording", frame)
+            }
+            NemuError::Link(msg) => write!(f, "Link cable error: {}", msg),
         }
     }
 }
@@ -30,13 +88,25 @@ impl std::fmt::Display for NemuError {
 pub struct Nemu {
     pub(crate) cpu: cpu::Cpu,
     pub(crate) bus: bus::Bus,
+    header: Option<CartridgeHeader>,
+    rtc_clock: RtcClock,
+    model: Model,
 }
 
+// frontends run the emulator on its own thread, so nothing inside may pin it to one
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Nemu>();
+};
+
 impl Default for Nemu {
     fn default() -> Self {
         Self {
             cpu: cpu::Cpu::new(),
             bus: bus::Bus::new(),
+            header: None,
+            rtc_clock: RtcClock::default(),
+            model: Model::default(),
         }
     }
 }
@@ -45,26 +115,218 @@ impl Nemu {
     pub fn reset(&mut self) {
         self.cpu.reset();
         self.bus.reset();
+
+        // there is no CGB boot ROM to run, so CGB mode starts from the state it would leave behind
+        if self.bus.cgb {
+            self.skip_boot();
+        }
     }
 
+    /// Returns the elapsed time in T-cycles of the normal speed clock
     pub fn step(&mut self) -> u8 {
-        self.cpu.step(&mut self.bus)
+        let cycles = self.cpu.step(&mut self.bus);
+        if self.bus.double_speed { cycles / 2 } else { cycles }
+    }
+
+    /// Runs until the PPU finishes a frame, or for one frame's worth of cycles while the LCD is off
+    pub fn run_frame(&mut self) {
+        let mut cycles = 0u32;
+        while cycles < CYCLES_PER_FRAME {
+            cycles += self.step() as u32;
+            if self.has_frame() {
+                break;
+            }
+        }
     }
 
     pub fn skip_boot(&mut self) {
-        self.cpu.regs.skip_boot();
         self.bus.boot_rom_enabled = false;
+
+        if self.bus.cgb {
+            self.cpu.regs.skip_boot_cgb();
+            self.bus.ppu.write(0xFF40, 0x91);
+            self.bus.ppu.write(0xFF47, 0xFC);
+        } else {
+            self.cpu.regs.skip_boot();
+        }
+    }
+
+    /// Chooses the console, takes effect from the next `load_cartridge`
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    /// VRAM is out of the CPU's reach during mode 3 and OAM during modes 2 and 3, reads give 0xFF
+    /// and writes are dropped. Enabled by default, turning it off helps debugging homebrew that
+    /// writes at the wrong time. The setting is kept in save states and movies.
+    pub fn set_ppu_lockouts(&mut self, enabled: bool) {
+        self.bus.ppu.set_lockouts(enabled);
     }
 
-    pub fn load_cartridge(&mut self, bytes: &[u8]) -> Result<(), NemuError> {
-        self.bus.mbc = mbc::MbcType::new(bytes.to_vec())?;
-        Ok(())
+    /// Whether the loaded cartridge is running in CGB mode
+    pub fn is_cgb(&self) -> bool {
+        self.bus.cgb
+    }
+
+    /// Validates the header and inserts the cartridge, a rejected ROM leaves the current one in place
+    pub fn load_cartridge(&mut self, bytes: &[u8]) -> Result<CartridgeHeader, NemuError> {
+        let header = CartridgeHeader::parse(bytes)?;
+        self.bus.mbc = mbc::MbcType::new(bytes.to_vec(), &header)?;
+        self.bus.mbc.set_rtc_clock(self.rtc_clock);
+        self.header = Some(header.clone());
+
+        let cgb = self.model == Model::Cgb && header.supports_cgb();
+        if cgb || self.bus.cgb {
+            self.bus.set_cgb(cgb);
+            self.reset();
+        }
+        self.bus.joypad.set_sgb(self.model == Model::Sgb && header.supports_sgb());
+
+        Ok(header)
+    }
+
+    pub fn cartridge_header(&self) -> Option<&CartridgeHeader> {
+        self.header.as_ref()
+    }
+
+    /// Chooses what drives the cartridge RTC, applies to the loaded cartridge and any loaded later
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.rtc_clock = clock;
+        self.bus.mbc.set_rtc_clock(clock);
     }
     
+    pub fn has_battery(&self) -> bool {
+        self.bus.mbc.has_battery()
+    }
+
+    /// Exports battery backed cartridge RAM in the `.sav` layout used by other emulators, with the
+    /// RTC block appended for MBC3 timer carts. Returns `None` for cartridges without a battery.
+    pub fn export_save(&mut self) -> Option<Vec<u8>> {
+        self.bus.mbc.export_save()
+    }
+
+    /// Loads a `.sav` file into battery backed cartridge RAM, the size must match the header's RAM size
+    pub fn import_save(&mut self, data: &[u8]) -> Result<(), NemuError> {
+        self.bus.mbc.import_save(data)
+    }
+
+    /// Whether battery backed RAM was written since the last import or export
+    pub fn save_dirty(&self) -> bool {
+        self.bus.mbc.save_dirty()
+    }
+
+    /// Whether the cartridge's rumble motor is currently switched on, always false for carts without one
+    pub fn rumble_active(&self) -> bool {
+        self.bus.mbc.rumble_active()
+    }
+
+    /// Snapshots the whole machine into a versioned blob. The cartridge ROM is not included, a
+    /// state can only be loaded back while the same cartridge is inserted.
+    pub fn save_state(&mut self) -> Vec<u8> {
+        let mut w = state::StateWriter::new();
+
+        for byte in state::STATE_MAGIC {
+            w.u8(byte);
+        }
+        w.u16(state::STATE_VERSION);
+        self.write_cartridge_id(&mut w);
+
+        self.cpu.save_state(&mut w);
+        self.bus.save_state(&mut w);
+        w.finish()
+    }
+
+    /// Restores a blob from `save_state`, on error the emulator is left exactly as it was
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), NemuError> {
+        if data.len() < 6 || data[..4] != state::STATE_MAGIC {
+            return Err(NemuError::InvalidState("Not a Nemu save state".to_string()));
+        }
+
+        // older versions are migrated by the load_state methods as they read them
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version == 0 || version > state::STATE_VERSION {
+            return Err(NemuError::UnsupportedStateVersion(version));
+        }
+
+        let mut r = state::StateReader::new(&data[6..], version);
+
+        let mut expected = state::StateWriter::new();
+        self.write_cartridge_id(&mut expected);
+        let expected = expected.finish();
+
+        let mut cartridge = vec![0; expected.len()];
+        for byte in cartridge.iter_mut() {
+            *byte = r.u8()?;
+        }
+        if cartridge != expected {
+            return Err(NemuError::InvalidState("State was saved with a different cartridge".to_string()));
+        }
+
+        // loading stops at the first bad field, roll back instead of leaving a half loaded machine
+        let backup = self.save_state();
+        let result = self
+            .cpu
+            .load_state(&mut r)
+            .and_then(|_| self.bus.load_state(&mut r))
+            .and_then(|_| match r.is_empty() {
+                true => Ok(()),
+                false => Err(NemuError::InvalidState("Trailing data after the end of the state".to_string())),
+            });
+
+        if result.is_err() {
+            let mut r = state::StateReader::new(&backup[6 + expected.len()..], state::STATE_VERSION);
+            self.cpu.load_state(&mut r).expect("backup state is valid");
+            self.bus.load_state(&mut r).expect("backup state is valid");
+        }
+        result
+    }
+
+    fn write_cartridge_id(&self, w: &mut state::StateWriter) {
+        let (cartridge_type, header_checksum, global_checksum) = self
+            .header
+            .as_ref()
+            .map(|h| (h.cartridge_type, h.header_checksum, h.global_checksum))
+            .unwrap_or_default();
+
+        w.u8(cartridge_type);
+        w.u8(header_checksum);
+        w.u16(global_checksum);
+    }
+
     pub fn set_joypad(&mut self, input: JoypadButton, pressed: bool, is_direction: bool) {
         self.bus.joypad.set_joypad(input, pressed, is_direction);
     }
 
+    /// Last value written to the HuC3 tone generator, 0 when silent and on every other cartridge type
+    pub fn tone_generator(&self) -> u8 {
+        self.bus.mbc.tone()
+    }
+
+    /// Feeds the MBC7 accelerometer, `x` and `y` are in g along the cartridge's two axes
+    /// (0.0 is level, about ±1.0 is a full tilt). Ignored by every other cartridge type.
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.bus.mbc.set_tilt(x, y);
+    }
+
+    /// Sets the 128x112 grayscale picture (0 is black, 255 is white) the Pocket Camera sensor
+    /// sees on its next capture. Ignored by every other cartridge type.
+    pub fn set_camera_image(&mut self, image: &[u8; 128 * 112]) {
+        self.bus.mbc.set_camera_image(image);
+    }
+
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.bus.serial.connect(Some(device));
+    }
+
+    pub fn disconnect_serial(&mut self) {
+        self.bus.serial.connect(None);
+    }
+
+    /// Returns the last error reported by the device on the link port, if any
+    pub fn take_serial_error(&mut self) -> Option<NemuError> {
+        self.bus.serial.take_error()
+    }
+
     pub fn has_frame(&mut self) -> bool {
         if self.bus.ppu.frame_ready {
             self.bus.ppu.frame_ready = false;
@@ -77,6 +339,33 @@ impl Nemu {
     pub fn get_framebuffer(&mut self) -> &[u8; 160 * 144] {
         &self.bus.ppu.framebuffer
     }
+
+    /// The screen as 15-bit colors (red in bits 0-4, green in 5-9, blue in 10-14), in DMG mode
+    /// the four shades come out as grays
+    pub fn get_rgb_framebuffer(&self) -> &[u16; 160 * 144] {
+        &self.bus.ppu.rgb_framebuffer
+    }
+
+    /// Whether the loaded cartridge is running with Super Game Boy features
+    pub fn is_sgb(&self) -> bool {
+        self.bus.joypad.sgb.is_some()
+    }
+
+    /// The SGB picture as 15-bit colors: the 256x224 border with the colorized screen at (48, 40).
+    /// Returns `None` unless `is_sgb`.
+    pub fn get_sgb_framebuffer(&self) -> Option<&[u16; 256 * 224]> {
+        self.bus.joypad.sgb.as_ref().map(|sgb| &*sgb.frame)
+    }
+
+    /// Sets the output sample rate in Hz, 0 (the default) disables audio sample generation
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.bus.apu.set_sample_rate(sample_rate);
+    }
+
+    /// Drains the interleaved stereo (left, right) samples generated since the last call
+    pub fn drain_samples(&mut self) -> std::vec::Drain<'_, f32> {
+        self.bus.apu.drain_samples()
+    }
 }
 
 #[cfg(test)]
@@ -98,7 +387,7 @@ mod tests {
             if count == 0 {
                 count = 10000;
 
-                let output = &nemu.bus.serial_output;
+                let output = &nemu.bus.serial.output;
                 if output.contains("Passed") {
                     return true;
                 } else if output.contains("Failed") {
@@ -112,6 +401,1465 @@ mod tests {
         false
     }
 
+    /// For the blargg ROMs that print their result on screen only. Their console writes
+    /// characters straight into the BG map, but the font does not always sit at the tiles
+    /// matching ASCII, so words are matched by the distances between their tile numbers.
+    fn run_screen_rom(path: &str) -> bool {
+        fn screen_shows(nemu: &mut Nemu, word: &str) -> bool {
+            nemu.set_ppu_lockouts(false);
+            let map: Vec<u8> = (0x9800..0x9C00).map(|addr| nemu.bus.peek(addr)).collect();
+            nemu.set_ppu_lockouts(true);
+
+            let word = word.as_bytes();
+            map.windows(word.len()).any(|tiles| {
+                let offset = tiles[0].wrapping_sub(word[0]);
+                tiles.iter().zip(word).all(|(&tile, &c)| tile.wrapping_sub(c) == offset)
+            })
+        }
+
+        let rom_data = std::fs::read(path).expect("Failed to read test ROM");
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&rom_data).expect("Failed to load test ROM");
+        nemu.skip_boot();
+
+        for _ in 0..3000 {
+            nemu.run_frame();
+
+            if screen_shows(&mut nemu, "Passed") {
+                return true;
+            } else if screen_shows(&mut nemu, "Failed") {
+                eprintln!("\x1b[31mTest ROM reported a failure on screen.\x1b[0m");
+                return false;
+            }
+        }
+
+        eprintln!("\x1b[31mTest ROM timed out without a result.\x1b[0m");
+        false
+    }
+
+    // the mooneye test suite is not part of the test-roms submodule, build it from
+    // https://github.com/Gekkio/mooneye-test-suite (or unpack a prebuilt release) into `mooneye/`
+    // at the repository root and run `cargo test -p nemu-core --lib -- --ignored`
+    const MOONEYE_ROMS: &str = "../mooneye";
+
+    fn run_mooneye_rom(path: &str) -> bool {
+        let rom_data = std::fs::read(path).expect("Failed to read test ROM");
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&rom_data).expect("Failed to load test ROM");
+        nemu.skip_boot();
+
+        for _ in 0..100_000_000 {
+            // mooneye tests signal completion with LD B, B
+            if nemu.bus.peek(nemu.cpu.regs.pc) == 0x40 {
+                let regs = &nemu.cpu.regs;
+                let result = [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l];
+
+                if result == [3, 5, 8, 13, 21, 34] {
+                    return true;
+                }
+
+                eprintln!("\x1b[31mMooneye test failed with registers {:?}\x1b[0m", result);
+                return false;
+            }
+
+            nemu.step();
+        }
+
+        eprintln!("\x1b[31mTest ROM timed out without a result.\x1b[0m");
+        false
+    }
+
+    /// Fills in the ROM size code and header checksum so `load_cartridge` accepts the ROM
+    fn fix_header(rom: &mut [u8]) {
+        rom[0x148] = (rom.len() / 0x8000).trailing_zeros() as u8;
+        rom[0x14D] = rom[0x134..=0x14C].iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+    }
+
+    fn test_cartridge(mbc_type: u8, banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * 0x4000];
+        rom[0x147] = mbc_type;
+        rom[0x149] = 0x04;
+        for bank in 0..banks {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        fix_header(&mut rom);
+        rom
+    }
+
+    #[test]
+    fn cartridge_header_validation() {
+        let mut nemu = Nemu::default();
+        assert!(matches!(nemu.load_cartridge(&[0; 0x100]), Err(NemuError::TruncatedRom { len: 0x100 })));
+
+        let mut rom = test_cartridge(0x13, 4);
+        rom[0x134..0x13B].copy_from_slice(b"POKEMON");
+        rom[0x143] = 0x80;
+        rom[0x146] = 0x03;
+        rom[0x14B] = 0x33;
+        rom[0x14E..0x150].copy_from_slice(&[0x12, 0x34]);
+        assert!(matches!(nemu.load_cartridge(&rom), Err(NemuError::BadHeaderChecksum { .. })));
+
+        fix_header(&mut rom);
+        let header = nemu.load_cartridge(&rom).unwrap();
+        assert_eq!(header.title, "POKEMON");
+        assert_eq!(header.cartridge_type, 0x13);
+        assert_eq!(header.rom_size_bytes(), Some(0x10000));
+        assert_eq!(header.ram_size_bytes(), 0x20000);
+        assert_eq!(header.global_checksum, 0x1234);
+        assert!(header.supports_cgb() && header.supports_sgb());
+        assert_eq!(nemu.cartridge_header(), Some(&header));
+
+        let mut short = rom[..0x8000].to_vec();
+        short[0x148] = 0x01;
+        assert!(matches!(
+            nemu.load_cartridge(&short),
+            Err(NemuError::RomSizeMismatch { expected: 0x10000, actual: 0x8000 })
+        ));
+    }
+
+    #[test]
+    fn mbc1_multicart_banking() {
+        let logo = [
+            0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+            0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+            0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+        ];
+
+        let mut rom = test_cartridge(0x01, 64);
+        let mut nemu = Nemu::default();
+        nemu.skip_boot();
+        nemu.load_cartridge(&rom).unwrap();
+        nemu.bus.write(0x4000, 0x01);
+        nemu.bus.write(0x2000, 0x03);
+        assert_eq!(nemu.bus.peek(0x4000), 0x23);
+
+        rom[0x40104..0x40134].copy_from_slice(&logo);
+        nemu.load_cartridge(&rom).unwrap();
+        nemu.bus.write(0x4000, 0x01);
+        nemu.bus.write(0x2000, 0x13);
+        assert_eq!(nemu.bus.peek(0x4000), 0x13);
+        assert_eq!(nemu.bus.peek(0x0000), 0x00);
+
+        nemu.bus.write(0x6000, 0x01);
+        assert_eq!(nemu.bus.peek(0x0000), 0x10);
+    }
+
+    #[test]
+    fn load_version_1_state() {
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&test_cartridge(0x00, 2)).unwrap();
+        nemu.skip_boot();
+
+        // a version 1 blob, the parts that did not change since are written by the current code
+        let mut w = state::StateWriter::new();
+        for byte in state::STATE_MAGIC {
+            w.u8(byte);
+        }
+        w.u16(1);
+        nemu.write_cartridge_id(&mut w);
+
+        // no HALT bug flag
+        let mut cpu = state::StateWriter::new();
+        nemu.cpu.save_state(&mut cpu);
+        let cpu = cpu.finish();
+        cpu[..cpu.len() - 1].iter().for_each(|&byte| w.u8(byte));
+
+        // 8KB of WRAM and no CGB, HDMA or OAM DMA fields
+        w.bytes(&[0x5A; 0x2000]);
+        w.bytes(&nemu.bus.io);
+        w.bytes(&nemu.bus.hram);
+        w.u8(0x00);
+        w.bool(false);
+        nemu.bus.timer.save_state(&mut w);
+
+        // the PPU halfway through mode 3 of line 0x40, with a single VRAM bank and no palettes,
+        // FIFO or STAT line
+        for register in [0x91, 0x83, 0x00, 0x00, 0x40, 0x00, 0xE4, 0xE4, 0xE4, 0x00, 0x07] {
+            w.u8(register);
+        }
+        w.u16(120);
+        w.u8(0x03);
+        w.u8(0x00);
+        let mut vram = [0; 0x2000];
+        vram[0] = 0x12;
+        w.bytes(&vram);
+        w.bytes(&[0; 0xA0]);
+        w.bytes(&[0x02; 160 * 144]);
+        w.bool(false);
+
+        nemu.bus.apu.save_state(&mut w);
+
+        // no SGB flag
+        let mut joypad = state::StateWriter::new();
+        nemu.bus.joypad.save_state(&mut joypad);
+        let joypad = joypad.finish();
+        joypad[..joypad.len() - 1].iter().for_each(|&byte| w.u8(byte));
+
+        nemu.bus.serial.save_state(&mut w);
+        nemu.bus.mbc.save_state(&mut w);
+
+        nemu.load_state(&w.finish()).unwrap();
+        assert!(!nemu.is_cgb());
+        assert_eq!((nemu.bus.peek(0xC000), nemu.bus.peek(0xDFFF)), (0x5A, 0x5A));
+        assert_eq!((nemu.bus.peek(0xFF44), nemu.bus.peek(0xFF41) & 0x03), (0x40, 0x03));
+        assert!(nemu.get_rgb_framebuffer().iter().all(|&color| color == 0x294A));
+
+        // mode 3 is picked up again by the pixel FIFO, the PPU runs a whole frame from there
+        for _ in 0..CYCLES_PER_FRAME / 4 {
+            nemu.bus.tick(1);
+        }
+        assert_eq!(nemu.bus.peek(0xFF44), 0x40);
+        nemu.set_ppu_lockouts(false);
+        assert_eq!(nemu.bus.peek(0x8000), 0x12);
+
+        // and it saves back in the current format
+        let state = nemu.save_state();
+        assert_eq!(u16::from_le_bytes([state[4], state[5]]), state::STATE_VERSION);
+        nemu.load_state(&state).unwrap();
+        assert_eq!(nemu.save_state(), state);
+    }
+
+    #[test]
+    fn save_state_roundtrip() {
+        let mut nemu = Nemu::default();
+        nemu.set_rtc_clock(RtcClock::Emulated);
+        nemu.load_cartridge(&test_cartridge(0x10, 8)).unwrap();
+        nemu.skip_boot();
+
+        nemu.bus.write(0x0000, 0x0A);
+        nemu.bus.write(0x2000, 0x05);
+        nemu.bus.write(0xA010, 0x42);
+        nemu.bus.write(0xC000, 0x99);
+        for _ in 0..20_000 { nemu.step(); }
+
+        let state = nemu.save_state();
+        let pc = nemu.cpu.regs.pc;
+
+        nemu.bus.write(0x2000, 0x02);
+        nemu.bus.write(0xA010, 0x00);
+        nemu.bus.write(0xC000, 0x00);
+        for _ in 0..20_000 { nemu.step(); }
+
+        nemu.load_state(&state).unwrap();
+        assert_eq!(nemu.cpu.regs.pc, pc);
+        assert_eq!(nemu.bus.peek(0x4000), 0x05);
+        assert_eq!(nemu.bus.peek(0xA010), 0x42);
+        assert_eq!(nemu.bus.peek(0xC000), 0x99);
+        assert_eq!(nemu.save_state(), state);
+
+        // the lockouts setting is part of the state
+        nemu.set_ppu_lockouts(false);
+        let unlocked = nemu.save_state();
+        nemu.set_ppu_lockouts(true);
+        nemu.load_state(&unlocked).unwrap();
+        assert!(!nemu.bus.ppu.lockouts());
+        nemu.load_state(&state).unwrap();
+        assert!(nemu.bus.ppu.lockouts());
+
+        let mut bad = state.clone();
+        bad[0] = b'X';
+        assert!(matches!(nemu.load_state(&bad), Err(NemuError::InvalidState(_))));
+
+        let mut bad = state.clone();
+        bad[4] = 0xFF;
+        assert!(matches!(nemu.load_state(&bad), Err(NemuError::UnsupportedStateVersion(0x00FF))));
+
+        // a truncated state is rejected without touching the running machine
+        nemu.bus.write(0xC000, 0x11);
+        assert!(nemu.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(nemu.bus.peek(0xC000), 0x11);
+
+        nemu.load_cartridge(&test_cartridge(0x13, 8)).unwrap();
+        assert!(matches!(nemu.load_state(&state), Err(NemuError::InvalidState(_))));
+    }
+
+    #[test]
+    fn rewind_steps_back_through_frames() {
+        let mut rom = test_cartridge(0x00, 2);
+        // JP 0x150, then INC A / LD (0xC000),A in a loop so every frame leaves WRAM different
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x150..0x156].copy_from_slice(&[0x3C, 0xEA, 0x00, 0xC0, 0x18, 0xFA]);
+
+        for interval in [1, 3] {
+            let mut nemu = Nemu::default();
+            nemu.load_cartridge(&rom).unwrap();
+            nemu.skip_boot();
+            nemu.bus.write(0xFF40, 0x91);
+
+            let mut rewind = Rewind::new(interval, usize::MAX);
+            let mut states = vec![];
+            for _ in 0..10 {
+                nemu.run_frame();
+                rewind.push_frame(&mut nemu);
+                states.push((nemu.save_state(), *nemu.get_framebuffer()));
+            }
+            assert_eq!(rewind.len(), 10 / interval as usize);
+
+            for frame in (interval as usize..10).rev() {
+                assert!(rewind.step_back(&mut nemu));
+                assert_eq!(rewind.frame(), frame as u64);
+                assert_eq!(nemu.save_state(), states[frame - 1].0);
+                assert_eq!(nemu.get_framebuffer(), &states[frame - 1].1);
+            }
+            assert!(!rewind.step_back(&mut nemu));
+
+            // running out of history keeps the oldest snapshot around
+            assert_eq!((rewind.len(), rewind.frame()), (1, interval as u64));
+        }
+
+        // a tight budget only keeps the most recent snapshots
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&rom).unwrap();
+        nemu.skip_boot();
+        nemu.bus.write(0xFF40, 0x91);
+
+        let full = nemu.save_state().len();
+        let mut rewind = Rewind::new(1, full + 256);
+        for _ in 0..50 {
+            nemu.run_frame();
+            rewind.push_frame(&mut nemu);
+            assert!(rewind.memory_used() <= full + 256);
+        }
+        assert!(rewind.len() > 1 && rewind.len() < 50);
+    }
+
+    #[test]
+    fn movie_record_and_playback() {
+        let mut rom = test_cartridge(0x03, 2);
+        // JP 0x150, then keep adding the direction keys to 0xC000 so any change in input shows up
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x150..0x15D].copy_from_slice(&[
+            0x21, 0x00, 0xC0, 0x3E, 0x20, 0xE0, 0x00, 0xF0, 0x00, 0x86, 0x77, 0x18, 0xF6,
+        ]);
+
+        let record = |recorder: &mut MovieRecorder, nemu: &mut Nemu, frames: usize| {
+            for frame in 0..frames {
+                nemu.set_joypad(JoypadButton::RightOrA, frame % 3 == 0, true);
+                nemu.set_joypad(JoypadButton::DownOrStart, frame % 5 == 0, true);
+                recorder.record_frame(nemu);
+            }
+        };
+
+        let mut nemu = Nemu::default();
+        let mut recorder = MovieRecorder::power_on(&mut nemu, &rom).unwrap();
+        record(&mut recorder, &mut nemu, 20);
+        assert_eq!(recorder.frame(), 20);
+
+        let bytes = recorder.finish().to_bytes();
+        let movie = Movie::from_bytes(&bytes).unwrap();
+        assert_eq!(movie.len(), 20);
+        assert!(!movie.starts_from_state());
+
+        let mut replay = Nemu::default();
+        let mut player = MoviePlayer::new(movie, &mut replay, &rom).unwrap();
+        while player.play_frame(&mut replay).unwrap() {}
+        assert_eq!(player.frame(), 20);
+        assert_eq!(replay.save_state(), nemu.save_state());
+
+        // flipping an input is caught on the frame it changes the state
+        let mut tampered = bytes.clone();
+        tampered[0x11 + 7 * 9] ^= 0x10;
+        let mut player = MoviePlayer::new(Movie::from_bytes(&tampered).unwrap(), &mut replay, &rom).unwrap();
+        let result = loop {
+            match player.play_frame(&mut replay) {
+                Ok(true) => {}
+                other => break other,
+            }
+        };
+        assert!(matches!(result, Err(NemuError::MovieDesync { frame: 7 })));
+
+        let mut other_rom = rom.clone();
+        other_rom[0x200] = 0xFF;
+        let movie = Movie::from_bytes(&bytes).unwrap();
+        assert!(matches!(MoviePlayer::new(movie, &mut replay, &other_rom), Err(NemuError::InvalidMovie(_))));
+        assert!(Movie::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        // a corrupt frame count is rejected instead of being allocated for
+        let huge = [&bytes[..0x0D], &[0xFF; 4]].concat();
+        assert!(matches!(Movie::from_bytes(&huge), Err(NemuError::InvalidMovie(_))));
+
+        // version 1 movies have no model byte and still load, as DMG
+        let v1 = [&bytes[..4], &1u16.to_le_bytes(), &bytes[6..0x0A], &bytes[0x0C..]].concat();
+        assert_eq!(Movie::from_bytes(&v1).unwrap().model(), Model::Dmg);
+
+        // versions 1 and 2 have no lockouts byte and replay with the lockouts on
+        let v2 = [&bytes[..4], &2u16.to_le_bytes(), &bytes[6..0x0B], &bytes[0x0C..]].concat();
+        replay.set_ppu_lockouts(false);
+        MoviePlayer::new(Movie::from_bytes(&v2).unwrap(), &mut replay, &rom).unwrap();
+        assert!(replay.bus.ppu.lockouts());
+
+        // the lockouts setting travels with the movie too
+        let mut nemu = Nemu::default();
+        nemu.set_ppu_lockouts(false);
+        let mut recorder = MovieRecorder::power_on(&mut nemu, &rom).unwrap();
+        record(&mut recorder, &mut nemu, 5);
+
+        let movie = Movie::from_bytes(&recorder.finish().to_bytes()).unwrap();
+        let mut replay = Nemu::default();
+        let mut player = MoviePlayer::new(movie, &mut replay, &rom).unwrap();
+        while player.play_frame(&mut replay).unwrap() {}
+        assert!(!replay.bus.ppu.lockouts());
+        assert_eq!(replay.save_state(), nemu.save_state());
+
+        // a movie can also start from the middle of a session, with cartridge RAM carried along
+        nemu.bus.write(0x0000, 0x0A);
+        nemu.bus.write(0xA000, 0x42);
+        let mut recorder = MovieRecorder::from_state(&mut nemu, &rom).unwrap();
+        record(&mut recorder, &mut nemu, 10);
+
+        let movie = Movie::from_bytes(&recorder.finish().to_bytes()).unwrap();
+        assert!(movie.starts_from_state());
+
+        let mut replay = Nemu::default();
+        let mut player = MoviePlayer::new(movie, &mut replay, &rom).unwrap();
+        while player.play_frame(&mut replay).unwrap() {}
+        assert!(player.is_finished());
+        assert_eq!(replay.bus.peek(0xA000), 0x42);
+        assert_eq!(replay.save_state(), nemu.save_state());
+
+        // the console model travels with the movie, a CGB recording replays on a default Nemu
+        let mut cgb_rom = rom.clone();
+        cgb_rom[0x143] = 0x80;
+        fix_header(&mut cgb_rom);
+
+        let mut nemu = Nemu::default();
+        nemu.set_model(Model::Cgb);
+        let mut recorder = MovieRecorder::power_on(&mut nemu, &cgb_rom).unwrap();
+        record(&mut recorder, &mut nemu, 10);
+
+        let movie = Movie::from_bytes(&recorder.finish().to_bytes()).unwrap();
+        assert_eq!(movie.model(), Model::Cgb);
+
+        let mut replay = Nemu::default();
+        let mut player = MoviePlayer::new(movie, &mut replay, &cgb_rom).unwrap();
+        while player.play_frame(&mut replay).unwrap() {}
+        assert!(replay.is_cgb());
+        assert_eq!(replay.save_state(), nemu.save_state());
+    }
+
+    #[test]
+    fn cgb_mode() {
+        let mut rom = test_cartridge(0x00, 2);
+        rom[0x143] = 0x80;
+        // JP 0x150, STOP, then spin
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x150..0x154].copy_from_slice(&[0x10, 0x00, 0x18, 0xFE]);
+        fix_header(&mut rom);
+
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&rom).unwrap();
+        assert!(!nemu.is_cgb());
+
+        nemu.set_model(Model::Cgb);
+        nemu.load_cartridge(&rom).unwrap();
+        assert!(nemu.is_cgb());
+        assert_eq!((nemu.cpu.regs.a, nemu.cpu.regs.pc), (0x11, 0x100));
+
+        // WRAM banks 1-7 at 0xD000, bank 0 selects 1
+        for bank in 1..8 {
+            nemu.bus.write(0xFF70, bank);
+            nemu.bus.write(0xD000, bank * 0x11);
+        }
+        nemu.bus.write(0xFF70, 0);
+        assert_eq!(nemu.bus.peek(0xFF70), 0xF9);
+        assert_eq!(nemu.bus.peek(0xD000), 0x11);
+        nemu.bus.write(0xFF70, 5);
+        assert_eq!(nemu.bus.peek(0xD000), 0x55);
+        assert_eq!(nemu.bus.peek(0xF000), 0x55);
+
+        // VRAM bank 1 holds the tile attributes, palette 2 color 1 is pure red. The LCD is off
+        // so VRAM stays reachable.
+        nemu.bus.write(0xFF40, 0x00);
+        nemu.bus.write(0xFF4F, 1);
+        for i in 0..0x400 { nemu.bus.write(0x9800 + i, 0x02); }
+        nemu.bus.write(0xFF4F, 0);
+        assert_eq!(nemu.bus.peek(0x9800), 0x00);
+
+        nemu.bus.write(0xFF68, 0x80 | (2 * 8 + 2));
+        nemu.bus.write(0xFF69, 0x1F);
+        nemu.bus.write(0xFF69, 0x00);
+        assert_eq!(nemu.bus.peek(0xFF68), 0xC0 | (2 * 8 + 4));
+
+        // general purpose HDMA copies tile 0 (color 1 on every row) from WRAM straight away
+        for i in 0..0x10 { nemu.bus.write(0xC000 + i, if i % 2 == 0 { 0xFF } else { 0x00 }); }
+        nemu.bus.write(0xFF51, 0xC0);
+        nemu.bus.write(0xFF52, 0x00);
+        nemu.bus.write(0xFF53, 0x00);
+        nemu.bus.write(0xFF54, 0x00);
+        nemu.bus.write(0xFF55, 0x00);
+        assert_eq!(nemu.bus.peek(0xFF55), 0xFF);
+        assert_eq!(nemu.bus.peek(0x8000), 0xFF);
+
+        // a frame may still be pending from before the LCD went off
+        nemu.bus.write(0xFF40, 0x91);
+        nemu.run_frame();
+        nemu.run_frame();
+        assert!(nemu.get_rgb_framebuffer().iter().all(|&color| color == 0x001F));
+
+        // HBlank HDMA moves one block per line, the address registers kept counting up
+        nemu.bus.write(0xFF52, 0x00);
+        nemu.bus.write(0xFF53, 0x01);
+        nemu.bus.write(0xFF54, 0x00);
+        nemu.bus.write(0xFF55, 0x81);
+        assert_eq!(nemu.bus.peek(0xFF55), 0x01);
+        while nemu.bus.peek(0xFF55) == 0x01 { nemu.step(); }
+        assert_eq!(nemu.bus.peek(0xFF55), 0x00);
+        while nemu.bus.peek(0xFF55) == 0x00 { nemu.step(); }
+        assert_eq!(nemu.bus.peek(0xFF55), 0xFF);
+        assert_eq!(nemu.bus.peek(0x8100), 0xFF);
+
+        // STOP with KEY1 armed switches to double speed, where steps take half the time
+        nemu.bus.write(0xFF4D, 0x01);
+        assert_eq!(nemu.bus.peek(0xFF4D), 0x7F);
+        nemu.cpu.regs.pc = 0x150;
+        nemu.step();
+        assert_eq!(nemu.bus.peek(0xFF4D), 0xFE);
+        assert_eq!(nemu.step(), 6);
+
+        let state = nemu.save_state();
+        nemu.load_state(&state).unwrap();
+        assert!(nemu.is_cgb());
+        assert_eq!(nemu.save_state(), state);
+    }
+
+    #[test]
+    fn ppu_fifo_timing() {
+        // M-cycles from turning the LCD on until HBlank, mode 2 is 80 dots and mode 3 at least 172
+        fn hblank_after(nemu: &mut Nemu) -> u32 {
+            nemu.bus.write(0xFF40, 0x00);
+            nemu.bus.write(0xFF40, 0xB3);
+            let mut cycles = 0;
+            while nemu.bus.peek(0xFF41) & 0x03 != 0x00 {
+                nemu.bus.tick(1);
+                cycles += 1;
+            }
+            cycles
+        }
+
+        let mut nemu = Nemu::default();
+        nemu.bus.write(0xFF4B, 0xFF);
+        assert_eq!(hblank_after(&mut nemu), 252 / 4);
+
+        // fine scroll throws pixels away, one dot each
+        nemu.bus.write(0xFF43, 0x04);
+        assert_eq!(hblank_after(&mut nemu), 256 / 4);
+        nemu.bus.write(0xFF43, 0x00);
+
+        // the window restarts the fetcher, a sprite at the start of a tile waits for it
+        nemu.bus.write(0xFF4A, 0x00);
+        nemu.bus.write(0xFF4B, 0x57);
+        assert_eq!(hblank_after(&mut nemu), 260 / 4);
+        nemu.bus.write(0xFF4B, 0xFF);
+
+        nemu.bus.write(0xFE00, 16);
+        nemu.bus.write(0xFE01, 8);
+        assert_eq!(hblank_after(&mut nemu), 264 / 4);
+        nemu.bus.write(0xFE00, 0);
+
+        // BGP written in the middle of mode 3 only affects the pixels after it, the first pixel
+        // goes out 12 dots into mode 3 and the write itself takes an M-cycle
+        nemu.bus.write(0xFF47, 0x00);
+        nemu.bus.write(0xFF40, 0x00);
+        nemu.bus.write(0xFF40, 0x91);
+        for _ in 0..(80 + 12 + 40) / 4 - 1 {
+            nemu.bus.tick(1);
+        }
+        nemu.bus.write(0xFF47, 0xFF);
+        for _ in 0..456 / 4 {
+            nemu.bus.tick(1);
+        }
+
+        let line = &nemu.get_framebuffer()[..160];
+        assert!(line[..40].iter().all(|&shade| shade == 0));
+        assert!(line[40..].iter().all(|&shade| shade == 3));
+    }
+
+    #[test]
+    fn halt_bug() {
+        let mut nemu = Nemu::default();
+        for (i, op) in [0x76, 0x3C, 0xFB, 0x76, 0x00, 0x76, 0x3C].into_iter().enumerate() {
+            nemu.bus.write(0xC000 + i as u16, op);
+        }
+        nemu.bus.write(0xFFFF, interrupts::INT_TIMER);
+        nemu.bus.write(0xFF0F, interrupts::INT_TIMER);
+        (nemu.cpu.regs.pc, nemu.cpu.regs.sp, nemu.cpu.regs.a) = (0xC000, 0xD000, 0x00);
+
+        // IME off with an interrupt pending, HALT falls through and INC A is read twice
+        nemu.cpu.step(&mut nemu.bus);
+        assert!(!nemu.cpu.halted);
+        nemu.cpu.step(&mut nemu.bus);
+        nemu.cpu.step(&mut nemu.bus);
+        assert_eq!((nemu.cpu.regs.a, nemu.cpu.regs.pc), (0x02, 0xC002));
+
+        // EI, HALT, the interrupt is taken and returns to the HALT
+        nemu.cpu.step(&mut nemu.bus);
+        nemu.cpu.step(&mut nemu.bus);
+        nemu.cpu.step(&mut nemu.bus);
+        assert_eq!(nemu.cpu.regs.pc, 0x0050);
+        assert_eq!((nemu.bus.peek(0xCFFE), nemu.bus.peek(0xCFFF)), (0x03, 0xC0));
+
+        // IME off and nothing pending, HALT waits and wakes up without taking the interrupt
+        nemu.cpu.ime = cpu::InterruptMode::Disabled;
+        nemu.cpu.regs.pc = 0xC005;
+        nemu.cpu.step(&mut nemu.bus);
+        nemu.cpu.step(&mut nemu.bus);
+        assert!(nemu.cpu.halted);
+        nemu.bus.write(0xFF0F, interrupts::INT_TIMER);
+        nemu.cpu.step(&mut nemu.bus);
+        nemu.cpu.step(&mut nemu.bus);
+        assert_eq!((nemu.cpu.regs.a, nemu.cpu.regs.pc), (0x03, 0xC007));
+        assert_ne!(nemu.bus.peek(0xFF0F) & interrupts::INT_TIMER, 0);
+    }
+
+    #[test]
+    fn halt_bug_rom() {
+        // halt_bug.gb sits at the root of the test ROMs and has no serial output
+        assert!(run_screen_rom("../tests/halt_bug.gb"));
+    }
+
+    #[test]
+    fn ppu_lockouts() {
+        let mut nemu = Nemu::default();
+        let mode = |nemu: &Nemu| nemu.bus.peek(0xFF41) & 0x03;
+
+        // OAM scan, VRAM is still free
+        nemu.bus.write(0xFF40, 0x91);
+        nemu.bus.write(0x8000, 0x12);
+        nemu.bus.write(0xFE00, 0x34);
+        assert_eq!(nemu.bus.peek(0x8000), 0x12);
+        assert_eq!(nemu.bus.peek(0xFE00), 0xFF);
+
+        while mode(&nemu) != 0x03 { nemu.bus.tick(1); }
+        nemu.bus.write(0x8000, 0x56);
+        assert_eq!(nemu.bus.peek(0x8000), 0xFF);
+
+        while mode(&nemu) != 0x00 { nemu.bus.tick(1); }
+        assert_eq!(nemu.bus.peek(0x8000), 0x12);
+        assert_eq!(nemu.bus.peek(0xFE00), 0x00);
+
+        nemu.set_ppu_lockouts(false);
+        while mode(&nemu) != 0x03 { nemu.bus.tick(1); }
+        nemu.bus.write(0x8000, 0x56);
+        nemu.bus.write(0xFE00, 0x34);
+        assert_eq!(nemu.bus.peek(0x8000), 0x56);
+        assert_eq!(nemu.bus.peek(0xFE00), 0x34);
+    }
+
+    #[test]
+    fn oam_bug() {
+        let mut nemu = Nemu::default();
+        for i in 0..0xA0 {
+            nemu.bus.write(0xFE00 + i, (i * 37 + 11) as u8);
+        }
+        nemu.bus.write(0xC000, 0x23); // INC HL
+        nemu.bus.write(0xFF40, 0x91);
+        nemu.set_ppu_lockouts(false);
+
+        let line = |nemu: &mut Nemu, ly: u8| while nemu.bus.peek(0xFF44) != ly { nemu.bus.tick(1); };
+        let oam_row = |nemu: &Nemu, row: u16| (0..8).map(|i| nemu.bus.peek(0xFE00 + row * 8 + i)).collect::<Vec<_>>();
+
+        // INC HL with HL in OAM, the IDU cycle lands on row 5
+        line(&mut nemu, 1);
+        nemu.bus.tick(4);
+        nemu.cpu.regs.pc = 0xC000;
+        (nemu.cpu.regs.h, nemu.cpu.regs.l) = (0xFE, 0x00);
+        nemu.cpu.step(&mut nemu.bus);
+        assert_eq!(oam_row(&nemu, 5), [0xBB, 0xF0, 0xF5, 0x1A, 0x3F, 0x64, 0x89, 0xAE]);
+
+        // a plain read on row 8
+        line(&mut nemu, 2);
+        nemu.bus.tick(8);
+        nemu.bus.read(0xFE00);
+        assert_eq!(oam_row(&nemu, 8), [0x23, 0x58, 0x6D, 0x92, 0xB7, 0xDC, 0x01, 0x26]);
+
+        // nothing happens outside of the OAM scan
+        line(&mut nemu, 3);
+        while nemu.bus.peek(0xFF41) & 0x03 != 0x00 { nemu.bus.tick(1); }
+        nemu.bus.write(0xFE00, 0x00);
+        assert_eq!(oam_row(&nemu, 8), [0x23, 0x58, 0x6D, 0x92, 0xB7, 0xDC, 0x01, 0x26]);
+    }
+
+    #[test]
+    fn oam_bug_suite() {
+        for rom in [
+            "1-lcd_sync", "2-causes", "3-non_causes", "4-scanline_timing", "5-timing_bug", "6-timing_no_bug",
+            "7-timing_effect", "8-instr_effect",
+        ] {
+            let path = format!("../tests/oam_bug/rom_singles/{}.gb", rom);
+            assert!(run_test_rom(&path), "{}", rom);
+        }
+    }
+
+    #[test]
+    fn oam_dma_transfer() {
+        let mut nemu = Nemu::default();
+        for i in 0..0xA0 {
+            nemu.bus.write(0xC000 + i, i as u8 + 1);
+            nemu.bus.write(0xD000 + i, (0x80 + i) as u8);
+        }
+
+        // one startup cycle after the write, then a byte per M-cycle
+        nemu.bus.write(0xFF46, 0xC0);
+        nemu.bus.tick(1);
+        assert_eq!(nemu.bus.peek(0xFE00), 0x00);
+        nemu.bus.tick(1);
+        assert_eq!(nemu.bus.peek(0xFE00), 0x01);
+        assert_eq!(nemu.bus.peek(0xFF46), 0xC0);
+
+        // only HRAM is reachable, the external bus gives the byte being copied and OAM gives 0xFF
+        nemu.bus.write(0xFF80, 0x42);
+        assert_eq!(nemu.bus.read(0xFF80), 0x42);
+        assert_eq!(nemu.bus.read(0x0000), 0x04);
+        assert_eq!(nemu.bus.read(0xFE00), 0xFF);
+        nemu.bus.write(0xC000, 0x00);
+
+        for _ in 0..0xA0 {
+            nemu.bus.tick(1);
+        }
+        assert_eq!(nemu.bus.read(0xFE9F), 0xA0);
+        assert_eq!(nemu.bus.read(0xC000), 0x01);
+
+        // a restart keeps the old transfer going until the new one is through its startup cycle
+        nemu.bus.write(0xFF46, 0xC0);
+        for _ in 0..11 {
+            nemu.bus.tick(1);
+        }
+        nemu.bus.write(0xFF46, 0xD0);
+        nemu.bus.tick(1);
+        nemu.bus.tick(1);
+        assert_eq!(nemu.bus.peek(0xFE00), 0x80);
+        assert_eq!(nemu.bus.peek(0xFE01), 0x02);
+        assert_eq!(nemu.bus.peek(0xFE0B), 0x0C);
+    }
+
+    #[test]
+    #[ignore = "needs the mooneye test suite ROMs, see MOONEYE_ROMS"]
+    fn mooneye_oam_dma() {
+        for rom in [
+            "oam_dma/basic", "oam_dma/reg_read", "oam_dma/sources-GS", "oam_dma_restart", "oam_dma_start",
+            "oam_dma_timing",
+        ] {
+            let path = format!("{}/acceptance/{}.gb", MOONEYE_ROMS, rom);
+            assert!(run_mooneye_rom(&path), "{}", rom);
+        }
+    }
+
+    #[test]
+    fn stat_irq_blocking() {
+        let mut nemu = Nemu::default();
+        nemu.bus.write(0xFF41, 0x28);
+        nemu.bus.write(0xFF40, 0x91);
+        nemu.bus.write(0xFF0F, 0x00);
+
+        let mode = |nemu: &Nemu| nemu.bus.peek(0xFF41) & 0x03;
+        let stat_irq = |nemu: &Nemu| nemu.bus.peek(0xFF0F) & interrupts::INT_LCDSTAT;
+
+        // mode 3 drops the line, HBlank raises it again
+        while mode(&nemu) != 0x00 { nemu.bus.tick(1); }
+        assert_ne!(stat_irq(&nemu), 0);
+        nemu.bus.write(0xFF0F, 0x00);
+
+        // the next line's mode 2 follows HBlank directly, so the line never drops and it is blocked
+        while mode(&nemu) != 0x03 { nemu.bus.tick(1); }
+        assert_eq!(stat_irq(&nemu), 0);
+
+        // after mode 3 the HBlank edge requests it again
+        while mode(&nemu) != 0x00 { nemu.bus.tick(1); }
+        assert_ne!(stat_irq(&nemu), 0);
+
+        // DMG sees every source enabled while STAT is written, which fires in HBlank
+        nemu.bus.write(0xFF41, 0x00);
+        nemu.bus.write(0xFF0F, 0x00);
+        while mode(&nemu) != 0x03 { nemu.bus.tick(1); }
+        while mode(&nemu) != 0x00 { nemu.bus.tick(1); }
+        nemu.bus.write(0xFF41, 0x00);
+        assert_ne!(stat_irq(&nemu), 0);
+
+        // a LYC write matching LY raises it straight away
+        nemu.bus.write(0xFF0F, 0x00);
+        nemu.bus.write(0xFF41, 0x40);
+        nemu.bus.write(0xFF0F, 0x00);
+        let ly = nemu.bus.peek(0xFF44);
+        nemu.bus.write(0xFF45, ly);
+        assert_ne!(stat_irq(&nemu), 0);
+    }
+
+    #[test]
+    #[ignore = "needs the mooneye test suite ROMs, see MOONEYE_ROMS"]
+    fn mooneye_stat_irq() {
+        for rom in ["stat_irq_blocking", "stat_lyc_onoff"] {
+            let path = format!("{}/acceptance/ppu/{}.gb", MOONEYE_ROMS, rom);
+            assert!(run_mooneye_rom(&path), "{}", rom);
+        }
+    }
+
+    #[test]
+    fn sgb_packets_and_border() {
+        fn send_packet(nemu: &mut Nemu, packet: &[u8]) {
+            let mut data = [0; 16];
+            data[..packet.len()].copy_from_slice(packet);
+
+            nemu.bus.write(0xFF00, 0x00);
+            nemu.bus.write(0xFF00, 0x30);
+            // 128 bits LSB first and a 0 stop bit, P15 low sends a 1 and P14 low a 0
+            for bit in (0..128).map(|i| (data[i / 8] >> (i % 8)) & 0x01).chain([0]) {
+                nemu.bus.write(0xFF00, if bit != 0 { 0x10 } else { 0x20 });
+                nemu.bus.write(0xFF00, 0x30);
+            }
+        }
+
+        let mut rom = test_cartridge(0x00, 2);
+        rom[0x146] = 0x03;
+        rom[0x14B] = 0x33;
+        // JR -2
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        fix_header(&mut rom);
+
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&rom).unwrap();
+        assert!(!nemu.is_sgb() && nemu.get_sgb_framebuffer().is_none());
+
+        nemu.set_model(Model::Sgb);
+        nemu.load_cartridge(&rom).unwrap();
+        nemu.skip_boot();
+        assert!(nemu.is_sgb());
+
+        // LCD on, every BG pixel comes out as shade 3
+        nemu.bus.write(0xFF40, 0x91);
+        nemu.bus.write(0xFF47, 0xFF);
+
+        // PAL01 with a shared color 0, then ATTR_DIV splitting the screen at column 10
+        send_packet(&mut nemu, &[0x01, 0x34, 0x12, 0, 0, 0, 0, 0x1F, 0, 0, 0, 0, 0, 0xE0, 0x03]);
+        send_packet(&mut nemu, &[0x31, 0x21, 10]);
+
+        nemu.run_frame();
+        nemu.run_frame();
+        let frame = nemu.get_sgb_framebuffer().unwrap();
+        assert_eq!(frame[0], 0x1234);
+        assert_eq!(frame[40 * 256 + 48], 0x001F);
+        assert_eq!(frame[40 * 256 + 48 + 84], 0x2866);
+        assert_eq!(frame[(40 + 143) * 256 + 48 + 159], 0x03E0);
+
+        // CHR_TRN loads a border tile of color 1, PCT_TRN makes palette 4 color 1 blue
+        for i in 0..8 { nemu.bus.write(0x8000 + i * 2, 0xFF); }
+        send_packet(&mut nemu, &[0x99, 0x00]);
+        nemu.run_frame();
+        for i in 0..0x10 { nemu.bus.write(0x8000 + i, 0x00); }
+        nemu.bus.write(0x8802, 0x00);
+        nemu.bus.write(0x8803, 0x7C);
+        send_packet(&mut nemu, &[0xA1]);
+        nemu.run_frame();
+        let frame = nemu.get_sgb_framebuffer().unwrap();
+        assert_eq!(frame[0], 0x7C00);
+        assert_eq!(frame[40 * 256 + 48], 0x001F);
+
+        // MLT_REQ for two players, P15 going high again moves on to the next controller
+        send_packet(&mut nemu, &[0x89, 0x01]);
+        assert_eq!(nemu.bus.peek(0xFF00) & 0x0F, 0x0F);
+        nemu.bus.write(0xFF00, 0x10);
+        nemu.bus.write(0xFF00, 0x30);
+        assert_eq!(nemu.bus.peek(0xFF00) & 0x0F, 0x0E);
+
+        let state = nemu.save_state();
+        nemu.load_state(&state).unwrap();
+        assert!(nemu.is_sgb());
+        assert_eq!(nemu.save_state(), state);
+    }
+
+    #[test]
+    fn battery_save_roundtrip() {
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&test_cartridge(0x02, 4)).unwrap();
+        assert!(nemu.export_save().is_none());
+        assert!(nemu.import_save(&[0; 0x20000]).is_err());
+
+        let mut rom = test_cartridge(0x03, 4);
+        rom[0x149] = 0x02;
+        fix_header(&mut rom);
+        nemu.load_cartridge(&rom).unwrap();
+
+        nemu.bus.write(0x0000, 0x0A);
+        assert!(!nemu.save_dirty());
+        nemu.bus.write(0xA123, 0x42);
+        assert!(nemu.save_dirty());
+
+        let save = nemu.export_save().unwrap();
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!(save[0x123], 0x42);
+        assert!(!nemu.save_dirty());
+
+        nemu.load_cartridge(&rom).unwrap();
+        assert!(nemu.import_save(&save[..0x1000]).is_err());
+        nemu.import_save(&save).unwrap();
+        nemu.bus.write(0x0000, 0x0A);
+        assert_eq!(nemu.bus.peek(0xA123), 0x42);
+        assert!(!nemu.save_dirty());
+
+        // MBC3 timer carts carry the clock registers after the RAM
+        let mut nemu = Nemu::default();
+        nemu.set_rtc_clock(RtcClock::Emulated);
+        nemu.load_cartridge(&test_cartridge(0x10, 4)).unwrap();
+        nemu.bus.write(0x0000, 0x0A);
+        nemu.bus.write(0x4000, 0x09);
+        nemu.bus.write(0xA000, 42);
+
+        let save = nemu.export_save().unwrap();
+        assert_eq!(save.len(), 0x20000 + 48);
+        assert_eq!(save[0x20000 + 4], 42);
+
+        nemu.load_cartridge(&test_cartridge(0x10, 4)).unwrap();
+        nemu.import_save(&save).unwrap();
+        nemu.bus.write(0x0000, 0x0A);
+        nemu.bus.write(0x6000, 0x00);
+        nemu.bus.write(0x6000, 0x01);
+        nemu.bus.write(0x4000, 0x09);
+        assert_eq!(nemu.bus.peek(0xA000) & 0x3F, 42);
+    }
+
+    #[test]
+    #[ignore = "needs the mooneye test suite ROMs, see MOONEYE_ROMS"]
+    fn mooneye_mbc1() {
+        for rom in [
+            "bits_bank1", "bits_bank2", "bits_mode", "bits_ramg", "multicart_rom_8Mb", "ram_64kb",
+            "ram_256kb", "rom_512kb", "rom_1Mb", "rom_2Mb", "rom_4Mb", "rom_8Mb", "rom_16Mb",
+        ] {
+            let path = format!("{}/emulator-only/mbc1/{}.gb", MOONEYE_ROMS, rom);
+            assert!(run_mooneye_rom(&path), "{}", rom);
+        }
+    }
+
+    #[test]
+    fn mbc2_banking_and_ram() {
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&test_cartridge(0x06, 16)).unwrap();
+        assert!(nemu.has_battery());
+
+        // bit 8 clear selects RAM enable, set selects the ROM bank
+        nemu.bus.write(0x2100, 0x05);
+        assert_eq!(nemu.bus.peek(0x4000), 5);
+        nemu.bus.write(0x2100, 0x00);
+        assert_eq!(nemu.bus.peek(0x4000), 1);
+
+        assert_eq!(nemu.bus.peek(0xA000), 0xFF);
+        nemu.bus.write(0x0000, 0x0A);
+        nemu.bus.write(0xA001, 0xAB);
+        assert_eq!(nemu.bus.peek(0xA001), 0xFB);
+        assert_eq!(nemu.bus.peek(0xA201), 0xFB);
+        assert_eq!(nemu.bus.peek(0xBE01), 0xFB);
+    }
+
+    #[test]
+    #[ignore = "needs the mooneye test suite ROMs, see MOONEYE_ROMS"]
+    fn mooneye_mbc2() {
+        for rom in ["bits_ramg", "bits_romb", "bits_unused", "ram", "rom_512kb", "rom_1Mb", "rom_2Mb"] {
+            let path = format!("{}/emulator-only/mbc2/{}.gb", MOONEYE_ROMS, rom);
+            assert!(run_mooneye_rom(&path), "{}", rom);
+        }
+    }
+
+    #[test]
+    fn mbc3_banking_and_rtc() {
+        let mut nemu = Nemu::default();
+        nemu.set_rtc_clock(RtcClock::Emulated);
+        nemu.load_cartridge(&test_cartridge(0x10, 128)).unwrap();
+        assert!(nemu.has_battery());
+
+        nemu.bus.write(0x2000, 0x7F);
+        assert_eq!(nemu.bus.peek(0x4000), 0x7F);
+        nemu.bus.write(0x2000, 0x00);
+        assert_eq!(nemu.bus.peek(0x4000), 1);
+
+        nemu.bus.write(0x0000, 0x0A);
+        nemu.bus.write(0x4000, 0x03);
+        nemu.bus.write(0xA000, 0x42);
+        nemu.bus.write(0x4000, 0x00);
+        assert_eq!(nemu.bus.peek(0xA000), 0x00);
+        nemu.bus.write(0x4000, 0x03);
+        assert_eq!(nemu.bus.peek(0xA000), 0x42);
+
+        // 23:59:58 on day 511, two seconds later the day counter overflows
+        for (register, value) in [(0x08, 58), (0x09, 59), (0x0A, 23), (0x0B, 0xFF), (0x0C, 0x01)] {
+            nemu.bus.write(0x4000, register);
+            nemu.bus.write(0xA000, value);
+        }
+        for _ in 0..2 << 20 {
+            nemu.bus.tick(1);
+        }
+
+        let latch = |nemu: &mut Nemu| {
+            nemu.bus.write(0x6000, 0x00);
+            nemu.bus.write(0x6000, 0x01);
+        };
+        let read = |nemu: &mut Nemu, register: u8| {
+            nemu.bus.write(0x4000, register);
+            nemu.bus.peek(0xA000)
+        };
+
+        latch(&mut nemu);
+        assert_eq!(read(&mut nemu, 0x08) & 0x3F, 0);
+        assert_eq!(read(&mut nemu, 0x09) & 0x3F, 0);
+        assert_eq!(read(&mut nemu, 0x0A) & 0x1F, 0);
+        assert_eq!(read(&mut nemu, 0x0B), 0);
+        assert_eq!(read(&mut nemu, 0x0C) & 0xC1, 0x80);
+
+        // halting stops the clock, and the latched values stay put until the next latch
+        nemu.bus.write(0x4000, 0x0C);
+        nemu.bus.write(0xA000, 0x40);
+        for _ in 0..2 << 20 {
+            nemu.bus.tick(1);
+        }
+        assert_eq!(read(&mut nemu, 0x0C) & 0xC1, 0x80);
+        latch(&mut nemu);
+        assert_eq!(read(&mut nemu, 0x08) & 0x3F, 0);
+        assert_eq!(read(&mut nemu, 0x0C) & 0xC1, 0x40);
+    }
+
+    #[test]
+    fn mbc5_banking_and_rumble() {
+        let mut nemu = Nemu::default();
+        nemu.skip_boot();
+        nemu.load_cartridge(&test_cartridge(0x1E, 512)).unwrap();
+        assert!(nemu.has_battery());
+
+        // the 9th bit comes from a separate register, and bank 0 is not remapped
+        nemu.bus.write(0x2000, 0x34);
+        nemu.bus.write(0x3000, 0x01);
+        assert_eq!(nemu.bus.peek(0x4000), 0x34);
+        assert_eq!(nemu.bus.peek(0x0000), 0x00);
+        nemu.bus.write(0x2000, 0x00);
+        nemu.bus.write(0x3000, 0x00);
+        assert_eq!(nemu.bus.peek(0x4000), 0x00);
+
+        nemu.bus.write(0x0000, 0x0A);
+        nemu.bus.write(0x4000, 0x0F);
+        assert!(nemu.rumble_active());
+        nemu.bus.write(0xA000, 0x42);
+        nemu.bus.write(0x4000, 0x07);
+        assert!(!nemu.rumble_active());
+        assert_eq!(nemu.bus.peek(0xA000), 0x42);
+        nemu.bus.write(0x4000, 0x00);
+        assert_eq!(nemu.bus.peek(0xA000), 0x00);
+
+        nemu.load_cartridge(&test_cartridge(0x1B, 4)).unwrap();
+        nemu.bus.write(0x0000, 0x0A);
+        nemu.bus.write(0x4000, 0x0F);
+        assert!(!nemu.rumble_active());
+        nemu.bus.write(0xA000, 0x42);
+        nemu.bus.write(0x4000, 0x07);
+        assert_eq!(nemu.bus.peek(0xA000), 0x00);
+    }
+
+    #[test]
+    fn huc1_ir_mode() {
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&test_cartridge(0xFF, 64)).unwrap();
+        assert!(nemu.has_battery());
+
+        nemu.bus.write(0x2000, 0x3F);
+        assert_eq!(nemu.bus.peek(0x4000), 0x3F);
+
+        nemu.bus.write(0x4000, 0x02);
+        nemu.bus.write(0xA000, 0x42);
+        assert_eq!(nemu.bus.peek(0xA000), 0x42);
+
+        nemu.bus.write(0x0000, 0x0E);
+        assert_eq!(nemu.bus.peek(0xA000), 0xC0);
+        nemu.bus.write(0xA000, 0x01);
+        nemu.bus.write(0x0000, 0x00);
+        assert_eq!(nemu.bus.peek(0xA000), 0x42);
+    }
+
+    #[test]
+    fn huc3_rtc_commands() {
+        let mut nemu = Nemu::default();
+        nemu.set_rtc_clock(RtcClock::Emulated);
+        nemu.load_cartridge(&test_cartridge(0xFE, 128)).unwrap();
+
+        nemu.bus.write(0x0000, 0x0A);
+        nemu.bus.write(0xA000, 0x42);
+        nemu.bus.write(0x0000, 0x00);
+        nemu.bus.write(0xA000, 0x00);
+        assert_eq!(nemu.bus.peek(0xA000), 0x42);
+
+        let command = |nemu: &mut Nemu, value: u8| {
+            nemu.bus.write(0x0000, 0x0B);
+            nemu.bus.write(0xA000, value);
+            nemu.bus.write(0x0000, 0x0D);
+            assert_eq!(nemu.bus.peek(0xA000) & 0x01, 0x01);
+            nemu.bus.write(0x0000, 0x0C);
+            nemu.bus.peek(0xA000) & 0x0F
+        };
+
+        // 23:59 (1439 minutes = 0x59F) on day 0x0123, one minute later the day rolls over
+        command(&mut nemu, 0x40);
+        command(&mut nemu, 0x50);
+        for nibble in [0xF, 0x9, 0x5, 0x3, 0x2, 0x1, 0x0] {
+            command(&mut nemu, 0x30 | nibble);
+        }
+        for _ in 0..(60 << 20) / 0x80 {
+            nemu.bus.mbc.tick(0x80);
+        }
+
+        command(&mut nemu, 0x40);
+        let time: Vec<u8> = (0..7).map(|_| command(&mut nemu, 0x10)).collect();
+        assert_eq!(time, [0x0, 0x0, 0x0, 0x4, 0x2, 0x1, 0x0]);
+
+        command(&mut nemu, 0x62);
+        assert_eq!(nemu.tone_generator(), 0x02);
+
+        // the clock is carried in battery saves
+        let save = nemu.export_save().unwrap();
+        let mut other = Nemu::default();
+        other.set_rtc_clock(RtcClock::Emulated);
+        other.load_cartridge(&test_cartridge(0xFE, 128)).unwrap();
+        other.import_save(&save).unwrap();
+        command(&mut other, 0x40);
+        command(&mut other, 0x50);
+        let time: Vec<u8> = (0..7).map(|_| command(&mut other, 0x10)).collect();
+        assert_eq!(time, [0x0, 0x0, 0x0, 0x4, 0x2, 0x1, 0x0]);
+    }
+
+    #[test]
+    fn mbc7_accelerometer_and_eeprom() {
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&test_cartridge(0x22, 8)).unwrap();
+        assert!(nemu.has_battery());
+
+        nemu.bus.write(0x0000, 0x0A);
+        assert_eq!(nemu.bus.peek(0xA020), 0xFF);
+        nemu.bus.write(0x4000, 0x40);
+
+        nemu.set_tilt(1.0, -1.0);
+        nemu.bus.write(0xA000, 0x55);
+        assert_eq!(nemu.bus.peek(0xA030), 0x80);
+        nemu.bus.write(0xA010, 0xAA);
+        let x = u16::from_le_bytes([nemu.bus.peek(0xA020), nemu.bus.peek(0xA030)]);
+        let y = u16::from_le_bytes([nemu.bus.peek(0xA040), nemu.bus.peek(0xA050)]);
+        assert_eq!((x, y), (0x81D0 + 0x70, 0x81D0 - 0x70));
+
+        // without erasing first the latch keeps its old values
+        nemu.set_tilt(0.0, 0.0);
+        nemu.bus.write(0xA010, 0xAA);
+        assert_eq!(nemu.bus.peek(0xA020), 0x40);
+
+        let send = |nemu: &mut Nemu, bits: u32, count: u32| {
+            let mut out = 0u32;
+            for i in (0..count).rev() {
+                let di = (((bits >> i) & 1) as u8) << 1;
+                nemu.bus.write(0xA080, 0x80 | di);
+                nemu.bus.write(0xA080, 0xC0 | di);
+                out = (out << 1) | (nemu.bus.peek(0xA080) & 0x01) as u32;
+            }
+            nemu.bus.write(0xA080, 0x00);
+            out
+        };
+
+        // start bit and opcode, followed by 8 address bits
+        let (ewen, write, read) = (0b100 << 8, 0b101 << 8, 0b110 << 8);
+
+        // EWEN, WRITE 0xBEEF to word 5, then READ it back after the dummy bit
+        send(&mut nemu, ewen | 0xC0, 11);
+        send(&mut nemu, ((write | 5) << 16) | 0xBEEF, 27);
+        assert!(nemu.save_dirty());
+        let out = send(&mut nemu, (read | 5) << 16, 27);
+        assert_eq!(out & 0xFFFF, 0xBEEF);
+
+        let save = nemu.export_save().unwrap();
+        assert_eq!(save.len(), 256);
+        assert_eq!(&save[10..12], &[0xEF, 0xBE]);
+        assert_eq!(save[0], 0xFF);
+    }
+
+    #[test]
+    fn pocket_camera_capture() {
+        let mut nemu = Nemu::default();
+        nemu.load_cartridge(&test_cartridge(0xFC, 64)).unwrap();
+
+        // left half white, right half black
+        let mut image = [0xFF; 128 * 112];
+        for row in image.chunks_exact_mut(128) {
+            row[64..].fill(0x00);
+        }
+        nemu.set_camera_image(&image);
+
+        nemu.bus.write(0x4000, 0x10);
+        nemu.bus.write(0xA002, 0x10);
+        nemu.bus.write(0xA003, 0x00);
+        for entry in 0..16 {
+            nemu.bus.write(0xA006 + entry * 3, 0x40);
+            nemu.bus.write(0xA007 + entry * 3, 0x80);
+            nemu.bus.write(0xA008 + entry * 3, 0xC0);
+        }
+
+        nemu.bus.write(0xA000, 0x03);
+        assert_eq!(nemu.bus.peek(0xA000), 0x03);
+        assert_eq!(nemu.bus.peek(0xA001), 0x00);
+
+        let mut cycles = 0;
+        while nemu.bus.peek(0xA000) & 0x01 != 0 {
+            nemu.bus.tick(1);
+            cycles += 1;
+        }
+        assert_eq!(cycles, 32446 + 512 + 16 * 0x1000);
+        assert_eq!(nemu.bus.peek(0xA000), 0x02);
+
+        nemu.bus.write(0x4000, 0x00);
+        assert_eq!(nemu.bus.peek(0xA100), 0x00);
+        assert_eq!(nemu.bus.peek(0xA101), 0x00);
+        assert_eq!(nemu.bus.peek(0xA100 + 8 * 16), 0xFF);
+        assert_eq!(nemu.bus.peek(0xA101 + 8 * 16), 0xFF);
+        assert_eq!(nemu.bus.peek(0xA100 + 16 * 16 * 14 - 1), 0xFF);
+        assert!(nemu.save_dirty());
+    }
+
+    #[test]
+    fn apu_register_masks() {
+        let mut nemu = Nemu::default();
+        nemu.bus.write(0xFF26, 0x80);
+        nemu.bus.write(0xFF10, 0x00);
+        nemu.bus.write(0xFF1A, 0x00);
+
+        assert_eq!(nemu.bus.peek(0xFF10), 0x80);
+        assert_eq!(nemu.bus.peek(0xFF15), 0xFF);
+        assert_eq!(nemu.bus.peek(0xFF1A), 0x7F);
+        assert_eq!(nemu.bus.peek(0xFF26), 0xF0);
+
+        nemu.bus.write(0xFF26, 0x00);
+        assert_eq!(nemu.bus.peek(0xFF26), 0x70);
+    }
+
+    #[test]
+    fn apu_generates_samples() {
+        let mut nemu = Nemu::default();
+        nemu.set_sample_rate(44_100);
+
+        nemu.bus.write(0xFF26, 0x80); // power on
+        nemu.bus.write(0xFF24, 0x77); // max volume
+        nemu.bus.write(0xFF25, 0x22); // channel 2 to both outputs
+        nemu.bus.write(0xFF16, 0x80); // 50% duty
+        nemu.bus.write(0xFF17, 0xF0); // full volume, no envelope
+        nemu.bus.write(0xFF18, 0x00);
+        nemu.bus.write(0xFF19, 0x87); // trigger
+
+        assert_eq!(nemu.bus.peek(0xFF26) & 0x02, 0x02);
+
+        for _ in 0..(4_194_304 / 4 / 10) {
+            nemu.bus.tick(1);
+        }
+
+        let samples: Vec<f32> = nemu.drain_samples().collect();
+        assert!((samples.len() as i32 - 8820).abs() <= 4);
+        assert!(samples.iter().any(|&s| s > 0.1));
+        assert!(samples.iter().any(|&s| s < -0.1));
+        assert_eq!(nemu.drain_samples().count(), 0);
+    }
+
+    #[test]
+    fn serial_internal_clock_transfer() {
+        struct Inverter;
+
+        impl SerialDevice for Inverter {
+            fn exchange(&mut self, byte: u8) -> u8 {
+                !byte
+            }
+        }
+
+        let mut nemu = Nemu::default();
+        nemu.connect_serial(Box::new(Inverter));
+        nemu.bus.write(0xFF01, 0x3C);
+        nemu.bus.write(0xFF02, 0x81);
+
+        // 8 bits at 8192 Hz take 1024 M-cycles, nothing should be visible before that
+        for _ in 0..900 {
+            nemu.bus.tick(1);
+        }
+        assert_eq!(nemu.bus.peek(0xFF02), 0xFF);
+        assert_eq!(nemu.bus.peek(0xFF0F) & interrupts::INT_SERIAL, 0);
+
+        for _ in 0..200 {
+            nemu.bus.tick(1);
+        }
+        assert_eq!(nemu.bus.peek(0xFF01), 0xC3);
+        assert_eq!(nemu.bus.peek(0xFF02), 0x7F);
+        assert_ne!(nemu.bus.peek(0xFF0F) & interrupts::INT_SERIAL, 0);
+    }
+
+    #[test]
+    fn linked_pair_exchanges_bytes() {
+        let mut master = Nemu::default();
+        // the master starts a little later so the slave is already listening
+        master.load_cartridge(&serial_test_program(0x42, 0x81, 0x190)).unwrap();
+        master.skip_boot();
+
+        let mut slave = Nemu::default();
+        slave.load_cartridge(&serial_test_program(0x99, 0x80, 0x150)).unwrap();
+        slave.skip_boot();
+
+        let mut pair = LinkedPair::new(master, slave);
+        pair.run_for(4 * 4096);
+
+        let (a, b) = pair.cycles();
+        assert!(a.abs_diff(b) < 512);
+
+        let (mut master, mut slave) = pair.into_inner();
+        assert_eq!(master.bus.peek(0xFF01), 0x99);
+        assert_eq!(slave.bus.peek(0xFF01), 0x42);
+        assert_ne!(master.bus.peek(0xFF0F) & interrupts::INT_SERIAL, 0);
+        assert_ne!(slave.bus.peek(0xFF0F) & interrupts::INT_SERIAL, 0);
+        assert_eq!(master.bus.peek(0xFF02) & 0x80, 0);
+        assert_eq!(slave.bus.peek(0xFF02) & 0x80, 0);
+
+        // make sure both emulators are still usable on their own
+        master.step();
+        slave.step();
+    }
+
+    #[test]
+    fn linked_pair_cancelled_transfer() {
+        let mut pair = LinkedPair::new(Nemu::default(), Nemu::default());
+
+        // the second side listens, then gives up before anyone clocks it
+        pair.second().bus.write(0xFF01, 0x55);
+        pair.second().bus.write(0xFF02, 0x80);
+        pair.second().bus.tick(1);
+        pair.second().bus.write(0xFF02, 0x00);
+
+        // so a later transfer from the first side finds nobody listening
+        pair.first().bus.write(0xFF01, 0x42);
+        pair.first().bus.write(0xFF02, 0x81);
+        for _ in 0..2048 {
+            pair.first().bus.tick(1);
+        }
+        assert_eq!(pair.first().bus.peek(0xFF01), 0xFF);
+        assert_eq!(pair.second().bus.peek(0xFF01), 0x55);
+    }
+
+    fn serial_test_program(sb: u8, sc: u8, start: usize) -> Vec<u8> {
+        // JP 0x150 over the header and NOP padding, then LD A,n / LDH (SB),A / LD A,n / LDH (SC),A / JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[start..start + 10].copy_from_slice(&[0x3E, sb, 0xE0, 0x01, 0x3E, sc, 0xE0, 0x02, 0x18, 0xFE]);
+        fix_header(&mut rom);
+        rom
+    }
+
+    #[test]
+    fn tcp_link_exchanges_bytes() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let timeout = Duration::from_secs(2);
+        let link = TcpLink::host("127.0.0.1:0", timeout).unwrap();
+        let addr = link.local_addr().unwrap();
+
+        let (armed_tx, armed_rx) = mpsc::channel();
+
+        let slave = std::thread::spawn(move || {
+            let mut nemu = Nemu::default();
+            nemu.load_cartridge(&serial_test_program(0x99, 0x80, 0x150)).unwrap();
+            nemu.skip_boot();
+            let link = TcpLink::connect(addr, timeout).unwrap();
+            while !link.is_connected() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            nemu.connect_serial(Box::new(link));
+
+            for _ in 0..100 {
+                nemu.step();
+            }
+            armed_tx.send(()).unwrap();
+
+            for _ in 0..100_000 {
+                if nemu.bus.peek(0xFF02) & 0x80 == 0 {
+                    break;
+                }
+                nemu.step();
+            }
+            nemu.bus.peek(0xFF01)
+        });
+
+        let mut master = Nemu::default();
+        master.load_cartridge(&serial_test_program(0x42, 0x81, 0x150)).unwrap();
+        master.skip_boot();
+
+        while !link.is_connected() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        master.connect_serial(Box::new(link));
+
+        armed_rx.recv().unwrap();
+        for _ in 0..10_000 {
+            master.step();
+        }
+
+        assert_eq!(slave.join().unwrap(), 0x42);
+        assert_eq!(master.bus.peek(0xFF01), 0x99);
+
+        // the partner is gone, which has to be reported instead of hanging the next transfer
+        master.bus.write(0xFF02, 0x81);
+        assert!(matches!(master.take_serial_error(), Some(NemuError::Link(_))));
+    }
+
+    #[test]
+    fn tcp_link_host_releases_port() {
+        use std::time::Duration;
+
+        let link = TcpLink::host("127.0.0.1:0", Duration::from_secs(1)).unwrap();
+        let addr = link.local_addr().unwrap();
+        drop(link);
+
+        // the accept thread notices the drop on its next poll and closes the listener
+        let rebound = (0..100).any(|_| {
+            std::thread::sleep(Duration::from_millis(10));
+            TcpLink::host(addr, Duration::from_secs(1)).is_ok()
+        });
+        assert!(rebound);
+    }
+
+    #[test]
+    fn printer_prints_band() {
+        fn send_packet(printer: &mut Printer, command: u8, compression: u8, data: &[u8]) -> (u8, u8) {
+            let mut body = vec![command, compression];
+            body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            body.extend_from_slice(data);
+            let checksum = body.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+
+            printer.exchange(0x88);
+            printer.exchange(0x33);
+            for b in body.into_iter().chain(checksum.to_le_bytes()) {
+                assert_eq!(printer.exchange(b), 0x00);
+            }
+            (printer.exchange(0x00), printer.exchange(0x00))
+        }
+
+        let mut printer = Printer::new();
+        let output = printer.output();
+
+        assert_eq!(send_packet(&mut printer, 0x01, 0, &[]), (0x81, 0x00));
+
+        // one band of solid color 3 tiles, RLE compressed as 5 runs of 128 bytes of 0xFF
+        assert_eq!(send_packet(&mut printer, 0x04, 1, &[0xFE, 0xFF].repeat(5)), (0x81, 0x08));
+        assert_eq!(send_packet(&mut printer, 0x04, 0, &[]), (0x81, 0x08));
+
+        // one line feed after, palette maps color 3 to shade 1
+        let (_, status) = send_packet(&mut printer, 0x02, 0, &[0x01, 0x01, 0x40, 0x40]);
+        assert_ne!(status & 0x02, 0);
+
+        let image = output.pop().expect("printer should have finished a page");
+        assert_eq!((image.width, image.height), (160, 32));
+        assert!(image.pixels[..160 * 16].iter().all(|&shade| shade == 1));
+        assert!(image.pixels[160 * 16..].iter().all(|&shade| shade == 0));
+        assert!(image.to_png().starts_with(b"\x89PNG\r\n\x1a\n"));
+
+        // corrupted checksum is reported in the status byte
+        printer.exchange(0x88);
+        printer.exchange(0x33);
+        for b in [0x0F, 0x00, 0x00, 0x00, 0x00, 0x00] {
+            printer.exchange(b);
+        }
+        assert_eq!((printer.exchange(0x00), printer.exchange(0x00) & 0x01), (0x81, 0x01));
+    }
+
     #[test]
     fn cpu_instrs() {
         let result = run_test_rom(
@@ -0,0 +1,106 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{Nemu, SerialDevice};
+
+#[derive(Default)]
+struct CableState {
+    /// SB of each side while it waits on the external clock
+    armed: [Option<u8>; 2],
+    /// Byte clocked in by the partner, waiting to be picked up by an externally clocked side
+    delivered: [Option<u8>; 2],
+}
+
+struct CableEnd {
+    side: usize,
+    state: Arc<Mutex<CableState>>,
+}
+
+impl SerialDevice for CableEnd {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let other = self.side ^ 1;
+        let mut state = self.state.lock().unwrap();
+
+        match state.armed[other].take() {
+            Some(partner_byte) => {
+                state.delivered[other] = Some(byte);
+                partner_byte
+            }
+            // the partner is not listening, so only the idle high line gets shifted in
+            None => 0xFF,
+        }
+    }
+
+    fn poll_external(&mut self, byte: u8) -> Option<u8> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.delivered[self.side].take() {
+            Some(partner_byte) => Some(partner_byte),
+            None => {
+                state.armed[self.side] = Some(byte);
+                None
+            }
+        }
+    }
+
+    fn cancel_external(&mut self) {
+        self.state.lock().unwrap().armed[self.side] = None;
+    }
+}
+
+/// Two emulators connected by a virtual link cable and stepped in lockstep.
+///
+/// The side lagging behind is always stepped next, so both stay within a single instruction
+/// of each other, well inside one serial bit period (512 cycles).
+pub struct LinkedPair {
+    nemus: [Nemu; 2],
+    cycles: [u64; 2],
+}
+
+impl LinkedPair {
+    pub fn new(mut first: Nemu, mut second: Nemu) -> Self {
+        let state = Arc::new(Mutex::new(CableState::default()));
+
+        first.connect_serial(Box::new(CableEnd { side: 0, state: state.clone() }));
+        second.connect_serial(Box::new(CableEnd { side: 1, state }));
+
+        Self {
+            nemus: [first, second],
+            cycles: [0, 0],
+        }
+    }
+
+    /// Steps the emulator that is behind by one instruction, returns which side was stepped
+    pub fn step(&mut self) -> usize {
+        let side = if self.cycles[0] <= self.cycles[1] { 0 } else { 1 };
+        self.cycles[side] += self.nemus[side].step() as u64;
+        side
+    }
+
+    /// Runs both sides until each has executed at least `cycles` more cycles
+    pub fn run_for(&mut self, cycles: u64) {
+        let target = self.cycles[0].max(self.cycles[1]) + cycles;
+        while self.cycles[0] < target || self.cycles[1] < target {
+            self.step();
+        }
+    }
+
+    pub fn cycles(&self) -> (u64, u64) {
+        (self.cycles[0], self.cycles[1])
+    }
+
+    pub fn first(&mut self) -> &mut Nemu {
+        &mut self.nemus[0]
+    }
+
+    pub fn second(&mut self) -> &mut Nemu {
+        &mut self.nemus[1]
+    }
+
+    /// Unplugs the cable and hands both emulators back
+    pub fn into_inner(self) -> (Nemu, Nemu) {
+        let [mut first, mut second] = self.nemus;
+        first.disconnect_serial();
+        second.disconnect_serial();
+        (first, second)
+    }
+}
@@ -0,0 +1,230 @@
+use super::ram::CartRam;
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+pub(crate) const IMAGE_WIDTH: usize = 128;
+pub(crate) const IMAGE_HEIGHT: usize = 112;
+
+// processed images land in RAM bank 0 as 16x14 tiles
+const IMAGE_OFFSET: usize = 0x100;
+
+const REG_CONTROL: usize = 0x00;
+const REG_EDGE_GAIN: usize = 0x01;
+const REG_EXPOSURE_HIGH: usize = 0x02;
+const REG_EXPOSURE_LOW: usize = 0x03;
+const REG_EDGE_INVERT: usize = 0x04;
+const REG_MATRIX: usize = 0x06;
+const REG_COUNT: usize = 0x36;
+
+const CONTROL_CAPTURE: u8 = 0b0000_0001;
+const EDGE_EXCLUSIVE: u8 = 0b1000_0000;
+const EDGE_2D: u8 = 0b0110_0000;
+const INVERT: u8 = 0b0000_1000;
+
+const EDGE_RATIOS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+pub(crate) struct PocketCamera {
+    rom: Vec<u8>,
+    pub(crate) ram: CartRam,
+
+    rom_bank: u8,
+    ram_bank: u8,
+    ram_enabled: bool,
+
+    registers: [u8; REG_COUNT],
+    capture_cycles: u32,
+    sensor: Box<[u8; IMAGE_WIDTH * IMAGE_HEIGHT]>,
+
+    rom_offset: usize,
+    rom_mask: u8,
+}
+
+impl PocketCamera {
+    pub(crate) fn new(rom: Vec<u8>) -> Self {
+        let num_banks = rom.len() / 0x4000;
+        let rom_mask = (num_banks.next_power_of_two() - 1) as u8;
+
+        Self {
+            rom,
+            ram: CartRam::new(0x20000),
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            registers: [0; REG_COUNT],
+            capture_cycles: 0,
+            sensor: Box::new([0x80; IMAGE_WIDTH * IMAGE_HEIGHT]),
+            rom_offset: 0x4000,
+            rom_mask,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn has_battery(&self) -> bool {
+        true
+    }
+
+    /// Grayscale picture the sensor sees on the next capture, 0 is black and 255 is white
+    pub(crate) fn set_image(&mut self, image: &[u8; IMAGE_WIDTH * IMAGE_HEIGHT]) {
+        self.sensor.copy_from_slice(image);
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.ram.save_state(w);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+        w.bytes(&self.registers);
+        w.u32(self.capture_cycles);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.ram.load_state(r)?;
+        self.rom_bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        r.bytes(&mut self.registers)?;
+        self.capture_cycles = r.u32()?;
+
+        self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub(crate) fn tick(&mut self, cycles: u8) {
+        if self.capture_cycles == 0 {
+            return;
+        }
+
+        self.capture_cycles = self.capture_cycles.saturating_sub(cycles as u32);
+        if self.capture_cycles == 0 {
+            self.capture();
+            self.registers[REG_CONTROL] &= !CONTROL_CAPTURE;
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+
+            0x4000..=0x7FFF => {
+                let index = self.rom_offset + (addr as usize - 0x4000);
+                self.rom[index]
+            }
+
+            // with bit 4 of the bank set the area maps the camera registers, of which only
+            // the control register can be read back
+            0xA000..=0xBFFF if (self.ram_bank & 0x10) != 0 => {
+                if (addr & 0x7F) == 0 { self.registers[REG_CONTROL] } else { 0x00 }
+            }
+
+            // RAM stays readable even while writes are disabled
+            0xA000..=0xBFFF => {
+                self.ram.read(self.ram_bank as usize * 0x2000 + (addr as usize - 0xA000))
+            }
+
+            _ => 0xFF,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = (value & 0x0F) == 0x0A;
+            }
+
+            0x2000..=0x3FFF => {
+                self.rom_bank = value & 0x3F;
+                self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+            }
+
+            0x4000..=0x5FFF => {
+                self.ram_bank = value & 0x1F;
+            }
+
+            0xA000..=0xBFFF if (self.ram_bank & 0x10) != 0 => {
+                let register = (addr & 0x7F) as usize;
+                if register >= REG_COUNT { return; }
+
+                if register == REG_CONTROL {
+                    self.write_control(value);
+                } else {
+                    self.registers[register] = value;
+                }
+            }
+
+            0xA000..=0xBFFF => {
+                // the camera owns RAM for the duration of a capture
+                if !self.ram_enabled || self.capture_cycles > 0 { return; }
+                self.ram.write(self.ram_bank as usize * 0x2000 + (addr as usize - 0xA000), value);
+            }
+
+            _ => {}
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        let value = value & 0x07;
+
+        if (value & CONTROL_CAPTURE) != 0 && self.capture_cycles == 0 {
+            let exposure = u16::from_be_bytes([self.registers[REG_EXPOSURE_HIGH], self.registers[REG_EXPOSURE_LOW]]);
+            let exclusive = (self.registers[REG_EDGE_GAIN] & EDGE_EXCLUSIVE) != 0;
+
+            // in M-cycles, reading the sensor out takes longer the longer it is exposed
+            self.capture_cycles = 32446 + if exclusive { 0 } else { 512 } + 16 * exposure as u32;
+        }
+
+        // a capture can be cancelled, but the busy bit can not be set without starting one
+        if (value & CONTROL_CAPTURE) == 0 {
+            self.capture_cycles = 0;
+        }
+
+        self.registers[REG_CONTROL] = value;
+    }
+
+    /// Runs the sensor image through exposure, edge enhancement and the dither matrix, then
+    /// stores it as 2bpp tiles. Gain and the voltage references are not modelled.
+    fn capture(&mut self) {
+        let exposure = u16::from_be_bytes([self.registers[REG_EXPOSURE_HIGH], self.registers[REG_EXPOSURE_LOW]]);
+        let exposure = exposure as f32 / 0x1000 as f32;
+
+        let edge = (self.registers[REG_EDGE_GAIN] & EDGE_2D) == EDGE_2D;
+        let ratio = EDGE_RATIOS[((self.registers[REG_EDGE_INVERT] >> 4) & 0x07) as usize];
+        let invert = (self.registers[REG_EDGE_INVERT] & INVERT) != 0;
+
+        let pixel = |x: usize, y: usize| self.sensor[y * IMAGE_WIDTH + x] as f32 * exposure;
+
+        for y in 0..IMAGE_HEIGHT {
+            for x in 0..IMAGE_WIDTH {
+                let mut value = pixel(x, y);
+
+                if edge {
+                    let neighbours = pixel(x.saturating_sub(1), y)
+                        + pixel((x + 1).min(IMAGE_WIDTH - 1), y)
+                        + pixel(x, y.saturating_sub(1))
+                        + pixel(x, (y + 1).min(IMAGE_HEIGHT - 1));
+                    value += ratio * (4.0 * value - neighbours);
+                }
+
+                let mut value = value.clamp(0.0, 255.0) as u8;
+                if invert {
+                    value = 255 - value;
+                }
+
+                let matrix = REG_MATRIX + ((y & 3) * 4 + (x & 3)) * 3;
+                let thresholds = &self.registers[matrix..matrix + 3];
+                let color = thresholds.iter().filter(|&&threshold| value < threshold).count() as u8;
+
+                let tile = (y / 8) * (IMAGE_WIDTH / 8) + x / 8;
+                let index = IMAGE_OFFSET + tile * 16 + (y & 7) * 2;
+                let bit = 7 - (x & 7);
+
+                let low = (self.ram.read(index) & !(1 << bit)) | ((color & 0x01) << bit);
+                let high = (self.ram.read(index + 1) & !(1 << bit)) | (((color >> 1) & 0x01) << bit);
+                self.ram.write(index, low);
+                self.ram.write(index + 1, high);
+            }
+        }
+    }
+}
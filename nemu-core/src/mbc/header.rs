@@ -0,0 +1,109 @@
+use crate::NemuError;
+
+const HEADER_END: usize = 0x150;
+
+/// The cartridge header at 0x100-0x14F, parsed and validated when a ROM is loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    /// 0x80 works on both DMG and CGB, 0xC0 is CGB only, anything else is a DMG game
+    pub cgb_flag: u8,
+    /// 0x03 enables the SGB functions
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    /// 0x33 means the licensee is in `new_licensee_code` instead
+    pub old_licensee_code: u8,
+    pub new_licensee_code: [u8; 2],
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, NemuError> {
+        if rom.len() < HEADER_END {
+            return Err(NemuError::TruncatedRom { len: rom.len() });
+        }
+
+        let header_checksum = rom[0x14D];
+        let computed = rom[0x134..=0x14C]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+
+        if computed != header_checksum {
+            return Err(NemuError::BadHeaderChecksum {
+                expected: header_checksum,
+                actual: computed,
+            });
+        }
+
+        let cgb_flag = rom[0x143];
+        // CGB games reuse the end of the title area for the manufacturer code and CGB flag
+        let title_end = if (cgb_flag & 0x80) != 0 { 0x13F } else { 0x144 };
+        let title = rom[0x134..title_end]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '?' })
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        let header = Self {
+            title,
+            cgb_flag,
+            sgb_flag: rom[0x146],
+            cartridge_type: rom[0x147],
+            rom_size: rom[0x148],
+            ram_size: rom[0x149],
+            old_licensee_code: rom[0x14B],
+            new_licensee_code: [rom[0x144], rom[0x145]],
+            version: rom[0x14C],
+            header_checksum,
+            global_checksum: u16::from_be_bytes([rom[0x14E], rom[0x14F]]),
+        };
+
+        let expected = header.rom_size_bytes().ok_or_else(|| {
+            NemuError::InvalidRom(format!("Unknown ROM size code: {:#04X}", header.rom_size))
+        })?;
+
+        if rom.len() != expected {
+            return Err(NemuError::RomSizeMismatch {
+                expected,
+                actual: rom.len(),
+            });
+        }
+
+        Ok(header)
+    }
+
+    /// ROM size in bytes, `None` for codes the header spec does not define
+    pub fn rom_size_bytes(&self) -> Option<usize> {
+        (self.rom_size <= 0x08).then(|| 0x8000 << self.rom_size)
+    }
+
+    /// External RAM size in bytes, 0 for carts without RAM (MBC2's built-in RAM is not counted)
+    pub fn ram_size_bytes(&self) -> usize {
+        ram_size_bytes(self.ram_size)
+    }
+
+    pub fn supports_cgb(&self) -> bool {
+        (self.cgb_flag & 0x80) != 0
+    }
+
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+}
+
+pub(crate) fn ram_size_bytes(code: u8) -> usize {
+    match code {
+        0x01 => 0x800,
+        0x02 => 0x2000,
+        0x03 => 0x8000,
+        0x04 => 0x20000,
+        0x05 => 0x10000,
+        _ => 0,
+    }
+}
@@ -0,0 +1,106 @@
+use super::ram::CartRam;
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+// what the IR receiver reports when it sees no light, nothing is ever on the other end
+const IR_NO_LIGHT: u8 = 0xC0;
+
+pub(crate) struct Huc1 {
+    rom: Vec<u8>,
+    pub(crate) ram: CartRam,
+
+    rom_bank: u8,
+    ram_bank: u8,
+    // writing 0x0E to 0x0000-0x1FFF swaps RAM for the infrared port, anything else maps RAM back
+    ir_mode: bool,
+
+    rom_offset: usize,
+    rom_mask: u8,
+}
+
+impl Huc1 {
+    pub(crate) fn new(rom: Vec<u8>) -> Self {
+        let num_banks = rom.len() / 0x4000;
+        let rom_mask = (num_banks.next_power_of_two() - 1) as u8;
+        let ram = CartRam::new(CartRam::header_size(&rom));
+
+        Self {
+            rom,
+            ram,
+            rom_bank: 1,
+            ram_bank: 0,
+            ir_mode: false,
+            rom_offset: 0x4000,
+            rom_mask,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn has_battery(&self) -> bool {
+        true
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.ram.save_state(w);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ir_mode);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.ram.load_state(r)?;
+        self.rom_bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.ir_mode = r.bool()?;
+
+        self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+
+            0x4000..=0x7FFF => {
+                let index = self.rom_offset + (addr as usize - 0x4000);
+                self.rom[index]
+            }
+
+            0xA000..=0xBFFF if self.ir_mode => IR_NO_LIGHT,
+
+            0xA000..=0xBFFF => {
+                self.ram.read(self.ram_bank as usize * 0x2000 + (addr as usize - 0xA000))
+            }
+
+            _ => 0xFF,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.ir_mode = (value & 0x0F) == 0x0E;
+            }
+
+            0x2000..=0x3FFF => {
+                self.rom_bank = value & 0x3F;
+                self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+            }
+
+            0x4000..=0x5FFF => {
+                self.ram_bank = value & 0x03;
+            }
+
+            // toggles the IR LED, which has nobody to talk to
+            0xA000..=0xBFFF if self.ir_mode => {}
+
+            0xA000..=0xBFFF => {
+                self.ram.write(self.ram_bank as usize * 0x2000 + (addr as usize - 0xA000), value);
+            }
+
+            _ => {}
+        }
+    }
+}
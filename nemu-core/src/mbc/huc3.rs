@@ -0,0 +1,358 @@
+use super::ram::CartRam;
+use super::rtc::{unix_now, RtcClock, CYCLES_PER_SECOND};
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+/// Clock block appended to battery saves: minutes, days, alarm minutes and alarm days as `u16`,
+/// the alarm enable, the seconds, two reserved bytes and the `u64` unix time of the save
+pub(crate) const SAVE_FOOTER_SIZE: usize = 20;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const IR_NO_LIGHT: u8 = 0xC0;
+
+// values written to 0x0000-0x1FFF pick what 0xA000-0xBFFF maps to
+const MODE_RAM_READ: u8 = 0x00;
+const MODE_RAM: u8 = 0x0A;
+const MODE_RTC_COMMAND: u8 = 0x0B;
+const MODE_RTC_RESPONSE: u8 = 0x0C;
+const MODE_RTC_SEMAPHORE: u8 = 0x0D;
+const MODE_IR: u8 = 0x0E;
+
+const CMD_READ: u8 = 0x1;
+const CMD_WRITE: u8 = 0x2;
+const CMD_WRITE_INCREMENT: u8 = 0x3;
+const CMD_ADDRESS_LOW: u8 = 0x4;
+const CMD_ADDRESS_HIGH: u8 = 0x5;
+const CMD_TONE: u8 = 0x6;
+
+/// HuC3 clock, it only counts minutes of the day and days. The game talks to it one nibble at a
+/// time through a small address space: 0x00-0x02 minutes, 0x03-0x06 days, 0x58-0x5A alarm
+/// minutes, 0x5B-0x5E alarm days and 0x5F the alarm enable.
+struct Huc3Clock {
+    minutes: u16,
+    days: u16,
+    alarm_minutes: u16,
+    alarm_days: u16,
+    alarm_enabled: bool,
+
+    clock: RtcClock,
+    seconds: u32,
+    cycles: u32,
+    last_sync: u64,
+}
+
+impl Huc3Clock {
+    fn new() -> Self {
+        Self {
+            minutes: 0,
+            days: 0,
+            alarm_minutes: 0,
+            alarm_days: 0,
+            alarm_enabled: false,
+            clock: RtcClock::WallTime,
+            seconds: 0,
+            cycles: 0,
+            last_sync: unix_now(),
+        }
+    }
+
+    fn set_clock(&mut self, clock: RtcClock) {
+        self.sync();
+        self.clock = clock;
+        self.last_sync = unix_now();
+    }
+
+    #[inline(always)]
+    fn tick(&mut self, cycles: u8) {
+        if self.clock != RtcClock::Emulated {
+            return;
+        }
+
+        self.cycles += cycles as u32;
+        if self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.advance(1);
+        }
+    }
+
+    fn sync(&mut self) {
+        if self.clock != RtcClock::WallTime {
+            return;
+        }
+
+        let now = unix_now();
+        let elapsed = now.saturating_sub(self.last_sync);
+        self.last_sync = now;
+        self.advance(elapsed);
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        let total = self.seconds as u64 + seconds;
+        self.seconds = (total % 60) as u32;
+
+        let minutes = self.minutes as u64 + total / 60;
+        self.minutes = (minutes % MINUTES_PER_DAY as u64) as u16;
+        self.days = self.days.wrapping_add((minutes / MINUTES_PER_DAY as u64) as u16);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.minutes);
+        w.u16(self.days);
+        w.u16(self.alarm_minutes);
+        w.u16(self.alarm_days);
+        w.bool(self.alarm_enabled);
+        w.u32(self.seconds);
+        w.u32(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.minutes = r.u16()?;
+        self.days = r.u16()?;
+        self.alarm_minutes = r.u16()?;
+        self.alarm_days = r.u16()?;
+        self.alarm_enabled = r.bool()?;
+        self.seconds = r.u32()?;
+        self.cycles = r.u32()?;
+
+        self.last_sync = unix_now();
+        Ok(())
+    }
+
+    fn save_footer(&mut self) -> [u8; SAVE_FOOTER_SIZE] {
+        self.sync();
+
+        let mut footer = [0; SAVE_FOOTER_SIZE];
+        for (i, value) in [self.minutes, self.days, self.alarm_minutes, self.alarm_days].into_iter().enumerate() {
+            footer[i * 2..i * 2 + 2].copy_from_slice(&value.to_le_bytes());
+        }
+        footer[8] = self.alarm_enabled as u8;
+        footer[9] = self.seconds as u8;
+        footer[12..].copy_from_slice(&unix_now().to_le_bytes());
+        footer
+    }
+
+    fn load_save_footer(&mut self, footer: &[u8; SAVE_FOOTER_SIZE]) {
+        let value = |i: usize| u16::from_le_bytes([footer[i * 2], footer[i * 2 + 1]]);
+
+        self.minutes = value(0) % MINUTES_PER_DAY;
+        self.days = value(1);
+        self.alarm_minutes = value(2);
+        self.alarm_days = value(3);
+        self.alarm_enabled = (footer[8] & 0x01) != 0;
+        self.seconds = (footer[9] % 60) as u32;
+        self.cycles = 0;
+
+        // catch up on the time that passed while the game was not running
+        self.last_sync = u64::from_le_bytes(footer[12..].try_into().unwrap());
+        self.sync();
+        self.last_sync = unix_now();
+    }
+
+    fn read_nibble(&self, address: u8) -> u8 {
+        let nibble = |value: u16, index: u8| ((value >> (index * 4)) & 0x0F) as u8;
+
+        match address {
+            0x00..=0x02 => nibble(self.minutes, address),
+            0x03..=0x06 => nibble(self.days, address - 0x03),
+            0x58..=0x5A => nibble(self.alarm_minutes, address - 0x58),
+            0x5B..=0x5E => nibble(self.alarm_days, address - 0x5B),
+            0x5F => self.alarm_enabled as u8,
+            _ => 0x00,
+        }
+    }
+
+    fn write_nibble(&mut self, address: u8, value: u8) {
+        let set = |target: &mut u16, index: u8| {
+            let shift = index * 4;
+            *target = (*target & !(0x0F << shift)) | (((value & 0x0F) as u16) << shift);
+        };
+
+        match address {
+            0x00..=0x02 => {
+                set(&mut self.minutes, address);
+                self.seconds = 0;
+            }
+            0x03..=0x06 => set(&mut self.days, address - 0x03),
+            0x58..=0x5A => set(&mut self.alarm_minutes, address - 0x58),
+            0x5B..=0x5E => set(&mut self.alarm_days, address - 0x5B),
+            0x5F => self.alarm_enabled = (value & 0x01) != 0,
+            _ => {}
+        }
+    }
+}
+
+pub(crate) struct Huc3 {
+    rom: Vec<u8>,
+    pub(crate) ram: CartRam,
+    clock: Huc3Clock,
+
+    rom_bank: u8,
+    ram_bank: u8,
+    mode: u8,
+
+    // RTC command interface
+    address: u8,
+    response: u8,
+    last_command: u8,
+    // the piezo tone generator is driven by command 6, frontends can poll it to play a sound
+    tone: u8,
+
+    rom_offset: usize,
+    rom_mask: u8,
+}
+
+impl Huc3 {
+    pub(crate) fn new(rom: Vec<u8>) -> Self {
+        let num_banks = rom.len() / 0x4000;
+        let rom_mask = (num_banks.next_power_of_two() - 1) as u8;
+        let ram = CartRam::new(CartRam::header_size(&rom));
+
+        Self {
+            rom,
+            ram,
+            clock: Huc3Clock::new(),
+            rom_bank: 1,
+            ram_bank: 0,
+            mode: MODE_RAM_READ,
+            address: 0,
+            response: 0,
+            last_command: 0,
+            tone: 0,
+            rom_offset: 0x4000,
+            rom_mask,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn has_battery(&self) -> bool {
+        true
+    }
+
+    #[inline(always)]
+    pub(crate) fn tone(&self) -> u8 {
+        self.tone
+    }
+
+    pub(crate) fn set_rtc_clock(&mut self, clock: RtcClock) {
+        self.clock.set_clock(clock);
+    }
+
+    #[inline(always)]
+    pub(crate) fn tick(&mut self, cycles: u8) {
+        self.clock.tick(cycles);
+    }
+
+    /// The clock state that goes after the RAM in battery saves
+    pub(crate) fn save_footer(&mut self) -> [u8; SAVE_FOOTER_SIZE] {
+        self.clock.save_footer()
+    }
+
+    pub(crate) fn load_save_footer(&mut self, footer: &[u8; SAVE_FOOTER_SIZE]) {
+        self.clock.load_save_footer(footer);
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.ram.save_state(w);
+        self.clock.save_state(w);
+
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.u8(self.mode);
+        w.u8(self.address);
+        w.u8(self.response);
+        w.u8(self.last_command);
+        w.u8(self.tone);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.ram.load_state(r)?;
+        self.clock.load_state(r)?;
+
+        self.rom_bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.mode = r.u8()?;
+        self.address = r.u8()?;
+        self.response = r.u8()?;
+        self.last_command = r.u8()?;
+        self.tone = r.u8()?;
+
+        self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+
+            0x4000..=0x7FFF => {
+                let index = self.rom_offset + (addr as usize - 0x4000);
+                self.rom[index]
+            }
+
+            0xA000..=0xBFFF => match self.mode {
+                MODE_RAM_READ | MODE_RAM => {
+                    self.ram.read(self.ram_bank as usize * 0x2000 + (addr as usize - 0xA000))
+                }
+                MODE_RTC_RESPONSE => 0x80 | (self.last_command << 4) | self.response,
+                // commands complete instantly, so the clock always reports ready
+                MODE_RTC_SEMAPHORE => 0x01,
+                MODE_IR => IR_NO_LIGHT,
+                _ => 0xFF,
+            },
+
+            _ => 0xFF,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.mode = value & 0x0F;
+            }
+
+            0x2000..=0x3FFF => {
+                self.rom_bank = value & 0x7F;
+                self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+            }
+
+            0x4000..=0x5FFF => {
+                self.ram_bank = value & 0x03;
+            }
+
+            0xA000..=0xBFFF => match self.mode {
+                MODE_RAM => {
+                    self.ram.write(self.ram_bank as usize * 0x2000 + (addr as usize - 0xA000), value);
+                }
+                MODE_RTC_COMMAND => self.run_command(value),
+                _ => {}
+            },
+
+            _ => {}
+        }
+    }
+
+    fn run_command(&mut self, value: u8) {
+        let (command, argument) = ((value >> 4) & 0x07, value & 0x0F);
+        self.clock.sync();
+
+        match command {
+            CMD_READ => {
+                self.response = self.clock.read_nibble(self.address);
+                self.address = self.address.wrapping_add(1);
+            }
+            CMD_WRITE => self.clock.write_nibble(self.address, argument),
+            CMD_WRITE_INCREMENT => {
+                self.clock.write_nibble(self.address, argument);
+                self.address = self.address.wrapping_add(1);
+            }
+            CMD_ADDRESS_LOW => self.address = (self.address & 0xF0) | argument,
+            CMD_ADDRESS_HIGH => self.address = (self.address & 0x0F) | (argument << 4),
+            CMD_TONE => self.tone = argument,
+            _ => {}
+        }
+
+        self.last_command = command;
+    }
+}
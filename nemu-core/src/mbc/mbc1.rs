@@ -1,11 +1,24 @@
+use super::ram::CartRam;
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
 pub(crate) struct Mbc1 {
     rom: Vec<u8>,
-    ram: Vec<u8>,
+    pub(crate) ram: CartRam,
 
     rom_bank: u8,
     ram_bank: u8,
     ram_enabled: bool,
     banking_mode: bool,
+    battery: bool,
+    // MBC1M only wires 4 bits of the lower bank register, the upper bits select the game
+    multicart: bool,
 
     rom_offset: usize,
     bank0_offset: usize,
@@ -14,17 +27,21 @@ pub(crate) struct Mbc1 {
 }
 
 impl Mbc1 {
-    pub(crate) fn new(rom: Vec<u8>) -> Self {
+    pub(crate) fn new(rom: Vec<u8>, battery: bool) -> Self {
         let num_banks = rom.len() / 0x4000;
         let rom_mask = (num_banks.next_power_of_two() - 1) as u8;
+        let multicart = is_multicart(&rom);
+        let ram = CartRam::new(CartRam::header_size(&rom));
 
         let mut mbc = Self {
             rom,
-            ram: vec![0; 0x8000],
+            ram,
             rom_bank: 1,
             ram_bank: 0,
             ram_enabled: false,
             banking_mode: false,
+            battery,
+            multicart,
             rom_offset: 0,
             bank0_offset: 0,
             ram_offset: 0,
@@ -35,6 +52,11 @@ impl Mbc1 {
         mbc
     }
 
+    #[inline(always)]
+    pub(crate) fn has_battery(&self) -> bool {
+        self.battery
+    }
+
     #[inline(always)]
     pub(crate) fn read(&self, addr: u16) -> u8 {
         match addr {
@@ -50,8 +72,7 @@ impl Mbc1 {
 
             0xA000..=0xBFFF => {
                 if !self.ram_enabled { return 0xFF; }
-                let index = self.ram_offset + (addr as usize - 0xA000);
-                self.ram[index]
+                self.ram.read(self.ram_offset + (addr as usize - 0xA000))
             }
 
             _ => 0xFF,
@@ -83,23 +104,44 @@ impl Mbc1 {
 
             0xA000..=0xBFFF => {
                 if !self.ram_enabled { return; }
-                let index = self.ram_offset + (addr as usize - 0xA000);
-                self.ram[index] = value;
+                self.ram.write(self.ram_offset + (addr as usize - 0xA000), value);
             }
 
             _ => {}
         }
     }
 
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.ram.save_state(w);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+        w.bool(self.banking_mode);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.ram.load_state(r)?;
+        self.rom_bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        self.banking_mode = r.bool()?;
+
+        self.update_offsets();
+        Ok(())
+    }
+
     fn update_offsets(&mut self) {
-        let rom_bank = if self.banking_mode {
-            ((self.ram_bank << 5) | self.rom_bank) & self.rom_mask
+        let (lower, shift) = if self.multicart {
+            (self.rom_bank & 0x0F, 4)
         } else {
-            self.rom_bank & self.rom_mask
-        } as usize;
+            (self.rom_bank, 5)
+        };
+
+        // the upper bits always reach the switchable area, the mode only affects bank 0 and RAM
+        let rom_bank = (((self.ram_bank << shift) | lower) & self.rom_mask) as usize;
 
         let bank0 = if self.banking_mode {
-            ((self.ram_bank << 5) & self.rom_mask) as usize
+            ((self.ram_bank << shift) & self.rom_mask) as usize
         } else {
             0
         };
@@ -114,4 +156,11 @@ impl Mbc1 {
         };
         self.ram_offset = ram_bank * 0x2000;
     }
-}
\ No newline at end of file
+}
+
+/// Multicart compilations carry a copy of the header for each game, so the second game's
+/// Nintendo logo shows up at the start of bank 0x10
+fn is_multicart(rom: &[u8]) -> bool {
+    let logo = 0x10 * 0x4000 + 0x104;
+    rom.get(logo..logo + NINTENDO_LOGO.len()) == Some(&NINTENDO_LOGO[..])
+}
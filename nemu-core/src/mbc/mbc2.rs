@@ -0,0 +1,95 @@
+use super::ram::CartRam;
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+pub(crate) struct Mbc2 {
+    rom: Vec<u8>,
+    pub(crate) ram: CartRam, // 512 x 4 bits, only the lower nibble of each byte is used
+
+    rom_bank: u8,
+    ram_enabled: bool,
+    battery: bool,
+
+    rom_offset: usize,
+    rom_mask: u8,
+}
+
+impl Mbc2 {
+    pub(crate) fn new(rom: Vec<u8>, battery: bool) -> Self {
+        let num_banks = rom.len() / 0x4000;
+        let rom_mask = (num_banks.next_power_of_two() - 1) as u8;
+
+        Self {
+            rom,
+            ram: CartRam::new(0x200),
+            rom_bank: 1,
+            ram_enabled: false,
+            battery,
+            rom_offset: 0x4000,
+            rom_mask,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.ram.save_state(w);
+        w.u8(self.rom_bank);
+        w.bool(self.ram_enabled);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.ram.load_state(r)?;
+        self.rom_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+
+        self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+
+            0x4000..=0x7FFF => {
+                let index = self.rom_offset + (addr as usize - 0x4000);
+                self.rom[index]
+            }
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled { return 0xFF; }
+                // only 9 address bits are wired, the 512 nibbles echo across the whole area
+                0xF0 | self.ram.read((addr & 0x01FF) as usize)
+            }
+
+            _ => 0xFF,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            // address bit 8 selects between the RAM enable and ROM bank registers
+            0x0000..=0x3FFF => {
+                if (addr & 0x0100) == 0 {
+                    self.ram_enabled = (value & 0x0F) == 0x0A;
+                } else {
+                    let bank = value & 0x0F;
+                    self.rom_bank = if bank == 0 { 1 } else { bank };
+                    self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+                }
+            }
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled { return; }
+                self.ram.write((addr & 0x01FF) as usize, value & 0x0F);
+            }
+
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use super::ram::CartRam;
+use super::rtc::{Rtc, RtcClock};
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+pub(crate) struct Mbc3 {
+    rom: Vec<u8>,
+    pub(crate) ram: CartRam,
+    pub(crate) rtc: Option<Rtc>,
+
+    rom_bank: u8,
+    // 0x00-0x03 selects a RAM bank, 0x08-0x0C maps an RTC register instead
+    ram_bank: u8,
+    ram_enabled: bool,
+    battery: bool,
+
+    rom_offset: usize,
+    rom_mask: u8,
+}
+
+impl Mbc3 {
+    pub(crate) fn new(rom: Vec<u8>, battery: bool, timer: bool) -> Self {
+        let num_banks = rom.len() / 0x4000;
+        let rom_mask = (num_banks.next_power_of_two() - 1) as u8;
+
+        let ram = CartRam::new(CartRam::header_size(&rom));
+
+        Self {
+            rom,
+            ram,
+            rtc: timer.then(Rtc::new),
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            battery,
+            rom_offset: 0x4000,
+            rom_mask,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    pub(crate) fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.set_clock(clock);
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn tick(&mut self, cycles: u8) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.ram.save_state(w);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(w);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.ram.load_state(r)?;
+        self.rom_bank = r.u8()?;
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(r)?;
+        }
+
+        self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+
+            0x4000..=0x7FFF => {
+                let index = self.rom_offset + (addr as usize - 0x4000);
+                self.rom[index]
+            }
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled { return 0xFF; }
+
+                match (self.ram_bank, &self.rtc) {
+                    (0x00..=0x03, _) => self.ram.read(self.ram_bank as usize * 0x2000 + (addr as usize - 0xA000)),
+                    (0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_bank),
+                    _ => 0xFF,
+                }
+            }
+
+            _ => 0xFF,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = (value & 0x0F) == 0x0A;
+            }
+
+            0x2000..=0x3FFF => {
+                let bank = value & 0x7F;
+                self.rom_bank = if bank == 0 { 1 } else { bank };
+                self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+            }
+
+            0x4000..=0x5FFF => {
+                self.ram_bank = value & 0x0F;
+            }
+
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled { return; }
+
+                match (self.ram_bank, &mut self.rtc) {
+                    (0x00..=0x03, _) => {
+                        self.ram.write(self.ram_bank as usize * 0x2000 + (addr as usize - 0xA000), value);
+                    }
+                    (0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_bank, value),
+                    _ => {}
+                }
+            }
+
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use super::ram::CartRam;
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+const RUMBLE_MOTOR: u8 = 0b0000_1000;
+
+pub(crate) struct Mbc5 {
+    rom: Vec<u8>,
+    pub(crate) ram: CartRam,
+
+    rom_bank: u16,
+    ram_bank: u8,
+    ram_enabled: bool,
+    battery: bool,
+    rumble: bool,
+    motor_on: bool,
+
+    rom_offset: usize,
+    ram_offset: usize,
+    rom_mask: u16,
+}
+
+impl Mbc5 {
+    pub(crate) fn new(rom: Vec<u8>, battery: bool, rumble: bool) -> Self {
+        let num_banks = rom.len() / 0x4000;
+        let rom_mask = (num_banks.next_power_of_two() - 1) as u16;
+
+        let ram = CartRam::new(CartRam::header_size(&rom));
+
+        Self {
+            rom,
+            ram,
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            battery,
+            rumble,
+            motor_on: false,
+            rom_offset: 0x4000,
+            ram_offset: 0,
+            rom_mask,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    #[inline(always)]
+    pub(crate) fn rumble_active(&self) -> bool {
+        self.motor_on
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.ram.save_state(w);
+        w.u16(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_enabled);
+        w.bool(self.motor_on);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.ram.load_state(r)?;
+        self.rom_bank = r.u16()?;
+        self.ram_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        self.motor_on = r.bool()?;
+
+        self.update_rom_offset();
+        self.ram_offset = self.ram_bank as usize * 0x2000;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+
+            0x4000..=0x7FFF => {
+                let index = self.rom_offset + (addr as usize - 0x4000);
+                self.rom[index]
+            }
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled { return 0xFF; }
+                self.ram.read(self.ram_offset + (addr as usize - 0xA000))
+            }
+
+            _ => 0xFF,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                // unlike the older MBCs all 8 bits are checked
+                self.ram_enabled = value == 0x0A;
+            }
+
+            // unlike the older MBCs bank 0 can be mapped into the switchable area
+            0x2000..=0x2FFF => {
+                self.rom_bank = (self.rom_bank & 0x100) | value as u16;
+                self.update_rom_offset();
+            }
+
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | (((value & 0x01) as u16) << 8);
+                self.update_rom_offset();
+            }
+
+            0x4000..=0x5FFF => {
+                // rumble carts wire bit 3 to the motor instead of the RAM bank
+                if self.rumble {
+                    self.motor_on = (value & RUMBLE_MOTOR) != 0;
+                    self.ram_bank = value & 0x07;
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+                self.ram_offset = self.ram_bank as usize * 0x2000;
+            }
+
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled { return; }
+                self.ram.write(self.ram_offset + (addr as usize - 0xA000), value);
+            }
+
+            _ => {}
+        }
+    }
+
+    fn update_rom_offset(&mut self) {
+        self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+    }
+}
@@ -0,0 +1,365 @@
+use super::ram::CartRam;
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+// accelerometer reading at rest, and roughly how far one g of tilt moves it
+const TILT_CENTER: f32 = 0x81D0 as f32;
+const TILT_PER_G: f32 = 0x70 as f32;
+
+const EEPROM_CS: u8 = 0b1000_0000;
+const EEPROM_CLK: u8 = 0b0100_0000;
+const EEPROM_DI: u8 = 0b0000_0010;
+const EEPROM_DO: u8 = 0b0000_0001;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EepromState {
+    Idle,
+    // start bit, 2 opcode bits and 8 address bits
+    Command,
+    Read,
+    Write,
+    WriteAll,
+}
+
+/// 93LC56 serial EEPROM in x16 organisation, 128 words stored little endian in `ram`
+struct Eeprom {
+    cs: bool,
+    clk: bool,
+    di: bool,
+    do_: bool,
+
+    state: EepromState,
+    shift: u16,
+    bits: u8,
+    address: u8,
+    write_enabled: bool,
+}
+
+impl Eeprom {
+    fn new() -> Self {
+        Self {
+            cs: false,
+            clk: false,
+            di: false,
+            do_: true,
+            state: EepromState::Idle,
+            shift: 0,
+            bits: 0,
+            address: 0,
+            write_enabled: false,
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.cs);
+        w.bool(self.clk);
+        w.bool(self.di);
+        w.bool(self.do_);
+
+        w.u8(self.state as u8);
+        w.u16(self.shift);
+        w.u8(self.bits);
+        w.u8(self.address);
+        w.bool(self.write_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.cs = r.bool()?;
+        self.clk = r.bool()?;
+        self.di = r.bool()?;
+        self.do_ = r.bool()?;
+
+        self.state = match r.u8()? {
+            0 => EepromState::Idle,
+            1 => EepromState::Command,
+            2 => EepromState::Read,
+            3 => EepromState::Write,
+            4 => EepromState::WriteAll,
+            state => return Err(NemuError::InvalidState(format!("Invalid EEPROM state: {}", state))),
+        };
+        self.shift = r.u16()?;
+        self.bits = r.u8()?;
+        self.address = r.u8()?;
+        self.write_enabled = r.bool()?;
+        Ok(())
+    }
+
+    fn read(&self) -> u8 {
+        let mut value = 0;
+        if self.cs { value |= EEPROM_CS; }
+        if self.clk { value |= EEPROM_CLK; }
+        if self.di { value |= EEPROM_DI; }
+        if self.do_ { value |= EEPROM_DO; }
+        value
+    }
+
+    fn write(&mut self, value: u8, ram: &mut CartRam) {
+        let cs = (value & EEPROM_CS) != 0;
+        let clk = (value & EEPROM_CLK) != 0;
+        self.di = (value & EEPROM_DI) != 0;
+
+        // deselecting the chip aborts whatever command was in flight
+        if !cs {
+            self.state = EepromState::Idle;
+            self.do_ = true;
+        } else if clk && !self.clk {
+            self.clock_in(ram);
+        }
+
+        self.cs = cs;
+        self.clk = clk;
+    }
+
+    fn clock_in(&mut self, ram: &mut CartRam) {
+        let bit = self.di as u16;
+
+        match self.state {
+            EepromState::Idle => {
+                if bit == 1 {
+                    self.state = EepromState::Command;
+                    self.shift = 0;
+                    self.bits = 0;
+                }
+            }
+
+            EepromState::Command => {
+                self.shift = (self.shift << 1) | bit;
+                self.bits += 1;
+                if self.bits == 10 {
+                    self.run_command(ram);
+                }
+            }
+
+            EepromState::Read => {
+                // sequential reads roll over into the next word
+                if self.bits == 16 {
+                    self.address = (self.address + 1) & 0x7F;
+                    self.shift = read_word(ram, self.address);
+                    self.bits = 0;
+                }
+                self.do_ = (self.shift & 0x8000) != 0;
+                self.shift <<= 1;
+                self.bits += 1;
+            }
+
+            EepromState::Write | EepromState::WriteAll => {
+                self.shift = (self.shift << 1) | bit;
+                self.bits += 1;
+                if self.bits < 16 {
+                    return;
+                }
+
+                if self.write_enabled {
+                    if self.state == EepromState::WriteAll {
+                        for address in 0..0x80 {
+                            write_word(ram, address, self.shift);
+                        }
+                    } else {
+                        write_word(ram, self.address, self.shift);
+                    }
+                }
+                self.state = EepromState::Idle;
+                self.do_ = true;
+            }
+        }
+    }
+
+    fn run_command(&mut self, ram: &mut CartRam) {
+        let opcode = (self.shift >> 8) & 0x03;
+        let field = self.shift as u8;
+        self.address = field & 0x7F;
+        self.state = EepromState::Idle;
+        self.shift = 0;
+        self.bits = 0;
+
+        match opcode {
+            0b10 => {
+                self.state = EepromState::Read;
+                self.shift = read_word(ram, self.address);
+                // a dummy zero precedes the data
+                self.do_ = false;
+            }
+            0b01 => self.state = EepromState::Write,
+            0b11 => {
+                if self.write_enabled {
+                    write_word(ram, self.address, 0xFFFF);
+                }
+                self.do_ = true;
+            }
+            // the top address bits pick between the special commands
+            _ => match field >> 6 {
+                0b00 => self.write_enabled = false,
+                0b01 => self.state = EepromState::WriteAll,
+                0b10 => {
+                    if self.write_enabled {
+                        for address in 0..0x80 {
+                            write_word(ram, address, 0xFFFF);
+                        }
+                    }
+                    self.do_ = true;
+                }
+                _ => self.write_enabled = true,
+            },
+        }
+    }
+}
+
+fn read_word(ram: &CartRam, address: u8) -> u16 {
+    let index = address as usize * 2;
+    u16::from_le_bytes([ram.read(index), ram.read(index + 1)])
+}
+
+fn write_word(ram: &mut CartRam, address: u8, value: u16) {
+    let index = address as usize * 2;
+    let [low, high] = value.to_le_bytes();
+    ram.write(index, low);
+    ram.write(index + 1, high);
+}
+
+pub(crate) struct Mbc7 {
+    rom: Vec<u8>,
+    pub(crate) ram: CartRam,
+    eeprom: Eeprom,
+
+    rom_bank: u8,
+    // RAM access needs both 0x0A written to 0x0000-0x1FFF and 0x40 written to 0x4000-0x5FFF
+    ram_enabled: bool,
+    ram_enabled2: bool,
+
+    tilt: (f32, f32),
+    latch_erased: bool,
+    accel_x: u16,
+    accel_y: u16,
+
+    rom_offset: usize,
+    rom_mask: u8,
+}
+
+impl Mbc7 {
+    pub(crate) fn new(rom: Vec<u8>) -> Self {
+        let num_banks = rom.len() / 0x4000;
+        let rom_mask = (num_banks.next_power_of_two() - 1) as u8;
+
+        // a blank EEPROM reads back all ones
+        let mut ram = CartRam::new(0x100);
+        ram.import(&[0xFF; 0x100]);
+
+        Self {
+            rom,
+            ram,
+            eeprom: Eeprom::new(),
+            rom_bank: 1,
+            ram_enabled: false,
+            ram_enabled2: false,
+            tilt: (0.0, 0.0),
+            latch_erased: false,
+            accel_x: 0x8000,
+            accel_y: 0x8000,
+            rom_offset: 0x4000,
+            rom_mask,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn has_battery(&self) -> bool {
+        true
+    }
+
+    pub(crate) fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x, y);
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.ram.save_state(w);
+        self.eeprom.save_state(w);
+
+        w.u8(self.rom_bank);
+        w.bool(self.ram_enabled);
+        w.bool(self.ram_enabled2);
+        w.bool(self.latch_erased);
+        w.u16(self.accel_x);
+        w.u16(self.accel_y);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.ram.load_state(r)?;
+        self.eeprom.load_state(r)?;
+
+        self.rom_bank = r.u8()?;
+        self.ram_enabled = r.bool()?;
+        self.ram_enabled2 = r.bool()?;
+        self.latch_erased = r.bool()?;
+        self.accel_x = r.u16()?;
+        self.accel_y = r.u16()?;
+
+        self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+
+            0x4000..=0x7FFF => {
+                let index = self.rom_offset + (addr as usize - 0x4000);
+                self.rom[index]
+            }
+
+            0xA000..=0xAFFF => {
+                if !self.ram_enabled || !self.ram_enabled2 { return 0xFF; }
+
+                match (addr >> 4) & 0x0F {
+                    0x2 => self.accel_x as u8,
+                    0x3 => (self.accel_x >> 8) as u8,
+                    0x4 => self.accel_y as u8,
+                    0x5 => (self.accel_y >> 8) as u8,
+                    0x6 => 0x00,
+                    0x8 => self.eeprom.read(),
+                    _ => 0xFF,
+                }
+            }
+
+            _ => 0xFF,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value == 0x0A;
+            }
+
+            0x2000..=0x3FFF => {
+                self.rom_bank = value;
+                self.rom_offset = (self.rom_bank & self.rom_mask) as usize * 0x4000;
+            }
+
+            0x4000..=0x5FFF => {
+                self.ram_enabled2 = value == 0x40;
+            }
+
+            0xA000..=0xAFFF => {
+                if !self.ram_enabled || !self.ram_enabled2 { return; }
+
+                match (addr >> 4) & 0x0F {
+                    0x0 if value == 0x55 => {
+                        self.latch_erased = true;
+                        self.accel_x = 0x8000;
+                        self.accel_y = 0x8000;
+                    }
+                    0x1 if value == 0xAA && self.latch_erased => {
+                        self.latch_erased = false;
+                        self.accel_x = (TILT_CENTER + self.tilt.0 * TILT_PER_G) as u16;
+                        self.accel_y = (TILT_CENTER + self.tilt.1 * TILT_PER_G) as u16;
+                    }
+                    0x8 => self.eeprom.write(value, &mut self.ram),
+                    _ => {}
+                }
+            }
+
+            _ => {}
+        }
+    }
+}
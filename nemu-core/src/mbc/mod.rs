@@ -1,11 +1,32 @@
 use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
 
+mod camera;
+mod header;
+mod huc1;
+mod huc3;
 mod no_mbc;
 mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod mbc7;
+mod ram;
+mod rtc;
+
+pub use header::CartridgeHeader;
+pub use rtc::RtcClock;
 
 pub(crate) enum MbcType {
     NoMbc(no_mbc::NoMbc),
     Mbc1(mbc1::Mbc1),
+    Mbc2(mbc2::Mbc2),
+    Mbc3(mbc3::Mbc3),
+    Mbc5(mbc5::Mbc5),
+    Mbc7(mbc7::Mbc7),
+    Camera(camera::PocketCamera),
+    Huc1(huc1::Huc1),
+    Huc3(huc3::Huc3),
 }
 
 impl Default for MbcType {
@@ -15,12 +36,27 @@ impl Default for MbcType {
 }
 
 impl MbcType {
-    pub(crate) fn new(data: Vec<u8>) -> Result<Self, NemuError> {
-        let mbc_type = data[0x147];
+    pub(crate) fn new(data: Vec<u8>, header: &CartridgeHeader) -> Result<Self, NemuError> {
+        let mbc_type = header.cartridge_type;
 
         match mbc_type {
             0x00 => Ok(Self::NoMbc(no_mbc::NoMbc::new(data))),
-            0x01 | 0x02 | 0x03 => Ok(Self::Mbc1(mbc1::Mbc1::new(data))),
+            0x01 | 0x02 | 0x03 => Ok(Self::Mbc1(mbc1::Mbc1::new(data, mbc_type == 0x03))),
+            0x05 | 0x06 => Ok(Self::Mbc2(mbc2::Mbc2::new(data, mbc_type == 0x06))),
+            0x0F..=0x13 => Ok(Self::Mbc3(mbc3::Mbc3::new(
+                data,
+                matches!(mbc_type, 0x0F | 0x10 | 0x13),
+                matches!(mbc_type, 0x0F | 0x10),
+            ))),
+            0x19..=0x1E => Ok(Self::Mbc5(mbc5::Mbc5::new(
+                data,
+                matches!(mbc_type, 0x1B | 0x1E),
+                matches!(mbc_type, 0x1C..=0x1E),
+            ))),
+            0x22 => Ok(Self::Mbc7(mbc7::Mbc7::new(data))),
+            0xFC => Ok(Self::Camera(camera::PocketCamera::new(data))),
+            0xFE => Ok(Self::Huc3(huc3::Huc3::new(data))),
+            0xFF => Ok(Self::Huc1(huc1::Huc1::new(data))),
 
             _ => Err(NemuError::InvalidRom(format!(
                 "Unsupported MBC type: {:#04X}",
@@ -34,6 +70,13 @@ impl MbcType {
         match self {
             MbcType::NoMbc(mbc) => mbc.read(addr),
             MbcType::Mbc1(mbc) => mbc.read(addr),
+            MbcType::Mbc2(mbc) => mbc.read(addr),
+            MbcType::Mbc3(mbc) => mbc.read(addr),
+            MbcType::Mbc5(mbc) => mbc.read(addr),
+            MbcType::Mbc7(mbc) => mbc.read(addr),
+            MbcType::Camera(mbc) => mbc.read(addr),
+            MbcType::Huc1(mbc) => mbc.read(addr),
+            MbcType::Huc3(mbc) => mbc.read(addr),
         }
     }
 
@@ -42,6 +85,181 @@ impl MbcType {
         match self {
             MbcType::NoMbc(_) => {},
             MbcType::Mbc1(mbc) => mbc.write(addr, value),
+            MbcType::Mbc2(mbc) => mbc.write(addr, value),
+            MbcType::Mbc3(mbc) => mbc.write(addr, value),
+            MbcType::Mbc5(mbc) => mbc.write(addr, value),
+            MbcType::Mbc7(mbc) => mbc.write(addr, value),
+            MbcType::Camera(mbc) => mbc.write(addr, value),
+            MbcType::Huc1(mbc) => mbc.write(addr, value),
+            MbcType::Huc3(mbc) => mbc.write(addr, value),
+        }
+    }
+
+    pub(crate) fn has_battery(&self) -> bool {
+        match self {
+            MbcType::NoMbc(_) => false,
+            MbcType::Mbc1(mbc) => mbc.has_battery(),
+            MbcType::Mbc2(mbc) => mbc.has_battery(),
+            MbcType::Mbc3(mbc) => mbc.has_battery(),
+            MbcType::Mbc5(mbc) => mbc.has_battery(),
+            MbcType::Mbc7(mbc) => mbc.has_battery(),
+            MbcType::Camera(mbc) => mbc.has_battery(),
+            MbcType::Huc1(mbc) => mbc.has_battery(),
+            MbcType::Huc3(mbc) => mbc.has_battery(),
+        }
+    }
+
+    /// External RAM of battery backed cartridges, the only kind whose RAM outlives a power cycle
+    fn battery_ram(&mut self) -> Option<&mut ram::CartRam> {
+        if !self.has_battery() {
+            return None;
+        }
+
+        match self {
+            MbcType::NoMbc(_) => None,
+            MbcType::Mbc1(mbc) => Some(&mut mbc.ram),
+            MbcType::Mbc2(mbc) => Some(&mut mbc.ram),
+            MbcType::Mbc3(mbc) => Some(&mut mbc.ram),
+            MbcType::Mbc5(mbc) => Some(&mut mbc.ram),
+            MbcType::Mbc7(mbc) => Some(&mut mbc.ram),
+            MbcType::Camera(mbc) => Some(&mut mbc.ram),
+            MbcType::Huc1(mbc) => Some(&mut mbc.ram),
+            MbcType::Huc3(mbc) => Some(&mut mbc.ram),
+        }
+    }
+
+    pub(crate) fn save_dirty(&self) -> bool {
+        match self {
+            MbcType::NoMbc(_) => false,
+            MbcType::Mbc1(mbc) => mbc.has_battery() && mbc.ram.is_dirty(),
+            MbcType::Mbc2(mbc) => mbc.has_battery() && mbc.ram.is_dirty(),
+            MbcType::Mbc3(mbc) => mbc.has_battery() && mbc.ram.is_dirty(),
+            MbcType::Mbc5(mbc) => mbc.has_battery() && mbc.ram.is_dirty(),
+            MbcType::Mbc7(mbc) => mbc.has_battery() && mbc.ram.is_dirty(),
+            MbcType::Camera(mbc) => mbc.has_battery() && mbc.ram.is_dirty(),
+            MbcType::Huc1(mbc) => mbc.has_battery() && mbc.ram.is_dirty(),
+            MbcType::Huc3(mbc) => mbc.has_battery() && mbc.ram.is_dirty(),
+        }
+    }
+
+    pub(crate) fn export_save(&mut self) -> Option<Vec<u8>> {
+        let mut data = self.battery_ram()?.export();
+
+        match self {
+            MbcType::Mbc3(mbc3::Mbc3 { rtc: Some(rtc), .. }) => data.extend_from_slice(&rtc.save_footer()),
+            MbcType::Huc3(mbc) => data.extend_from_slice(&mbc.save_footer()),
+            _ => {}
+        }
+
+        Some(data)
+    }
+
+    pub(crate) fn import_save(&mut self, data: &[u8]) -> Result<(), NemuError> {
+        let ram = self
+            .battery_ram()
+            .ok_or_else(|| NemuError::InvalidSave("Cartridge has no battery backed RAM".to_string()))?;
+
+        let ram_size = ram.len();
+        if data.len() < ram_size {
+            return Err(NemuError::InvalidSave(format!(
+                "Expected {} bytes of RAM, got {}",
+                ram_size,
+                data.len()
+            )));
+        }
+        let (ram_data, footer) = data.split_at(ram_size);
+
+        match self {
+            MbcType::Mbc3(mbc3::Mbc3 { rtc: Some(rtc), .. }) if matches!(footer.len(), 44 | rtc::SAVE_FOOTER_SIZE) => {
+                rtc.load_save_footer(footer);
+            }
+            MbcType::Huc3(mbc) if footer.len() == huc3::SAVE_FOOTER_SIZE => {
+                mbc.load_save_footer(footer.try_into().unwrap());
+            }
+            // saves without the RTC block are still accepted, the clock just keeps its current time
+            _ if footer.is_empty() => {}
+            _ => {
+                return Err(NemuError::InvalidSave(format!(
+                    "Unexpected save size of {} bytes",
+                    data.len()
+                )));
+            }
+        }
+
+        self.battery_ram().unwrap().import(ram_data);
+        Ok(())
+    }
+
+    /// Bank registers and RAM, the ROM itself is never part of a save state
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        match self {
+            MbcType::NoMbc(_) => {}
+            MbcType::Mbc1(mbc) => mbc.save_state(w),
+            MbcType::Mbc2(mbc) => mbc.save_state(w),
+            MbcType::Mbc3(mbc) => mbc.save_state(w),
+            MbcType::Mbc5(mbc) => mbc.save_state(w),
+            MbcType::Mbc7(mbc) => mbc.save_state(w),
+            MbcType::Camera(mbc) => mbc.save_state(w),
+            MbcType::Huc1(mbc) => mbc.save_state(w),
+            MbcType::Huc3(mbc) => mbc.save_state(w),
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        match self {
+            MbcType::NoMbc(_) => Ok(()),
+            MbcType::Mbc1(mbc) => mbc.load_state(r),
+            MbcType::Mbc2(mbc) => mbc.load_state(r),
+            MbcType::Mbc3(mbc) => mbc.load_state(r),
+            MbcType::Mbc5(mbc) => mbc.load_state(r),
+            MbcType::Mbc7(mbc) => mbc.load_state(r),
+            MbcType::Camera(mbc) => mbc.load_state(r),
+            MbcType::Huc1(mbc) => mbc.load_state(r),
+            MbcType::Huc3(mbc) => mbc.load_state(r),
+        }
+    }
+
+    pub(crate) fn set_tilt(&mut self, x: f32, y: f32) {
+        if let MbcType::Mbc7(mbc) = self {
+            mbc.set_tilt(x, y);
+        }
+    }
+
+    pub(crate) fn set_camera_image(&mut self, image: &[u8; camera::IMAGE_WIDTH * camera::IMAGE_HEIGHT]) {
+        if let MbcType::Camera(mbc) = self {
+            mbc.set_image(image);
+        }
+    }
+
+    pub(crate) fn tone(&self) -> u8 {
+        match self {
+            MbcType::Huc3(mbc) => mbc.tone(),
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn rumble_active(&self) -> bool {
+        match self {
+            MbcType::Mbc5(mbc) => mbc.rumble_active(),
+            _ => false,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn tick(&mut self, cycles: u8) {
+        match self {
+            MbcType::Mbc3(mbc) => mbc.tick(cycles),
+            MbcType::Camera(mbc) => mbc.tick(cycles),
+            MbcType::Huc3(mbc) => mbc.tick(cycles),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn set_rtc_clock(&mut self, clock: RtcClock) {
+        match self {
+            MbcType::Mbc3(mbc) => mbc.set_rtc_clock(clock),
+            MbcType::Huc3(mbc) => mbc.set_rtc_clock(clock),
+            _ => {}
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,73 @@
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+/// External cartridge RAM, tracks whether it changed since the last export
+pub(crate) struct CartRam {
+    data: Vec<u8>,
+    dirty: bool,
+}
+
+impl CartRam {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            data: vec![0; size],
+            dirty: false,
+        }
+    }
+
+    /// RAM size declared at 0x149 in the cartridge header
+    pub(crate) fn header_size(rom: &[u8]) -> usize {
+        super::header::ram_size_bytes(rom[0x149])
+    }
+
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Address lines past the chip size are not connected, so larger offsets mirror
+    #[inline(always)]
+    pub(crate) fn read(&self, index: usize) -> u8 {
+        if self.data.is_empty() { return 0xFF; }
+        self.data[index & (self.data.len() - 1)]
+    }
+
+    #[inline(always)]
+    pub(crate) fn write(&mut self, index: usize, value: u8) {
+        if self.data.is_empty() { return; }
+
+        let mask = self.data.len() - 1;
+        let byte = &mut self.data[index & mask];
+        if *byte != value {
+            *byte = value;
+            self.dirty = true;
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub(crate) fn export(&mut self) -> Vec<u8> {
+        self.dirty = false;
+        self.data.clone()
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.data);
+    }
+
+    /// Loaded RAM differs from whatever is on disk, so it counts as unsaved
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        r.bytes(&mut self.data)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// `data` must be exactly as long as the RAM
+    pub(crate) fn import(&mut self, data: &[u8]) {
+        self.data.copy_from_slice(data);
+        self.dirty = false;
+    }
+}
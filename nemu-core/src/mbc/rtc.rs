@@ -0,0 +1,272 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+// the RTC runs off a 32.768 kHz crystal, which comes out to one second every 2^20 M-cycles
+pub(crate) const CYCLES_PER_SECOND: u32 = 1 << 20;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Size of the RTC block other emulators append to `.sav` files
+pub(crate) const SAVE_FOOTER_SIZE: usize = 48;
+
+const DH_DAY_HIGH: u8 = 0b0000_0001;
+const DH_HALT: u8 = 0b0100_0000;
+const DH_CARRY: u8 = 0b1000_0000;
+
+/// What drives the cartridge real-time clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RtcClock {
+    /// Follows the host's wall clock, even while the emulator is paused or closed
+    #[default]
+    WallTime,
+    /// Advances with emulated cycles only, keeping runs fully deterministic
+    Emulated,
+}
+
+pub(crate) struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day_low: u8,
+    day_high: u8,
+
+    latched: [u8; 5],
+    latch_prev: u8,
+
+    clock: RtcClock,
+    cycles: u32,
+    last_sync: u64,
+}
+
+impl Rtc {
+    pub(crate) fn new() -> Self {
+        Self {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            day_low: 0,
+            day_high: 0,
+            latched: [0; 5],
+            latch_prev: 0xFF,
+            clock: RtcClock::WallTime,
+            cycles: 0,
+            last_sync: unix_now(),
+        }
+    }
+
+    pub(crate) fn set_clock(&mut self, clock: RtcClock) {
+        self.sync();
+        self.clock = clock;
+        self.last_sync = unix_now();
+    }
+
+    #[inline(always)]
+    fn halted(&self) -> bool {
+        (self.day_high & DH_HALT) != 0
+    }
+
+    #[inline(always)]
+    pub(crate) fn tick(&mut self, cycles: u8) {
+        if self.clock != RtcClock::Emulated || self.halted() {
+            return;
+        }
+
+        self.cycles += cycles as u32;
+        if self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.advance(1);
+        }
+    }
+
+    /// Catches up with the host clock, only does anything in wall time mode
+    fn sync(&mut self) {
+        if self.clock != RtcClock::WallTime {
+            return;
+        }
+
+        let now = unix_now();
+        let elapsed = now.saturating_sub(self.last_sync);
+        self.last_sync = now;
+
+        if !self.halted() {
+            self.advance(elapsed);
+        }
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        // registers written with out of range values count up to their bit limit before wrapping,
+        // so step one second at a time until everything is back in range
+        while seconds > 0 && (self.seconds > 59 || self.minutes > 59 || self.hours > 23) {
+            self.tick_second();
+            seconds -= 1;
+        }
+
+        if seconds == 0 {
+            return;
+        }
+
+        let days = self.days() as u64;
+        let total = days * SECONDS_PER_DAY
+            + self.hours as u64 * 3600
+            + self.minutes as u64 * 60
+            + self.seconds as u64
+            + seconds;
+
+        let days = total / SECONDS_PER_DAY;
+        let time = total % SECONDS_PER_DAY;
+
+        self.seconds = (time % 60) as u8;
+        self.minutes = ((time / 60) % 60) as u8;
+        self.hours = (time / 3600) as u8;
+
+        if days > 0x1FF {
+            self.day_high |= DH_CARRY;
+        }
+        self.set_days((days & 0x1FF) as u16);
+    }
+
+    fn tick_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+
+        let days = self.days() + 1;
+        if days > 0x1FF {
+            self.day_high |= DH_CARRY;
+        }
+        self.set_days(days & 0x1FF);
+    }
+
+    #[inline(always)]
+    fn days(&self) -> u16 {
+        (((self.day_high & DH_DAY_HIGH) as u16) << 8) | self.day_low as u16
+    }
+
+    #[inline(always)]
+    fn set_days(&mut self, days: u16) {
+        self.day_low = days as u8;
+        self.day_high = (self.day_high & !DH_DAY_HIGH) | ((days >> 8) as u8 & DH_DAY_HIGH);
+    }
+
+    /// Writing 0x00 then 0x01 copies the live registers into the readable latch
+    pub(crate) fn write_latch(&mut self, value: u8) {
+        if self.latch_prev == 0x00 && value == 0x01 {
+            self.sync();
+            self.latched = [self.seconds, self.minutes, self.hours, self.day_low, self.day_high];
+        }
+        self.latch_prev = value;
+    }
+
+    /// `register` is the 0x08-0x0C value selected through the RAM bank register
+    pub(crate) fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.latched[0] | 0xC0,
+            0x09 => self.latched[1] | 0xC0,
+            0x0A => self.latched[2] | 0xE0,
+            0x0B => self.latched[3],
+            0x0C => self.latched[4] | 0x3E,
+            _ => 0xFF,
+        }
+    }
+
+    pub(crate) fn write(&mut self, register: u8, value: u8) {
+        self.sync();
+
+        match register {
+            0x08 => {
+                self.seconds = value & 0x3F;
+                self.cycles = 0;
+            }
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.day_low = value,
+            0x0C => self.day_high = value & (DH_DAY_HIGH | DH_HALT | DH_CARRY),
+            _ => {}
+        }
+    }
+
+    /// Registers as they are, without catching up with the host clock first
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        for value in [self.seconds, self.minutes, self.hours, self.day_low, self.day_high] {
+            w.u8(value);
+        }
+        w.bytes(&self.latched);
+        w.u8(self.latch_prev);
+        w.u32(self.cycles);
+    }
+
+    /// Unlike a battery save, a save state rewinds the clock along with everything else, so the
+    /// time spent away from the state is not caught up on
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.seconds = r.u8()?;
+        self.minutes = r.u8()?;
+        self.hours = r.u8()?;
+        self.day_low = r.u8()?;
+        self.day_high = r.u8()?;
+        r.bytes(&mut self.latched)?;
+        self.latch_prev = r.u8()?;
+        self.cycles = r.u32()?;
+
+        self.last_sync = unix_now();
+        Ok(())
+    }
+
+    /// Live and latched registers as little endian u32s, followed by a u64 unix timestamp
+    pub(crate) fn save_footer(&mut self) -> [u8; SAVE_FOOTER_SIZE] {
+        self.sync();
+
+        let live = [self.seconds, self.minutes, self.hours, self.day_low, self.day_high];
+        let mut footer = [0; SAVE_FOOTER_SIZE];
+
+        for (i, &value) in live.iter().chain(&self.latched).enumerate() {
+            footer[i * 4] = value;
+        }
+        footer[40..].copy_from_slice(&unix_now().to_le_bytes());
+        footer
+    }
+
+    /// Accepts the 48 byte footer and the older 44 byte one with a 32-bit timestamp
+    pub(crate) fn load_save_footer(&mut self, footer: &[u8]) {
+        let register = |i: usize| footer[i * 4];
+
+        self.seconds = register(0) & 0x3F;
+        self.minutes = register(1) & 0x3F;
+        self.hours = register(2) & 0x1F;
+        self.day_low = register(3);
+        self.day_high = register(4) & (DH_DAY_HIGH | DH_HALT | DH_CARRY);
+        for (i, latched) in self.latched.iter_mut().enumerate() {
+            *latched = register(5 + i);
+        }
+        self.cycles = 0;
+
+        let mut timestamp = [0; 8];
+        timestamp[..footer.len() - 40].copy_from_slice(&footer[40..]);
+
+        // catch up on the time that passed while the game was not running
+        self.last_sync = u64::from_le_bytes(timestamp);
+        self.sync();
+        self.last_sync = unix_now();
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
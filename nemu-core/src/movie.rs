@@ -0,0 +1,295 @@
+//! Input movies, a recording of the joypad for every frame that replays bit for bit.
+//!
+//! Movie file layout, all values little endian:
+//!
+//! | Offset | Size | Contents                                                      |
+//! |--------|------|---------------------------------------------------------------|
+//! | 0x00   | 4    | Magic `NEMV`                                                  |
+//! | 0x04   | 2    | Format version, currently 3                                   |
+//! | 0x06   | 4    | CRC-32 of the whole ROM                                       |
+//! | 0x0A   | 1    | Console model, 0 is DMG, 1 is CGB and 2 is SGB                |
+//! | 0x0B   | 1    | PPU lockouts, 0 is off and 1 is on                            |
+//! | 0x0C   | 1    | Start condition, 0 is power-on and 1 is an embedded save state |
+//! | 0x0D   | 4+n  | Only for save state starts: `u32` length followed by the state |
+//! | ...    | 4    | Number of frames                                              |
+//! | ...    | 9*n  | Per frame: joypad byte, then the `u64` state hash after it ran |
+//!
+//! Version 1 movies have no model byte and are played back as DMG, versions 1 and 2 have no
+//! lockouts byte and are played back with the lockouts on.
+//!
+//! The joypad byte has A, B, Select and Start in bits 0-3 and Right, Left, Up and Down in bits
+//! 4-7, a set bit is a held button. The state hash is FNV-1a over `Nemu::save_state`.
+//!
+//! Power-on movies start from a freshly inserted cartridge with blank RAM, start from a save
+//! state to keep an existing battery save. Both recording and playback switch the cartridge RTC
+//! to `RtcClock::Emulated`, and nothing should be plugged into the link port.
+
+use crate::crc::crc32;
+use crate::{JoypadButton, Model, Nemu, NemuError, RtcClock};
+
+const MOVIE_MAGIC: [u8; 4] = *b"NEMV";
+const MOVIE_VERSION: u16 = 3;
+
+const START_POWER_ON: u8 = 0;
+const START_SAVE_STATE: u8 = 1;
+
+const BUTTONS: [JoypadButton; 4] = [
+    JoypadButton::RightOrA,
+    JoypadButton::LeftOrB,
+    JoypadButton::UpOrSelect,
+    JoypadButton::DownOrStart,
+];
+
+pub struct Movie {
+    rom_crc: u32,
+    model: Model,
+    ppu_lockouts: bool,
+    start_state: Option<Vec<u8>>,
+    inputs: Vec<u8>,
+    hashes: Vec<u64>,
+}
+
+impl Movie {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, NemuError> {
+        let mut pos: usize = 0;
+        let mut take = |len: usize| {
+            let bytes = pos
+                .checked_add(len)
+                .and_then(|end| data.get(pos..end))
+                .ok_or_else(|| NemuError::InvalidMovie("Movie is truncated".to_string()))?;
+            pos += len;
+            Ok::<_, NemuError>(bytes)
+        };
+
+        if take(4)? != MOVIE_MAGIC {
+            return Err(NemuError::InvalidMovie("Not a Nemu movie".to_string()));
+        }
+
+        let version = u16::from_le_bytes(take(2)?.try_into().unwrap());
+        if version == 0 || version > MOVIE_VERSION {
+            return Err(NemuError::InvalidMovie(format!("Unsupported format version {}", version)));
+        }
+
+        let rom_crc = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let model = match version {
+            1 => Model::Dmg,
+            _ => match take(1)?[0] {
+                0 => Model::Dmg,
+                1 => Model::Cgb,
+                2 => Model::Sgb,
+                model => return Err(NemuError::InvalidMovie(format!("Unknown console model {}", model))),
+            },
+        };
+        let ppu_lockouts = match version {
+            1 | 2 => true,
+            _ => match take(1)?[0] {
+                0 => false,
+                1 => true,
+                lockouts => return Err(NemuError::InvalidMovie(format!("Invalid PPU lockouts flag {}", lockouts))),
+            },
+        };
+        let start_state = match take(1)?[0] {
+            START_POWER_ON => None,
+            START_SAVE_STATE => {
+                let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+                Some(take(len)?.to_vec())
+            }
+            start => return Err(NemuError::InvalidMovie(format!("Unknown start condition {}", start))),
+        };
+
+        // the frame count is checked against the data before anything is allocated for it
+        let frames = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+        let frame_data = take(frames.saturating_mul(9))?;
+        let inputs = frame_data.chunks_exact(9).map(|frame| frame[0]).collect();
+        let hashes = frame_data
+            .chunks_exact(9)
+            .map(|frame| u64::from_le_bytes(frame[1..].try_into().unwrap()))
+            .collect();
+
+        if pos != data.len() {
+            return Err(NemuError::InvalidMovie("Trailing data after the last frame".to_string()));
+        }
+
+        Ok(Self { rom_crc, model, ppu_lockouts, start_state, inputs, hashes })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(21 + self.inputs.len() * 9);
+        out.extend_from_slice(&MOVIE_MAGIC);
+        out.extend_from_slice(&MOVIE_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.rom_crc.to_le_bytes());
+        out.push(match self.model {
+            Model::Dmg => 0,
+            Model::Cgb => 1,
+            Model::Sgb => 2,
+        });
+        out.push(self.ppu_lockouts as u8);
+
+        match &self.start_state {
+            None => out.push(START_POWER_ON),
+            Some(state) => {
+                out.push(START_SAVE_STATE);
+                out.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                out.extend_from_slice(state);
+            }
+        }
+
+        out.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        for (input, hash) in self.inputs.iter().zip(&self.hashes) {
+            out.push(*input);
+            out.extend_from_slice(&hash.to_le_bytes());
+        }
+        out
+    }
+
+    /// Number of recorded frames
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    pub fn starts_from_state(&self) -> bool {
+        self.start_state.is_some()
+    }
+
+    /// Console the movie was recorded on, playback switches to it
+    pub fn model(&self) -> Model {
+        self.model
+    }
+}
+
+pub struct MovieRecorder {
+    movie: Movie,
+}
+
+impl MovieRecorder {
+    /// Inserts `rom` fresh and resets the system, the movie starts at power-on
+    pub fn power_on(nemu: &mut Nemu, rom: &[u8]) -> Result<Self, NemuError> {
+        nemu.set_rtc_clock(RtcClock::Emulated);
+        nemu.load_cartridge(rom)?;
+        nemu.reset();
+        release_all(nemu);
+
+        Ok(Self::new(nemu, rom, None))
+    }
+
+    /// Starts from the current machine state, which is embedded in the movie. `rom` has to be the
+    /// cartridge that is already loaded.
+    pub fn from_state(nemu: &mut Nemu, rom: &[u8]) -> Result<Self, NemuError> {
+        let header = crate::CartridgeHeader::parse(rom)?;
+        if nemu.cartridge_header() != Some(&header) {
+            return Err(NemuError::InvalidMovie("ROM does not match the loaded cartridge".to_string()));
+        }
+
+        nemu.set_rtc_clock(RtcClock::Emulated);
+        let state = nemu.save_state();
+        Ok(Self::new(nemu, rom, Some(state)))
+    }
+
+    fn new(nemu: &Nemu, rom: &[u8], start_state: Option<Vec<u8>>) -> Self {
+        Self {
+            movie: Movie {
+                rom_crc: crc32(rom),
+                model: nemu.model,
+                ppu_lockouts: nemu.bus.ppu.lockouts(),
+                start_state,
+                inputs: Vec::new(),
+                hashes: Vec::new(),
+            },
+        }
+    }
+
+    /// Runs one frame with whatever the frontend last passed to `Nemu::set_joypad` and records it
+    pub fn record_frame(&mut self, nemu: &mut Nemu) {
+        let input = nemu.bus.joypad.pressed();
+        nemu.run_frame();
+
+        self.movie.inputs.push(input);
+        self.movie.hashes.push(state_hash(nemu));
+    }
+
+    /// Frames recorded so far
+    pub fn frame(&self) -> usize {
+        self.movie.len()
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+}
+
+impl MoviePlayer {
+    /// Checks `rom` against the movie, then inserts it and restores the start condition
+    pub fn new(movie: Movie, nemu: &mut Nemu, rom: &[u8]) -> Result<Self, NemuError> {
+        if crc32(rom) != movie.rom_crc {
+            return Err(NemuError::InvalidMovie("Movie was recorded with a different ROM".to_string()));
+        }
+
+        nemu.set_model(movie.model);
+        nemu.set_ppu_lockouts(movie.ppu_lockouts);
+        nemu.set_rtc_clock(RtcClock::Emulated);
+        nemu.load_cartridge(rom)?;
+        nemu.reset();
+        release_all(nemu);
+
+        if let Some(state) = &movie.start_state {
+            nemu.load_state(state)?;
+        }
+
+        Ok(Self { movie, frame: 0 })
+    }
+
+    /// Frames played so far
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame == self.movie.len()
+    }
+
+    /// Feeds the next frame's input through `Nemu::set_joypad` and runs it. Returns false once
+    /// the movie is over, and `NemuError::MovieDesync` when the state no longer matches the recording.
+    pub fn play_frame(&mut self, nemu: &mut Nemu) -> Result<bool, NemuError> {
+        if self.is_finished() {
+            return Ok(false);
+        }
+
+        let input = self.movie.inputs[self.frame];
+        for (bit, button) in BUTTONS.into_iter().enumerate() {
+            nemu.set_joypad(button, (input & (1 << bit)) != 0, false);
+            nemu.set_joypad(button, (input & (0x10 << bit)) != 0, true);
+        }
+
+        nemu.run_frame();
+
+        let frame = self.frame;
+        self.frame += 1;
+
+        if state_hash(nemu) != self.movie.hashes[frame] {
+            return Err(NemuError::MovieDesync { frame });
+        }
+        Ok(true)
+    }
+}
+
+fn release_all(nemu: &mut Nemu) {
+    for button in BUTTONS {
+        nemu.set_joypad(button, false, false);
+        nemu.set_joypad(button, false, true);
+    }
+}
+
+fn state_hash(nemu: &mut Nemu) -> u64 {
+    nemu.save_state()
+        .iter()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3))
+}
@@ -0,0 +1,381 @@
+use super::{palette_color, Ppu, ATTR_BANK, ATTR_PALETTE, ATTR_PRIORITY, ATTR_X_FLIP, ATTR_Y_FLIP, DMG_RGB, SCREEN_WIDTH};
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+// the fetcher spends 2 dots on each of tile number, low byte and high byte, then waits to push
+const FETCH_READY: u8 = 6;
+const SPRITE_FETCH_DOTS: u8 = 6;
+const MAX_SPRITES: usize = 10;
+
+#[derive(Clone, Copy, Default)]
+struct Sprite {
+    x: u8,
+    y: u8,
+    tile: u8,
+    attributes: u8,
+    index: u8,
+}
+
+/// Sprite pixel waiting in the OBJ FIFO, color 0 is an empty slot
+#[derive(Clone, Copy, Default)]
+struct ObjPixel {
+    color: u8,
+    attributes: u8,
+    index: u8,
+}
+
+/// Mode 3 state: the background fetcher, both pixel FIFOs and the sprites found by the OAM scan
+#[derive(Default)]
+pub(super) struct PixelFifo {
+    // pixels sent to the LCD on this line, mode 3 ends at 160
+    pub(super) lx: u8,
+    // SCX fine scroll, or the part of a window left of the screen, thrown away before output
+    discard: u8,
+    // the very first fetch of a line is done twice
+    first_fetch: bool,
+    // window reached on this line
+    pub(super) window: bool,
+
+    step: u8,
+    fetch_x: u8,
+    tile: u8,
+    attributes: u8,
+    low: u8,
+    high: u8,
+
+    // the background FIFO is only refilled once empty, so it is a pair of shift registers
+    bg_low: u8,
+    bg_high: u8,
+    bg_attributes: u8,
+    bg_len: u8,
+    obj: [ObjPixel; 8],
+
+    sprites: [Sprite; MAX_SPRITES],
+    sprite_count: u8,
+    fetched: u16,
+    sprite_fetch: Option<u8>,
+    sprite_dots: u8,
+}
+
+impl PixelFifo {
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        for value in [self.lx, self.discard, self.step, self.fetch_x, self.tile, self.attributes, self.low, self.high] {
+            w.u8(value);
+        }
+        w.bool(self.first_fetch);
+        w.bool(self.window);
+
+        for value in [self.bg_low, self.bg_high, self.bg_attributes, self.bg_len] {
+            w.u8(value);
+        }
+        for pixel in &self.obj {
+            w.u8(pixel.color);
+            w.u8(pixel.attributes);
+            w.u8(pixel.index);
+        }
+
+        w.u8(self.sprite_count);
+        for sprite in &self.sprites {
+            for value in [sprite.x, sprite.y, sprite.tile, sprite.attributes, sprite.index] {
+                w.u8(value);
+            }
+        }
+        w.u16(self.fetched);
+        w.u8(self.sprite_fetch.unwrap_or(0xFF));
+        w.u8(self.sprite_dots);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        let values = [
+            &mut self.lx, &mut self.discard, &mut self.step, &mut self.fetch_x,
+            &mut self.tile, &mut self.attributes, &mut self.low, &mut self.high,
+        ];
+        for value in values {
+            *value = r.u8()?;
+        }
+        self.lx = self.lx.min(SCREEN_WIDTH as u8);
+        self.step = self.step.min(FETCH_READY);
+        self.first_fetch = r.bool()?;
+        self.window = r.bool()?;
+
+        for value in [&mut self.bg_low, &mut self.bg_high, &mut self.bg_attributes, &mut self.bg_len] {
+            *value = r.u8()?;
+        }
+        self.bg_len = self.bg_len.min(8);
+        for pixel in self.obj.iter_mut() {
+            pixel.color = r.u8()? & 0x03;
+            pixel.attributes = r.u8()?;
+            pixel.index = r.u8()?;
+        }
+
+        self.sprite_count = r.u8()?.min(MAX_SPRITES as u8);
+        for sprite in self.sprites.iter_mut() {
+            let values = [&mut sprite.x, &mut sprite.y, &mut sprite.tile, &mut sprite.attributes, &mut sprite.index];
+            for value in values {
+                *value = r.u8()?;
+            }
+        }
+        self.fetched = r.u16()?;
+        self.sprite_fetch = Some(r.u8()?).filter(|&i| i < self.sprite_count);
+        self.sprite_dots = r.u8()?.min(SPRITE_FETCH_DOTS);
+        Ok(())
+    }
+}
+
+impl Ppu {
+    /// Start of mode 3, picks the sprites on this line the way the OAM scan does and resets the fetcher
+    pub(super) fn start_pixel_transfer(&mut self) {
+        let height = if (self.lcdc & 0x04) != 0 { 16 } else { 8 };
+        let line = self.ly as u16 + 16;
+
+        let mut sprites = [Sprite::default(); MAX_SPRITES];
+        let mut count = 0;
+        for (index, sprite) in self.oam.chunks_exact(4).enumerate() {
+            let y = sprite[0] as u16;
+
+            if line >= y && line < y + height {
+                sprites[count] = Sprite {
+                    x: sprite[1],
+                    y: sprite[0],
+                    tile: sprite[2],
+                    attributes: sprite[3],
+                    index: index as u8,
+                };
+                count += 1;
+
+                if count == MAX_SPRITES {
+                    break;
+                }
+            }
+        }
+
+        self.wy_triggered |= self.ly == self.wy;
+        self.fifo = PixelFifo {
+            discard: self.scx & 0x07,
+            first_fetch: true,
+            sprites,
+            sprite_count: count as u8,
+            ..PixelFifo::default()
+        };
+    }
+
+    /// Runs one dot of mode 3
+    pub(super) fn pixel_transfer_dot(&mut self) {
+        // a sprite fetch holds the pixel output until the background fetcher has a tile ready,
+        // then takes another 6 dots
+        if let Some(i) = self.fifo.sprite_fetch {
+            if !self.fetcher_ready() {
+                self.fetch_background();
+            }
+            if self.fetcher_ready() {
+                self.fifo.sprite_dots += 1;
+
+                if self.fifo.sprite_dots == SPRITE_FETCH_DOTS {
+                    self.fetch_sprite(i as usize);
+                    self.fifo.sprite_fetch = None;
+                }
+            }
+            return;
+        }
+
+        if self.fifo.discard == 0 {
+            self.check_window();
+
+            if let Some(i) = self.next_sprite() {
+                self.fifo.sprite_fetch = Some(i);
+                self.fifo.sprite_dots = 0;
+                return self.pixel_transfer_dot();
+            }
+        }
+
+        self.shift_pixel();
+        self.fetch_background();
+    }
+
+    #[inline(always)]
+    fn fetcher_ready(&self) -> bool {
+        self.fifo.step == FETCH_READY && self.fifo.bg_len > 0
+    }
+
+    fn check_window(&mut self) {
+        let fifo = &mut self.fifo;
+        if fifo.window || (self.lcdc & 0x20) == 0 || !self.wy_triggered || self.wx > 166 {
+            return;
+        }
+
+        if fifo.lx as u16 + 7 >= self.wx as u16 {
+            fifo.window = true;
+            fifo.bg_len = 0;
+            fifo.step = 0;
+            fifo.fetch_x = 0;
+            fifo.first_fetch = false;
+            // WX below 7 starts the window left of the screen
+            fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+    }
+
+    /// Sprite due at the current pixel, the one with the lowest X first and OAM order among equals
+    fn next_sprite(&self) -> Option<u8> {
+        if (self.lcdc & 0x02) == 0 {
+            return None;
+        }
+
+        let position = self.fifo.lx as u16 + 8;
+        (0..self.fifo.sprite_count)
+            .filter(|&i| (self.fifo.fetched & (1 << i)) == 0)
+            .filter(|&i| (self.fifo.sprites[i as usize].x as u16) <= position)
+            .min_by_key(|&i| self.fifo.sprites[i as usize].x)
+    }
+
+    fn fetch_background(&mut self) {
+        match self.fifo.step {
+            1 => self.fetch_tile(),
+            3 => self.fifo.low = self.fetch_tile_data(0),
+            5 => self.fifo.high = self.fetch_tile_data(1),
+            _ => {}
+        }
+
+        let fifo = &mut self.fifo;
+        if fifo.step < FETCH_READY {
+            fifo.step += 1;
+        }
+
+        if fifo.step == FETCH_READY && fifo.bg_len == 0 {
+            fifo.step = 0;
+
+            if fifo.first_fetch {
+                fifo.first_fetch = false;
+                return;
+            }
+
+            let x_flip = (fifo.attributes & ATTR_X_FLIP) != 0;
+            fifo.bg_low = if x_flip { fifo.low.reverse_bits() } else { fifo.low };
+            fifo.bg_high = if x_flip { fifo.high.reverse_bits() } else { fifo.high };
+            fifo.bg_attributes = fifo.attributes;
+            fifo.bg_len = 8;
+            fifo.fetch_x = fifo.fetch_x.wrapping_add(1);
+        }
+    }
+
+    /// Tile map row and column for the fetcher, SCX and SCY are read on every fetch
+    #[inline(always)]
+    fn fetch_position(&self) -> (usize, usize) {
+        if self.fifo.window {
+            (self.fifo.fetch_x as usize & 31, self.wline_counter as usize)
+        } else {
+            (((self.scx >> 3) as usize + self.fifo.fetch_x as usize) & 31, self.ly.wrapping_add(self.scy) as usize)
+        }
+    }
+
+    fn fetch_tile(&mut self) {
+        let map_bit = if self.fifo.window { 0x40 } else { 0x08 };
+        let tilemap_base = if (self.lcdc & map_bit) != 0 { 0x1C00 } else { 0x1800 };
+
+        let (x, y) = self.fetch_position();
+        let map_index = tilemap_base + (y / 8) * 32 + x;
+        self.fifo.tile = self.vram[map_index];
+        self.fifo.attributes = if self.cgb { self.vram[0x2000 + map_index] } else { 0 };
+    }
+
+    fn fetch_tile_data(&self, plane: usize) -> u8 {
+        let (_, y) = self.fetch_position();
+        let attributes = self.fifo.attributes;
+
+        let tile_addr = if (self.lcdc & 0x10) != 0 {
+            self.fifo.tile as usize * 16
+        } else {
+            0x1000_i16.wrapping_add((self.fifo.tile as i8 as i16) * 16) as usize
+        };
+        let bank = if (attributes & ATTR_BANK) != 0 { 0x2000 } else { 0 };
+        let tile_line = if (attributes & ATTR_Y_FLIP) != 0 { 7 - (y % 8) } else { y % 8 };
+
+        self.vram[bank + tile_addr + tile_line * 2 + plane]
+    }
+
+    fn fetch_sprite(&mut self, i: usize) {
+        let sprite = self.fifo.sprites[i];
+        self.fifo.fetched |= 1 << i;
+
+        let height = if (self.lcdc & 0x04) != 0 { 16 } else { 8 };
+        let y_offset = (self.ly.wrapping_add(16).wrapping_sub(sprite.y)) & (height - 1);
+        let tile_line = if (sprite.attributes & ATTR_Y_FLIP) != 0 { height - 1 - y_offset } else { y_offset } as usize;
+        let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile } as usize;
+        let bank = if self.cgb && (sprite.attributes & ATTR_BANK) != 0 { 0x2000 } else { 0 };
+
+        let line_addr = bank + tile * 16 + tile_line * 2;
+        let (low, high) = (self.vram[line_addr], self.vram[line_addr + 1]);
+        let x_flip = (sprite.attributes & ATTR_X_FLIP) != 0;
+
+        // sprites hanging off the left edge lose their first pixels
+        let skip = (self.fifo.lx as usize + 8).saturating_sub(sprite.x as usize).min(8);
+        for x in skip..8 {
+            let bit = if x_flip { x } else { 7 - x };
+            let color = (((high >> bit) & 0x01) << 1) | ((low >> bit) & 0x01);
+            let slot = &mut self.fifo.obj[x - skip];
+
+            // DMG keeps whatever was fetched first, CGB lets the lower OAM index win
+            if color != 0 && (slot.color == 0 || (self.cgb && sprite.index < slot.index)) {
+                *slot = ObjPixel { color, attributes: sprite.attributes, index: sprite.index };
+            }
+        }
+    }
+
+    /// Shifts one pixel out of both FIFOs, mixes them and puts it on the LCD
+    fn shift_pixel(&mut self) {
+        let fifo = &mut self.fifo;
+        if fifo.bg_len == 0 {
+            return;
+        }
+
+        let bg_color = ((fifo.bg_high >> 6) & 0x02) | (fifo.bg_low >> 7);
+        let bg_attributes = fifo.bg_attributes;
+        fifo.bg_low <<= 1;
+        fifo.bg_high <<= 1;
+        fifo.bg_len -= 1;
+
+        if fifo.discard > 0 {
+            fifo.discard -= 1;
+            return;
+        }
+
+        let obj = fifo.obj[0];
+        fifo.obj.copy_within(1.., 0);
+        fifo.obj[7] = ObjPixel::default();
+
+        let x = fifo.lx as usize;
+        fifo.lx += 1;
+
+        // on CGB LCDC bit 0 does not hide the background, it takes away its priority over sprites
+        let bg_enabled = self.cgb || (self.lcdc & 0x01) != 0;
+        let bg_color = if bg_enabled { bg_color } else { 0 };
+        let obj_visible = obj.color != 0 && (self.lcdc & 0x02) != 0;
+
+        let (shade, color) = if self.cgb {
+            let bg_on_top = bg_color != 0
+                && (self.lcdc & 0x01) != 0
+                && ((obj.attributes | bg_attributes) & ATTR_PRIORITY) != 0;
+
+            if obj_visible && !bg_on_top {
+                (obj.color, palette_color(&self.obj_palettes, obj.attributes & ATTR_PALETTE, obj.color))
+            } else {
+                (bg_color, palette_color(&self.bg_palettes, bg_attributes & ATTR_PALETTE, bg_color))
+            }
+        } else {
+            let bg_on_top = bg_color != 0 && (obj.attributes & ATTR_PRIORITY) != 0;
+
+            let shade = if obj_visible && !bg_on_top {
+                let palette = if (obj.attributes & 0x10) != 0 { self.obp1 } else { self.obp0 };
+                (palette >> (obj.color * 2)) & 0x03
+            } else if bg_enabled {
+                (self.bgp >> (bg_color * 2)) & 0x03
+            } else {
+                0
+            };
+            (shade, DMG_RGB[shade as usize])
+        };
+
+        let index = self.ly as usize * SCREEN_WIDTH + x;
+        self.framebuffer[index] = shade;
+        self.rgb_framebuffer[index] = color;
+    }
+}
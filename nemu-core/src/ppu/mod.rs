@@ -1,10 +1,33 @@
+mod fifo;
 mod utils;
 
+use crate::NemuError;
 use crate::interrupts::{INT_LCDSTAT, INT_VBLANK};
+use crate::state::{StateReader, StateWriter};
+use fifo::PixelFifo;
 use utils::{Mode, STAT_HBLANK_IRQ, STAT_LYC_EQ_LY, STAT_LYC_IRQ, STAT_OAM_IRQ, STAT_VBLANK_IRQ};
 
 const SCREEN_WIDTH: usize = 160;
 const SCREEN_HEIGHT: usize = 144;
+const OAM_ROWS: usize = 20;
+
+// DMG shades as 15-bit colors for the RGB framebuffer
+const DMG_RGB: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+// CGB tile map attributes, sprites use the same bits for flips, bank and priority
+const ATTR_PALETTE: u8 = 0b0000_0111;
+const ATTR_BANK: u8 = 0b0000_1000;
+const ATTR_X_FLIP: u8 = 0b0010_0000;
+const ATTR_Y_FLIP: u8 = 0b0100_0000;
+const ATTR_PRIORITY: u8 = 0b1000_0000;
+
+/// How the CPU touched 0xFE00-0xFEFF while the OAM scan was running, see `Ppu::corrupt_oam`
+pub(crate) enum OamCorruption {
+    Read,
+    Write,
+    // a read while the IDU changes the same address, as in POP or LD A, (HL+)
+    ReadIdu,
+}
 
 pub struct Ppu {
     lcdc: u8,
@@ -21,10 +44,30 @@ pub struct Ppu {
     dots: u16,
     mode: Mode,
     wline_counter: u8,
-    vram: [u8; 0x2000],
+    // WY matched LY at some point this frame
+    wy_triggered: bool,
+    // STAT interrupt line, the interrupt is requested when it goes high
+    stat_line: bool,
+    write_irq: u8,
+    // CPU access to VRAM and OAM is blocked while the PPU uses them, a setting that survives resets
+    lockouts: bool,
+    fifo: PixelFifo,
+    vram: [u8; 0x4000], // bank 1 only exists on CGB
     oam: [u8; 0xA0],
 
+    // CGB
+    cgb: bool,
+    vram_bank: u8,
+    bcps: u8,
+    ocps: u8,
+    bg_palettes: [u8; 64],
+    obj_palettes: [u8; 64],
+    pub(crate) hblank_started: bool,
+
+    // shades 0-3 on DMG, raw color numbers in CGB mode where they only make sense with the palettes
     pub framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // 15-bit colors, red in bits 0-4, green in 5-9 and blue in 10-14
+    pub rgb_framebuffer: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
     pub frame_ready: bool,
 }
 
@@ -45,13 +88,31 @@ impl Ppu {
             dots: 0,
             mode: Mode::OAMSearch,
             wline_counter: 0,
-            vram: [0; 0x2000],
+            wy_triggered: false,
+            stat_line: false,
+            write_irq: 0,
+            lockouts: true,
+            fifo: PixelFifo::default(),
+            vram: [0; 0x4000],
             oam: [0; 0xA0],
+            cgb: false,
+            vram_bank: 0,
+            bcps: 0,
+            ocps: 0,
+            bg_palettes: [0xFF; 64],
+            obj_palettes: [0; 64],
+            hblank_started: false,
             framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            rgb_framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
             frame_ready: false,
         }
     }
 
+    /// Switching modes is only done while resetting, the model stays across resets
+    pub(crate) fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
     pub(crate) fn reset(&mut self) {
         self.lcdc = 0;
         self.stat = 0;
@@ -59,17 +120,137 @@ impl Ppu {
         self.scx = 0;
         self.ly = 0;
         self.lyc = 0;
+        self.bgp = 0;
+        self.obp0 = 0;
+        self.obp1 = 0;
         self.wy = 0;
         self.wx = 0;
         self.dots = 0;
         self.mode = Mode::OAMSearch;
         self.wline_counter = 0;
-        self.vram = [0; 0x2000];
+        self.wy_triggered = false;
+        self.stat_line = false;
+        self.write_irq = 0;
+        self.fifo = PixelFifo::default();
+        self.vram = [0; 0x4000];
         self.oam = [0; 0xA0];
+        self.vram_bank = 0;
+        self.bcps = 0;
+        self.ocps = 0;
+        // the CGB boot ROM leaves every background palette white
+        self.bg_palettes = [0xFF; 64];
+        self.obj_palettes = [0; 64];
+        self.hblank_started = false;
         self.framebuffer = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        self.rgb_framebuffer = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
         self.frame_ready = false;
     }
 
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        let registers = [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc,
+            self.bgp, self.obp0, self.obp1, self.wy, self.wx,
+        ];
+        for value in registers {
+            w.u8(value);
+        }
+
+        w.u16(self.dots);
+        w.u8(self.mode as u8);
+        w.u8(self.wline_counter);
+        w.bytes(&self.vram);
+        w.bytes(&self.oam);
+        w.bytes(&self.framebuffer);
+        w.bool(self.frame_ready);
+
+        w.bool(self.cgb);
+        w.u8(self.vram_bank);
+        w.u8(self.bcps);
+        w.u8(self.ocps);
+        w.bytes(&self.bg_palettes);
+        w.bytes(&self.obj_palettes);
+        w.bool(self.hblank_started);
+        for &color in &self.rgb_framebuffer {
+            w.u16(color);
+        }
+
+        w.bool(self.wy_triggered);
+        self.fifo.save_state(w);
+        w.bool(self.stat_line);
+        w.bool(self.lockouts);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        let registers = [
+            &mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly, &mut self.lyc,
+            &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx,
+        ];
+        for value in registers {
+            *value = r.u8()?;
+        }
+
+        self.dots = r.u16()?;
+        self.mode = match r.u8()? {
+            0x00 => Mode::HBlank,
+            0x01 => Mode::VBlank,
+            0x02 => Mode::OAMSearch,
+            0x03 => Mode::PixelTransfer,
+            mode => return Err(NemuError::InvalidState(format!("Invalid PPU mode: {}", mode))),
+        };
+        self.wline_counter = r.u8()?;
+
+        // version 1 states predate CGB support and only have VRAM bank 0
+        if r.version() < 2 {
+            r.bytes(&mut self.vram[..0x2000])?;
+            self.vram[0x2000..].fill(0);
+        } else {
+            r.bytes(&mut self.vram)?;
+        }
+
+        r.bytes(&mut self.oam)?;
+        r.bytes(&mut self.framebuffer)?;
+        self.frame_ready = r.bool()?;
+
+        if r.version() < 2 {
+            self.cgb = false;
+            self.vram_bank = 0;
+            for (rgb, &shade) in self.rgb_framebuffer.iter_mut().zip(self.framebuffer.iter()) {
+                *rgb = DMG_RGB[shade as usize & 0x03];
+            }
+        } else {
+            self.cgb = r.bool()?;
+            self.vram_bank = r.u8()? & 0x01;
+            self.bcps = r.u8()?;
+            self.ocps = r.u8()?;
+            r.bytes(&mut self.bg_palettes)?;
+            r.bytes(&mut self.obj_palettes)?;
+            self.hblank_started = r.bool()?;
+            for color in self.rgb_framebuffer.iter_mut() {
+                *color = r.u16()?;
+            }
+        }
+
+        // version 4 states have the pixel FIFO, older ones rendered whole lines and start this
+        // line's mode 3 over
+        if r.version() < 4 {
+            self.wy_triggered = self.ly >= self.wy;
+            self.fifo = PixelFifo::default();
+            if self.mode == Mode::PixelTransfer {
+                self.start_pixel_transfer();
+            }
+        } else {
+            self.wy_triggered = r.bool()?;
+            self.fifo.load_state(r)?;
+        }
+
+        // version 5 added the STAT line, before that it follows the current sources
+        self.stat_line = if r.version() < 5 { self.stat_sources(self.stat) } else { r.bool()? };
+        // version 8 added the lockouts switch, older states were always saved with it on
+        self.lockouts = r.version() < 8 || r.bool()?;
+        self.write_irq = 0;
+        Ok(())
+    }
+
     pub(crate) fn update(&mut self, cycles: u8) -> u8 {
         if (self.lcdc & 0x80) == 0 {
             // LCD is off
@@ -77,33 +258,130 @@ impl Ppu {
         }
 
         let mut irq_mask: u8 = 0;
-        self.dots += (cycles * 4) as u16;
+        let mut dots = cycles as u16 * 4;
+
+        while dots > 0 {
+            // mode 3 runs dot by dot, it lasts until the FIFO has pushed all 160 pixels
+            if self.mode == Mode::PixelTransfer {
+                self.pixel_transfer_dot();
+                self.dots += 1;
+                dots -= 1;
 
-        loop {
-            let threshold = match self.mode {
+                if self.fifo.lx as usize == SCREEN_WIDTH {
+                    irq_mask |= self.switch_modes();
+                }
+                continue;
+            }
+
+            let threshold: u16 = match self.mode {
                 Mode::OAMSearch => 80,
-                Mode::PixelTransfer => 252,
-                Mode::HBlank => 456,
-                Mode::VBlank => 456, // each line in vblank is 456 dots
+                _ => 456, // HBlank and every VBlank line end after 456 dots
             };
 
-            if self.dots < threshold {
-                break;
-            }
+            let step = dots.min(threshold.saturating_sub(self.dots));
+            self.dots += step;
+            dots -= step;
 
-            irq_mask |= self.switch_modes();
-            while self.dots >= 456 {
-                self.dots -= 456;
+            if self.dots >= threshold {
+                irq_mask |= self.switch_modes();
+                if self.dots >= 456 {
+                    self.dots -= 456;
+                }
             }
         }
 
         irq_mask
     }
 
+    /// Turning the lockouts off lets the CPU touch VRAM and OAM in any mode, for debugging
+    pub(crate) fn set_lockouts(&mut self, enabled: bool) {
+        self.lockouts = enabled;
+    }
+
+    pub(crate) fn lockouts(&self) -> bool {
+        self.lockouts
+    }
+
+    /// VRAM is in use during mode 3
+    #[inline(always)]
+    fn vram_locked(&self) -> bool {
+        self.lockouts && (self.lcdc & 0x80) != 0 && self.mode == Mode::PixelTransfer
+    }
+
+    /// OAM is in use during the OAM scan and mode 3
+    #[inline(always)]
+    fn oam_locked(&self) -> bool {
+        self.lockouts && (self.lcdc & 0x80) != 0 && matches!(self.mode, Mode::OAMSearch | Mode::PixelTransfer)
+    }
+
+    /// DMG OAM bug, the OAM row the scan is reading gets mixed with the row before it. The
+    /// patterns are the ones documented in Pan Docs, the first row is never affected.
+    pub(crate) fn corrupt_oam(&mut self, corruption: OamCorruption) {
+        if self.cgb || (self.lcdc & 0x80) == 0 || self.mode != Mode::OAMSearch {
+            return;
+        }
+
+        // the scan reads one 8 byte row every M-cycle
+        let row = self.dots as usize / 4;
+        if row == 0 || row >= OAM_ROWS {
+            return;
+        }
+
+        match corruption {
+            OamCorruption::Write => {
+                let (a, b, c) = (self.oam_word(row, 0), self.oam_word(row - 1, 0), self.oam_word(row - 1, 2));
+                self.set_oam_word(row, ((a ^ c) & (b ^ c)) ^ c);
+            }
+            OamCorruption::Read | OamCorruption::ReadIdu => {
+                // the read and the IDU write together also smear the row before over its neighbours,
+                // except around the first four rows and the last one
+                if matches!(corruption, OamCorruption::ReadIdu) && (4..OAM_ROWS - 1).contains(&row) {
+                    let (a, b) = (self.oam_word(row - 2, 0), self.oam_word(row - 1, 0));
+                    let (c, d) = (self.oam_word(row, 0), self.oam_word(row - 1, 2));
+                    self.set_oam_word(row - 1, (b & (a | c | d)) | (a & c & d));
+
+                    self.oam.copy_within((row - 1) * 8..row * 8, row * 8);
+                    self.oam.copy_within((row - 1) * 8..row * 8, (row - 2) * 8);
+                }
+
+                let (a, b, c) = (self.oam_word(row, 0), self.oam_word(row - 1, 0), self.oam_word(row - 1, 2));
+                self.set_oam_word(row, b | (a & c));
+            }
+        }
+
+        // the rest of the row comes from the row before it
+        self.oam.copy_within((row - 1) * 8 + 2..row * 8, row * 8 + 2);
+    }
+
+    #[inline(always)]
+    fn oam_word(&self, row: usize, word: usize) -> u16 {
+        let index = row * 8 + word * 2;
+        u16::from_le_bytes([self.oam[index], self.oam[index + 1]])
+    }
+
+    /// Replaces the first word of an OAM row
+    #[inline(always)]
+    fn set_oam_word(&mut self, row: usize, value: u16) {
+        self.oam[row * 8..row * 8 + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// OAM DMA writes straight into OAM, whatever mode the PPU is in
+    #[inline(always)]
+    pub(crate) fn write_oam_dma(&mut self, index: u8, value: u8) {
+        self.oam[index as usize] = value;
+    }
+
+    #[inline(always)]
+    fn vram_index(&self, addr: u16) -> usize {
+        self.vram_bank as usize * 0x2000 + (addr - 0x8000) as usize
+    }
+
     #[inline(always)]
     pub(crate) fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x8000..=0x9FFF => unsafe { *self.vram.get_unchecked((addr - 0x8000) as usize) },
+            0x8000..=0x9FFF if self.vram_locked() => 0xFF,
+            0x8000..=0x9FFF => unsafe { *self.vram.get_unchecked(self.vram_index(addr)) },
+            0xFE00..=0xFE9F if self.oam_locked() => 0xFF,
             0xFE00..=0xFE9F => unsafe { *self.oam.get_unchecked((addr - 0xFE00) as usize) },
             0xFF40 => self.lcdc,
             0xFF41 => self.stat,
@@ -116,6 +394,11 @@ impl Ppu {
             0xFF49 => self.obp1,
             0xFF4A => self.wy,
             0xFF4B => self.wx,
+            0xFF4F => 0xFE | self.vram_bank,
+            0xFF68 => 0x40 | self.bcps,
+            0xFF69 => self.bg_palettes[(self.bcps & 0x3F) as usize],
+            0xFF6A => 0x40 | self.ocps,
+            0xFF6B => self.obj_palettes[(self.ocps & 0x3F) as usize],
 
             _ => panic!("PPU read from invalid address: {:#06X}", addr),
         }
@@ -124,19 +407,29 @@ impl Ppu {
     #[inline(always)]
     pub(crate) fn write(&mut self, addr: u16, value: u8) {
         match addr {
-            0x8000..=0x9FFF => unsafe { *self.vram.get_unchecked_mut((addr - 0x8000) as usize) = value },
+            0x8000..=0x9FFF if self.vram_locked() => (),
+            0x8000..=0x9FFF => {
+                let index = self.vram_index(addr);
+                unsafe { *self.vram.get_unchecked_mut(index) = value }
+            }
+            0xFE00..=0xFE9F if self.oam_locked() => (),
             0xFE00..=0xFE9F => unsafe { *self.oam.get_unchecked_mut((addr - 0xFE00) as usize) = value },
             0xFF40 => self.set_lcdc(value),
-            0xFF41 => self.stat = (value & 0xF8) | (self.stat & 0x07),
+            0xFF41 => self.write_stat(value),
             0xFF42 => self.scy = value,
             0xFF43 => self.scx = value,
             0xFF44 => (), // LY is read-only
-            0xFF45 => self.lyc = value,
+            0xFF45 => self.write_lyc(value),
             0xFF47 => self.bgp = value,
             0xFF48 => self.obp0 = value,
             0xFF49 => self.obp1 = value,
             0xFF4A => self.wy = value,
             0xFF4B => self.wx = value,
+            0xFF4F => self.vram_bank = value & 0x01,
+            0xFF68 => self.bcps = value & 0xBF,
+            0xFF69 => write_palette(&mut self.bg_palettes, &mut self.bcps, value),
+            0xFF6A => self.ocps = value & 0xBF,
+            0xFF6B => write_palette(&mut self.obj_palettes, &mut self.ocps, value),
 
             _ => panic!("PPU write to invalid address: {:#06X}", addr),
         }
@@ -149,61 +442,98 @@ impl Ppu {
         match self.mode {
             Mode::OAMSearch => {
                 self.mode = Mode::PixelTransfer;
+                self.start_pixel_transfer();
             }
             Mode::PixelTransfer => {
-                self.draw_scanline();
-                self.mode = Mode::HBlank;
-
-                if (self.stat & STAT_HBLANK_IRQ) != 0 {
-                    irq_mask |= INT_LCDSTAT;
+                if self.fifo.window {
+                    self.wline_counter = self.wline_counter.wrapping_add(1);
                 }
+                self.mode = Mode::HBlank;
+                self.hblank_started = true;
             }
             Mode::HBlank => {
                 self.ly += 1;
-
-                if self.compare_lyc() {
-                    irq_mask |= INT_LCDSTAT;
-                }
+                self.compare_lyc();
 
                 if self.ly < 144 {
                     self.mode = Mode::OAMSearch;
-
-                    if (self.stat & STAT_OAM_IRQ) != 0 {
-                        irq_mask |= INT_LCDSTAT;
-                    }
                 } else {
                     self.mode = Mode::VBlank;
                     irq_mask |= INT_VBLANK;
-
-                    if (self.stat & STAT_VBLANK_IRQ) != 0 {
-                        irq_mask |= INT_LCDSTAT;
-                    }
-
                     self.frame_ready = true;
                 }
             }
             Mode::VBlank => {
                 self.ly += 1;
 
-                if self.compare_lyc() {
-                    irq_mask |= INT_LCDSTAT;
-                }
-
                 if self.ly > 153 {
                     self.ly = 0;
                     self.mode = Mode::OAMSearch;
                     self.wline_counter = 0;
-
-                    if (self.stat & STAT_OAM_IRQ) != 0 {
-                        irq_mask |= INT_LCDSTAT;
-                    }
+                    self.wy_triggered = false;
                 }
+
+                self.compare_lyc();
             }
         }
 
         self.stat = (self.stat & 0xFC) | (self.mode as u8);
 
-        irq_mask
+        irq_mask | self.update_stat_line(self.stat)
+    }
+
+    /// Level of the STAT interrupt line with the sources in `enables`, the OR of every active one
+    #[inline(always)]
+    fn stat_sources(&self, enables: u8) -> bool {
+        if (self.lcdc & 0x80) == 0 {
+            return false;
+        }
+
+        let mode_source = match self.mode {
+            Mode::HBlank => STAT_HBLANK_IRQ,
+            Mode::VBlank => STAT_VBLANK_IRQ,
+            Mode::OAMSearch => STAT_OAM_IRQ,
+            Mode::PixelTransfer => 0,
+        };
+        let lyc_source = if (self.stat & STAT_LYC_EQ_LY) != 0 { STAT_LYC_IRQ } else { 0 };
+
+        (enables & (mode_source | lyc_source)) != 0
+    }
+
+    /// Only a rising edge of the STAT line requests an interrupt, a source becoming active while
+    /// another one already holds the line high is blocked
+    #[inline(always)]
+    fn update_stat_line(&mut self, enables: u8) -> u8 {
+        let line = self.stat_sources(enables);
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+
+        if rising { INT_LCDSTAT } else { 0 }
+    }
+
+    /// STAT interrupt raised by the last write to LCDC, STAT or LYC
+    pub(crate) fn take_write_irq(&mut self) -> u8 {
+        std::mem::take(&mut self.write_irq)
+    }
+
+    fn write_stat(&mut self, value: u8) {
+        // DMG briefly sees every source enabled while STAT is written, so HBlank, VBlank or a
+        // LYC match raises the line no matter what gets written
+        if !self.cgb {
+            self.write_irq |= self.update_stat_line(STAT_HBLANK_IRQ | STAT_VBLANK_IRQ | STAT_LYC_IRQ);
+        }
+
+        self.stat = (value & 0xF8) | (self.stat & 0x07);
+        self.write_irq |= self.update_stat_line(self.stat);
+    }
+
+    fn write_lyc(&mut self, value: u8) {
+        self.lyc = value;
+
+        if (self.lcdc & 0x80) != 0 {
+            self.compare_lyc();
+            self.write_irq |= self.update_stat_line(self.stat);
+        }
     }
 
     #[inline(always)]
@@ -224,179 +554,49 @@ impl Ppu {
             self.dots = 0;
             self.mode = Mode::HBlank;
             self.wline_counter = 0;
+            self.wy_triggered = false;
             self.stat = (self.stat & 0xFC) | (Mode::HBlank as u8);
+            self.stat_line = false;
         }
 
+        self.lcdc = value;
+
         if new_lcd_enabled && !old_lcd_enabled {
             self.ly = 0;
             self.dots = 0;
             self.mode = Mode::OAMSearch;
             self.stat = (self.stat & 0xFC) | (Mode::OAMSearch as u8);
             self.compare_lyc();
+            self.write_irq |= self.update_stat_line(self.stat);
         }
-
-        self.lcdc = value;
     }
 
     #[inline(always)]
-    fn compare_lyc(&mut self) -> bool {
+    fn compare_lyc(&mut self) {
         if self.ly == self.lyc {
             self.stat |= STAT_LYC_EQ_LY;
-            (self.stat & STAT_LYC_IRQ) != 0
         } else {
             self.stat &= !STAT_LYC_EQ_LY;
-            false
         }
     }
 
-    fn draw_scanline(&mut self) {
-        let start = (self.ly as usize) * SCREEN_WIDTH;
-        let end = start + SCREEN_WIDTH;
-        let scanline = &mut self.framebuffer[start..end];
-        let mut bg_priority: [bool; SCREEN_WIDTH] = [false; SCREEN_WIDTH];
-
-        // Draw background
-        if (self.lcdc & 0x01) != 0 {
-            let y_pos = self.ly.wrapping_add(self.scy);
-            let tile_row = (y_pos / 8) as usize;
-            let tile_line = (y_pos % 8) as usize;
-
-            let tilemap_base = if (self.lcdc & 0x08) != 0 { 0x1C00 } else { 0x1800 };
-            let tile_data_unsigned: bool = (self.lcdc & 0x10) != 0;
-
-            for x in 0..SCREEN_WIDTH {
-                let x_pos = x.wrapping_add(self.scx as usize) % 256;
-                let tile_num = self.vram[tilemap_base + (tile_row * 32) + (x_pos / 8)];
-
-                let tile_addr = if tile_data_unsigned {
-                    tile_num as usize * 16
-                } else {
-                    0x1000_i16.wrapping_add((tile_num as i8 as i16) * 16) as usize
-                };
-
-                let line_addr = tile_addr + (tile_line * 2);
-                let byte1 = self.vram[line_addr];
-                let byte2 = self.vram[line_addr + 1];
-
-                let bit_index = 7 - (x_pos % 8);
-                let color_bit0 = (byte1 >> bit_index) & 0x01;
-                let color_bit1 = (byte2 >> bit_index) & 0x01;
-                let color_raw = (color_bit1 << 1) | color_bit0;
-                let color = (self.bgp >> (color_raw * 2)) & 0x03;
-
-                bg_priority[x] = color_raw != 0;
-                scanline[x] = color;
-            }
-        }
-
-        // Draw window
-        if (self.lcdc & 0x20) != 0 && self.wy <= self.ly && self.wx < 167 {
-            let tile_row = (self.wline_counter / 8) as usize;
-            let tile_line = (self.wline_counter % 8) as usize;
-
-            let tilemap_base = if (self.lcdc & 0x40) != 0 { 0x1C00 } else { 0x1800 };
-            let loop_start = (self.wx as usize).saturating_sub(7);
-
-            for x in loop_start..SCREEN_WIDTH {
-                let window_x = x + 7 - (self.wx as usize);
-                let tile_num = self.vram[tilemap_base + (tile_row * 32) + (window_x / 8)];
-
-                let tile_addr = if (self.lcdc & 0x10) != 0 {
-                    tile_num as usize * 16
-                } else {
-                    0x1000_i16.wrapping_add((tile_num as i8 as i16) * 16) as usize
-                };
-
-                let line_addr = tile_addr + (tile_line * 2);
-                let byte1 = self.vram[line_addr];
-                let byte2 = self.vram[line_addr + 1];
-
-                let bit_index = 7 - (window_x % 8);
-                let color_bit0 = (byte1 >> bit_index) & 0x01;
-                let color_bit1 = (byte2 >> bit_index) & 0x01;
-                let color_raw = (color_bit1 << 1) | color_bit0;
-                let color = (self.bgp >> (color_raw * 2)) & 0x03;
-
-                bg_priority[x] = color_raw != 0;
-                scanline[x] = color;
-            }
-
-            self.wline_counter = self.wline_counter.wrapping_add(1);
-        }
-
-        // Draw sprites
-        if (self.lcdc & 0x02) != 0 {
-            let sprite_height = if (self.lcdc & 0x04) != 0 { 16 } else { 8 };
-            let ly = self.ly as i16;
-
-            let mut sprites = [(0i16, 0i16, 0u8, 0u8); 10];
-            let mut sprite_count = 0;
-
-            for sprite in self.oam.chunks_exact(4) {
-                let sprite_y = (sprite[0] as i16) - 16;
-                let sprite_x = (sprite[1] as i16) - 8;
-
-                if ly >= sprite_y && ly < sprite_y + (sprite_height as i16) {
-                    let mut i = sprite_count;
-
-                    while i > 0 && sprites[i - 1].0 <= sprite_x {
-                        sprites[i] = sprites[i - 1];
-                        i -= 1;
-                    }
-
-                    sprites[i] = (sprite_x, sprite_y, sprite[2], sprite[3]);
-                    sprite_count += 1;
-
-                    if sprite_count >= 10 {
-                        break;
-                    }
-                }
-            }
-
-            for &(sprite_x, sprite_y, tile_num, attributes) in &sprites[..sprite_count] {
-                let y_offset = (ly - sprite_y) as u8;
-                let y_flip = (attributes & 0x40) != 0;
-                let x_flip = (attributes & 0x20) != 0;
-                let palette = if (attributes & 0x10) != 0 { self.obp1 } else { self.obp0 };
-
-                let tile_line = if y_flip {
-                    sprite_height - 1 - y_offset
-                } else {
-                    y_offset
-                } as usize;
-
-                let tile_addr = if sprite_height == 16 {
-                    (tile_num & 0xFE) as usize * 16
-                } else {
-                    tile_num as usize * 16
-                };
-
-                let line_addr = tile_addr + (tile_line * 2);
-                let byte1 = self.vram[line_addr];
-                let byte2 = self.vram[line_addr + 1];
-
-                for x in 0..8 {
-                    let pixel_x = sprite_x + (x as i16);
-
-                    if pixel_x < 0 || (pixel_x as usize) >= SCREEN_WIDTH ||
-                        bg_priority[pixel_x as usize] && (attributes & 0x80) != 0
-                    {
-                        continue;
-                    }
-
-                    let bit_index = if x_flip { x } else { 7 - x };
-                    let color_bit0 = (byte1 >> bit_index) & 0x01;
-                    let color_bit1 = (byte2 >> bit_index) & 0x01;
-                    let color_raw = (color_bit1 << 1) | color_bit0;
+    /// The 4KB of tile data the background is using, which is what SGB VRAM transfers copy
+    pub(crate) fn bg_tile_data(&self) -> &[u8] {
+        if (self.lcdc & 0x10) != 0 { &self.vram[..0x1000] } else { &self.vram[0x800..0x1800] }
+    }
+}
 
-                    if color_raw == 0 {
-                        continue;
-                    }
+/// BCPD/OCPD write, bit 7 of the index register makes the index advance after every write
+fn write_palette(palettes: &mut [u8; 64], index: &mut u8, value: u8) {
+    palettes[(*index & 0x3F) as usize] = value;
 
-                    let color = (palette >> (color_raw * 2)) & 0x03;
-                    scanline[pixel_x as usize] = color;
-                }
-            }
-        }
+    if (*index & 0x80) != 0 {
+        *index = 0x80 | ((*index + 1) & 0x3F);
     }
 }
+
+#[inline(always)]
+fn palette_color(palettes: &[u8; 64], palette: u8, color: u8) -> u16 {
+    let index = (palette as usize * 4 + color as usize) * 2;
+    u16::from_le_bytes([palettes[index], palettes[index + 1]]) & 0x7FFF
+}
@@ -0,0 +1,202 @@
+use std::collections::VecDeque;
+
+use crate::Nemu;
+
+/// Snapshot older than the newest one, stored as the XOR against the snapshot that followed it.
+/// Consecutive states differ in a few KB at most, so the zero runs make them small.
+struct Delta {
+    frame: u64,
+    data: Vec<u8>,
+}
+
+/// Rewind history made of periodic save states. The newest snapshot is kept in full and every
+/// older one as a delta against its successor, so the oldest can be dropped without decoding
+/// anything once the memory budget runs out.
+pub struct Rewind {
+    interval: u32,
+    budget: usize,
+    frame: u64,
+
+    newest: Option<(u64, Vec<u8>)>,
+    history: VecDeque<Delta>,
+    history_size: usize,
+}
+
+impl Rewind {
+    /// Snapshots every `interval` frames, keeping at most `budget` bytes of history
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            budget,
+            frame: 0,
+            newest: None,
+            history: VecDeque::new(),
+            history_size: 0,
+        }
+    }
+
+    /// Drops the whole history, needed whenever a different cartridge is loaded or the system resets
+    pub fn clear(&mut self) {
+        self.frame = 0;
+        self.newest = None;
+        self.history.clear();
+        self.history_size = 0;
+    }
+
+    /// Frames counted since the history was cleared, goes down again when rewinding
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Number of snapshots currently held
+    pub fn len(&self) -> usize {
+        self.history.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Bytes taken by the snapshots, never more than the budget unless a single full state is bigger
+    pub fn memory_used(&self) -> usize {
+        self.history_size + self.newest.as_ref().map_or(0, |(_, state)| state.len())
+    }
+
+    /// Call once for every frame the emulator completes, takes a snapshot every `interval` frames
+    pub fn push_frame(&mut self, nemu: &mut Nemu) {
+        self.frame += 1;
+        if self.frame.is_multiple_of(self.interval as u64) {
+            self.capture(nemu.save_state());
+        }
+    }
+
+    fn capture(&mut self, state: Vec<u8>) {
+        if let Some((frame, previous)) = self.newest.take() {
+            // a state of another size comes from another cartridge, nothing older can be restored
+            if previous.len() == state.len() {
+                let data = encode_delta(&previous, &state);
+                self.history_size += data.len();
+                self.history.push_back(Delta { frame, data });
+            } else {
+                self.history.clear();
+                self.history_size = 0;
+            }
+        }
+
+        self.newest = Some((self.frame, state));
+
+        while self.memory_used() > self.budget {
+            let Some(oldest) = self.history.pop_front() else { break };
+            self.history_size -= oldest.data.len();
+        }
+    }
+
+    /// Goes back one frame, restoring the framebuffer along with the rest of the machine. With an
+    /// interval above 1 the closest older snapshot is loaded and the frames after it are run again
+    /// with the joypad as it is now. Returns false once the history is exhausted.
+    pub fn step_back(&mut self, nemu: &mut Nemu) -> bool {
+        if self.frame == 0 {
+            return false;
+        }
+        let target = self.frame - 1;
+
+        while let Some((frame, _)) = &self.newest {
+            if *frame <= target {
+                break;
+            }
+            if !self.pop_newest() {
+                // nothing older, the oldest snapshot stays so it can still be returned to
+                return false;
+            }
+        }
+
+        let Some((frame, state)) = &self.newest else { return false };
+        if nemu.load_state(state).is_err() {
+            self.clear();
+            return false;
+        }
+
+        for _ in *frame..target {
+            nemu.run_frame();
+        }
+
+        self.frame = target;
+        true
+    }
+
+    /// Replaces the newest snapshot with the one before it, false if there is none
+    fn pop_newest(&mut self) -> bool {
+        let Some((_, state)) = &mut self.newest else { return false };
+        let Some(delta) = self.history.pop_back() else { return false };
+
+        self.history_size -= delta.data.len();
+        apply_delta(state, &delta.data);
+        self.newest = Some((delta.frame, std::mem::take(state)));
+        true
+    }
+}
+
+/// Encodes `old ^ new` as (zero run, literal length, literal bytes) triples with LEB128 lengths
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < old.len() {
+        let start = i;
+        while i < old.len() && old[i] == new[i] {
+            i += 1;
+        }
+        let zeros = i - start;
+
+        let start = i;
+        while i < old.len() && old[i] != new[i] {
+            i += 1;
+        }
+
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, i - start);
+        out.extend(old[start..i].iter().zip(&new[start..i]).map(|(a, b)| a ^ b));
+    }
+
+    out
+}
+
+/// XORs an encoded delta into `state`, which turns the newer state back into the older one
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut pos = 0;
+    let mut i = 0;
+
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let len = read_varint(delta, &mut pos);
+
+        for (byte, xor) in state[i..i + len].iter_mut().zip(&delta[pos..pos + len]) {
+            *byte ^= xor;
+        }
+        i += len;
+        pos += len;
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if (byte & 0x80) == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
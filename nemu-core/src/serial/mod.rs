@@ -0,0 +1,207 @@
+mod printer;
+mod tcp;
+
+use crate::NemuError;
+use crate::interrupts::INT_SERIAL;
+use crate::state::{StateReader, StateWriter};
+
+pub use printer::{PrintedImage, Printer, PrinterOutput};
+pub use tcp::TcpLink;
+
+// the internal shift clock (8192 Hz) ticks on the falling edge of bit 8 of the internal divider
+const SERIAL_CLOCK_DIV_BIT: u16 = 1 << 8;
+
+/// Something plugged into the other end of the link port.
+pub trait SerialDevice: Send {
+    /// Called when the Game Boy starts a transfer on its internal clock. Receives the byte
+    /// being shifted out and returns the byte that will be shifted in.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    /// Polled while the Game Boy waits on a transfer with the external clock selected. Receives
+    /// the byte waiting in SB and returns the partner's byte once the partner has started
+    /// clocking the transfer.
+    fn poll_external(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+
+    /// Called when the Game Boy stops waiting on the external clock before a partner clocked the
+    /// transfer, the byte last passed to `poll_external` must not be handed out anymore
+    fn cancel_external(&mut self) {}
+
+    /// Reports a failure that made the device stop responding, like a dropped network link
+    fn take_error(&mut self) -> Option<NemuError> {
+        None
+    }
+}
+
+pub(crate) struct Serial {
+    sb: u8,
+    sc: u8,
+    incoming: u8,
+    bits_left: u8,
+    external_clocked: bool,
+    prev_div_bit: bool,
+    device: Option<Box<dyn SerialDevice>>,
+
+    #[cfg(test)]
+    pub(crate) output: String,
+}
+
+impl Serial {
+    pub(crate) fn new() -> Self {
+        Self {
+            sb: 0,
+            sc: 0,
+            incoming: 0xFF,
+            bits_left: 0,
+            external_clocked: false,
+            prev_div_bit: false,
+            device: None,
+
+            #[cfg(test)]
+            output: String::new(),
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.cancel_external();
+        self.sb = 0;
+        self.sc = 0;
+        self.incoming = 0xFF;
+        self.bits_left = 0;
+        self.external_clocked = false;
+        self.prev_div_bit = false;
+
+        #[cfg(test)]
+        self.output.clear();
+    }
+
+    pub(crate) fn connect(&mut self, device: Option<Box<dyn SerialDevice>>) {
+        self.device = device;
+    }
+
+    pub(crate) fn take_error(&mut self) -> Option<NemuError> {
+        self.device.as_mut().and_then(|device| device.take_error())
+    }
+
+    /// The plugged in device is not part of the state, it stays connected across loads
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u8(self.incoming);
+        w.u8(self.bits_left);
+        w.bool(self.external_clocked);
+        w.bool(self.prev_div_bit);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()?;
+        self.incoming = r.u8()?;
+        self.bits_left = r.u8()?;
+        self.external_clocked = r.bool()?;
+        self.prev_div_bit = r.bool()?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn internal_clock(&self) -> bool {
+        (self.sc & 0x01) != 0
+    }
+
+    fn cancel_external(&mut self) {
+        let waiting = self.bits_left > 0 && !self.internal_clock() && !self.external_clocked;
+        if let (true, Some(device)) = (waiting, &mut self.device) {
+            device.cancel_external();
+        }
+    }
+
+    pub(crate) fn update(&mut self, div: u16) -> u8 {
+        let div_bit = (div & SERIAL_CLOCK_DIV_BIT) != 0;
+        let falling_edge = self.prev_div_bit && !div_bit;
+        self.prev_div_bit = div_bit;
+
+        if self.bits_left == 0 {
+            return 0;
+        }
+
+        if !self.internal_clock() && !self.external_clocked {
+            self.poll_partner();
+        }
+
+        // a partner clocking us runs at the same 8192 Hz, so its edges are approximated with ours
+        if falling_edge && (self.internal_clock() || self.external_clocked) {
+            return self.shift_bit();
+        }
+
+        0
+    }
+
+    fn poll_partner(&mut self) {
+        let sb = self.sb;
+        if let Some(byte) = self.device.as_mut().and_then(|device| device.poll_external(sb)) {
+            self.incoming = byte;
+            self.external_clocked = true;
+        }
+    }
+
+    fn shift_bit(&mut self) -> u8 {
+        self.sb = (self.sb << 1) | (self.incoming >> 7);
+        self.incoming <<= 1;
+        self.bits_left -= 1;
+
+        if self.bits_left == 0 {
+            self.sc &= 0x7F;
+            self.external_clocked = false;
+            return INT_SERIAL;
+        }
+
+        0
+    }
+
+    fn start_transfer(&mut self) {
+        self.bits_left = 8;
+        self.external_clocked = false;
+
+        if !self.internal_clock() {
+            // stays pending until a partner starts clocking it, see `poll_partner`
+            return;
+        }
+
+        #[cfg(test)]
+        self.output.push(self.sb as char);
+
+        self.incoming = match &mut self.device {
+            Some(device) => device.exchange(self.sb),
+            None => 0xFF,
+        };
+    }
+
+    #[inline(always)]
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | 0x7E,
+            _ => 0xFF,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF01 => self.sb = data,
+            0xFF02 => {
+                // rewriting SC ends a transfer still waiting on the partner
+                self.cancel_external();
+                self.sc = data & 0x81;
+                if (data & 0x80) != 0 {
+                    self.start_transfer();
+                } else {
+                    self.bits_left = 0;
+                    self.external_clocked = false;
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::SerialDevice;
+use crate::crc::crc32;
+
+const PRINTER_ID: u8 = 0x81;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0b0000_0001;
+const STATUS_PRINTING: u8 = 0b0000_0010;
+const STATUS_DATA_FULL: u8 = 0b0000_0100;
+const STATUS_UNPROCESSED: u8 = 0b0000_1000;
+
+const PAPER_WIDTH: usize = 160;
+// a DATA packet holds one band of 2 tile rows (20 tiles of 16 bytes each per row)
+const BAND_SIZE: usize = 640;
+const MAX_BANDS: usize = 9;
+// each line feed from the margin nibbles is treated as one band of blank paper
+const FEED_LINES: usize = 16;
+// number of STATUS polls the printer reports busy for after a PRINT
+const PRINT_POLLS: u8 = 4;
+
+const PNG_SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Ack,
+    Status,
+}
+
+/// A finished printout, `pixels` holds one shade (0 = white to 3 = black) per pixel
+pub struct PrintedImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl PrintedImage {
+    /// Encodes the image as an 8-bit grayscale PNG
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity((self.width + 1) * self.height);
+        for row in self.pixels.chunks_exact(self.width) {
+            raw.push(0); // filter type: none
+            raw.extend(row.iter().map(|&shade| PNG_SHADES[(shade & 0x03) as usize]));
+        }
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&(self.width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(self.height as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[8, 0, 0, 0, 0]); // 8-bit grayscale, no interlacing
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        write_png_chunk(&mut png, b"IHDR", &ihdr);
+        write_png_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+        write_png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+/// Handle to the images coming out of a `Printer` after it has been plugged into a `Nemu`
+#[derive(Clone)]
+pub struct PrinterOutput {
+    images: Arc<Mutex<VecDeque<PrintedImage>>>,
+}
+
+impl PrinterOutput {
+    pub fn pop(&self) -> Option<PrintedImage> {
+        self.images.lock().unwrap().pop_front()
+    }
+}
+
+/// Game Boy Printer, speaking the printer packet protocol over the link port.
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    busy_polls: u8,
+
+    bands: Vec<u8>,
+    paper: Vec<u8>,
+    output: PrinterOutput,
+}
+
+impl Printer {
+    pub fn new() -> Self {
+        Self {
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            busy_polls: 0,
+            bands: Vec::with_capacity(BAND_SIZE * MAX_BANDS),
+            paper: Vec::new(),
+            output: PrinterOutput {
+                images: Arc::new(Mutex::new(VecDeque::new())),
+            },
+        }
+    }
+
+    pub fn output(&self) -> PrinterOutput {
+        self.output.clone()
+    }
+
+    fn run_command(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            CMD_INIT => {
+                self.bands.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            CMD_DATA => {
+                let data = std::mem::take(&mut self.data);
+                let data = if self.compressed { decompress(&data) } else { data };
+
+                let room = BAND_SIZE * MAX_BANDS - self.bands.len();
+                self.bands.extend_from_slice(&data[..data.len().min(room)]);
+
+                if !data.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.bands.len() == BAND_SIZE * MAX_BANDS {
+                    self.status |= STATUS_DATA_FULL;
+                }
+            }
+            CMD_PRINT if self.data.len() >= 4 => {
+                let margins = self.data[1];
+                let palette = if self.data[2] == 0 { 0xE4 } else { self.data[2] };
+
+                self.print(margins >> 4, margins & 0x0F, palette);
+                self.status = (self.status & !STATUS_UNPROCESSED) | STATUS_PRINTING | STATUS_DATA_FULL;
+                self.busy_polls = PRINT_POLLS;
+            }
+            CMD_STATUS if self.busy_polls > 0 => {
+                self.busy_polls -= 1;
+                if self.busy_polls == 0 {
+                    self.status &= !(STATUS_PRINTING | STATUS_DATA_FULL);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, margin_before: u8, margin_after: u8, palette: u8) {
+        self.feed(margin_before);
+
+        let tiles_per_row = PAPER_WIDTH / 8;
+        let tile_rows = self.bands.len() / (tiles_per_row * 16);
+
+        for tile_row in 0..tile_rows {
+            for line in 0..8 {
+                for tile in 0..tiles_per_row {
+                    let offset = (tile_row * tiles_per_row + tile) * 16 + line * 2;
+                    let (low, high) = (self.bands[offset], self.bands[offset + 1]);
+
+                    for bit in (0..8).rev() {
+                        let color = (((high >> bit) & 0x01) << 1) | ((low >> bit) & 0x01);
+                        self.paper.push((palette >> (color * 2)) & 0x03);
+                    }
+                }
+            }
+        }
+
+        self.bands.clear();
+        self.feed(margin_after);
+
+        // a print without a trailing margin is continued by the next one on the same sheet
+        if margin_after != 0 && !self.paper.is_empty() {
+            let pixels = std::mem::take(&mut self.paper);
+            self.output.images.lock().unwrap().push_back(PrintedImage {
+                width: PAPER_WIDTH,
+                height: pixels.len() / PAPER_WIDTH,
+                pixels,
+            });
+        }
+    }
+
+    fn feed(&mut self, line_feeds: u8) {
+        let blank = line_feeds as usize * FEED_LINES * PAPER_WIDTH;
+        self.paper.resize(self.paper.len() + blank, 0);
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+
+        self.state = match self.state {
+            State::Magic1 if byte == 0x88 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == 0x33 => State::Command,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = (byte & 0x01) != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 { State::ChecksumLow } else { State::Data }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize { State::ChecksumLow } else { State::Data }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                self.run_command();
+                State::Ack
+            }
+            State::Ack => {
+                reply = PRINTER_ID;
+                State::Status
+            }
+            State::Status => {
+                reply = self.status;
+                State::Magic1
+            }
+        };
+
+        reply
+    }
+}
+
+/// Printer RLE: a control byte with bit 7 set repeats the next byte (n & 0x7F) + 2 times,
+/// otherwise the next n + 1 bytes are copied as they are
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(BAND_SIZE);
+    let mut i = 0;
+
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+
+        if (control & 0x80) != 0 {
+            let Some(&value) = data.get(i) else { break };
+            out.resize(out.len() + (control & 0x7F) as usize + 2, value);
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+
+    out
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+
+    let crc = crc32(kind.iter().chain(data));
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none() as u8;
+        let len = block.len() as u16;
+
+        out.push(last);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
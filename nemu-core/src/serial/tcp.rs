@@ -0,0 +1,265 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::SerialDevice;
+use crate::NemuError;
+
+const HANDSHAKE: [u8; 5] = *b"NEMU\x01";
+
+const MSG_TRANSFER: u8 = 0x01;
+const MSG_REPLY: u8 = 0x02;
+
+// how often a hosting link checks whether it was dropped while no peer has connected yet
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct Shared {
+    writer: Option<TcpStream>,
+    armed: Option<u8>,
+    delivered: Option<u8>,
+    error: Option<String>,
+    // set when the link is dropped, tells the background thread to stop
+    closed: bool,
+}
+
+/// Link cable to another emulator over TCP.
+///
+/// Wire protocol, after both sides send the 5 byte handshake `"NEMU"` followed by the protocol
+/// version (currently 1), every message is exactly two bytes, an opcode and a data byte:
+///
+/// - `0x01 b` TRANSFER: the sender started a transfer on its internal clock and shifts out `b`.
+///   The receiver must answer with a REPLY right away.
+/// - `0x02 b` REPLY: `b` is the SB of the receiving side if it was waiting on the external
+///   clock, or `0xFF` if it was not listening (in which case the TRANSFER byte is dropped).
+///
+/// The side driving the clock blocks on the REPLY, so both emulators agree on every byte. If no
+/// REPLY arrives within the timeout or the socket closes, the link reports a `NemuError::Link`
+/// through `Nemu::take_serial_error` and behaves like an unplugged cable from then on.
+///
+/// That wait happens on the thread running the emulator, so a silent peer stalls it for the
+/// whole timeout once. Keep the timeout short, around a second at most.
+pub struct TcpLink {
+    shared: Arc<Mutex<Shared>>,
+    replies: Receiver<u8>,
+    timeout: Duration,
+    failed: bool,
+    local_addr: Option<SocketAddr>,
+}
+
+impl TcpLink {
+    /// Waits for a peer on `addr` in the background. Transfers before the peer connects behave
+    /// like nothing is plugged in.
+    pub fn host(addr: impl ToSocketAddrs, timeout: Duration) -> Result<Self, NemuError> {
+        let listener = TcpListener::bind(addr).map_err(|e| NemuError::Link(e.to_string()))?;
+        let (mut link, shared, reply_tx) = Self::new(timeout);
+        link.local_addr = listener.local_addr().ok();
+
+        // polled rather than blocking in accept, so dropping the link frees the port even if
+        // nobody ever connects
+        listener.set_nonblocking(true).map_err(|e| NemuError::Link(e.to_string()))?;
+
+        std::thread::spawn(move || {
+            let result = loop {
+                if shared.lock().unwrap().closed {
+                    return;
+                }
+
+                match listener.accept() {
+                    Ok((stream, _)) => break stream.set_nonblocking(false).map(|_| stream),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL),
+                    Err(e) => break Err(e),
+                }
+            };
+
+            let result = result
+                .map_err(|e| e.to_string())
+                .and_then(|stream| run_connection(stream, timeout, &shared, &reply_tx));
+
+            if let Err(e) = result {
+                shared.lock().unwrap().error.get_or_insert(e);
+            }
+        });
+
+        Ok(link)
+    }
+
+    /// Connects to a peer that is hosting on `addr`
+    pub fn connect(addr: impl ToSocketAddrs, timeout: Duration) -> Result<Self, NemuError> {
+        let addr = addr
+            .to_socket_addrs()
+            .map_err(|e| NemuError::Link(e.to_string()))?
+            .next()
+            .ok_or_else(|| NemuError::Link("no address to connect to".to_string()))?;
+
+        let stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| NemuError::Link(e.to_string()))?;
+        let (link, shared, reply_tx) = Self::new(timeout);
+
+        std::thread::spawn(move || {
+            if let Err(e) = run_connection(stream, timeout, &shared, &reply_tx) {
+                shared.lock().unwrap().error.get_or_insert(e);
+            }
+        });
+
+        Ok(link)
+    }
+
+    fn new(timeout: Duration) -> (Self, Arc<Mutex<Shared>>, Sender<u8>) {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let (reply_tx, replies) = mpsc::channel();
+
+        let link = Self {
+            shared: shared.clone(),
+            replies,
+            timeout,
+            failed: false,
+            local_addr: None,
+        };
+
+        (link, shared, reply_tx)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        let shared = self.shared.lock().unwrap();
+        shared.writer.is_some() && shared.error.is_none()
+    }
+
+    /// Address a hosting link is listening on, useful when binding to port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    fn fail(&mut self, error: String) -> u8 {
+        self.shared.lock().unwrap().error.get_or_insert(error);
+        self.failed = true;
+        0xFF
+    }
+}
+
+impl Drop for TcpLink {
+    fn drop(&mut self) {
+        // unblocks the connection thread so it can exit
+        let mut shared = self.shared.lock().unwrap();
+        shared.closed = true;
+        if let Some(writer) = &shared.writer {
+            let _ = writer.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        if self.failed {
+            return 0xFF;
+        }
+
+        let sent = {
+            let mut shared = self.shared.lock().unwrap();
+
+            if let Some(error) = &shared.error {
+                let error = error.clone();
+                drop(shared);
+                return self.fail(error);
+            }
+
+            match &mut shared.writer {
+                Some(writer) => writer.write_all(&[MSG_TRANSFER, byte]).map_err(|e| e.to_string()),
+                None => return 0xFF, // peer has not connected yet
+            }
+        };
+
+        if let Err(e) = sent {
+            return self.fail(e);
+        }
+
+        match self.replies.recv_timeout(self.timeout) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Timeout) => self.fail("timed out waiting for the link partner".to_string()),
+            Err(RecvTimeoutError::Disconnected) => self.fail("link partner disconnected".to_string()),
+        }
+    }
+
+    fn poll_external(&mut self, byte: u8) -> Option<u8> {
+        let mut shared = self.shared.lock().unwrap();
+
+        match shared.delivered.take() {
+            Some(partner_byte) => Some(partner_byte),
+            None => {
+                shared.armed = Some(byte);
+                None
+            }
+        }
+    }
+
+    fn cancel_external(&mut self) {
+        self.shared.lock().unwrap().armed = None;
+    }
+
+    fn take_error(&mut self) -> Option<NemuError> {
+        let error = self.shared.lock().unwrap().error.take()?;
+        self.failed = true;
+        Some(NemuError::Link(error))
+    }
+}
+
+fn run_connection(
+    mut stream: TcpStream,
+    timeout: Duration,
+    shared: &Mutex<Shared>,
+    reply_tx: &Sender<u8>,
+) -> Result<(), String> {
+    let io_err = |e: std::io::Error| e.to_string();
+
+    stream.set_nodelay(true).map_err(io_err)?;
+    stream.set_read_timeout(Some(timeout)).map_err(io_err)?;
+    stream.write_all(&HANDSHAKE).map_err(io_err)?;
+
+    let mut handshake = [0; 5];
+    stream.read_exact(&mut handshake).map_err(io_err)?;
+    if handshake != HANDSHAKE {
+        return Err("link partner sent an invalid handshake".to_string());
+    }
+
+    // the partner may sit idle for as long as it likes once connected
+    stream.set_read_timeout(None).map_err(io_err)?;
+    {
+        // the link may have been dropped during the handshake
+        let mut shared = shared.lock().unwrap();
+        if shared.closed {
+            return Ok(());
+        }
+        shared.writer = Some(stream.try_clone().map_err(io_err)?);
+    }
+
+    let mut msg = [0; 2];
+    loop {
+        if stream.read_exact(&mut msg).is_err() {
+            return Err("link partner disconnected".to_string());
+        }
+
+        match msg {
+            [MSG_TRANSFER, byte] => {
+                let mut shared = shared.lock().unwrap();
+                let reply = match shared.armed.take() {
+                    Some(sb) => {
+                        shared.delivered = Some(byte);
+                        sb
+                    }
+                    None => 0xFF,
+                };
+
+                // writes go through the shared writer so they never interleave with a TRANSFER
+                if let Some(writer) = &mut shared.writer {
+                    writer.write_all(&[MSG_REPLY, reply]).map_err(io_err)?;
+                }
+            }
+            [MSG_REPLY, byte] => {
+                // the receiving end only disappears when the link itself is dropped
+                let _ = reply_tx.send(byte);
+            }
+            [op, _] => return Err(format!("unknown link message {:#04X}", op)),
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use crate::NemuError;
+use crate::state::{StateReader, StateWriter};
+
+pub(crate) const SGB_WIDTH: usize = 256;
+pub(crate) const SGB_HEIGHT: usize = 224;
+
+// the LCD image sits in the middle of the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+const SCREEN_WIDTH: usize = 160;
+const SCREEN_HEIGHT: usize = 144;
+
+// palette numbers per 8x8 cell of the LCD
+const ATTR_WIDTH: usize = 20;
+const ATTR_HEIGHT: usize = 18;
+
+const PACKET_SIZE: usize = 16;
+const MAX_PACKETS: usize = 7;
+
+const CMD_PAL01: u8 = 0x00;
+const CMD_PAL23: u8 = 0x01;
+const CMD_PAL03: u8 = 0x02;
+const CMD_PAL12: u8 = 0x03;
+const CMD_ATTR_BLK: u8 = 0x04;
+const CMD_ATTR_LIN: u8 = 0x05;
+const CMD_ATTR_DIV: u8 = 0x06;
+const CMD_ATTR_CHR: u8 = 0x07;
+const CMD_MLT_REQ: u8 = 0x11;
+const CMD_CHR_TRN: u8 = 0x13;
+const CMD_PCT_TRN: u8 = 0x14;
+const CMD_MASK_EN: u8 = 0x17;
+
+// what the SNES shows before the game sends any palette
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mask {
+    Off,
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    None,
+    // which half of the 256 border tiles gets loaded
+    Chr(u8),
+    Pct,
+}
+
+/// Super Game Boy side of the console. Command packets arrive bit by bit through P14/P15 writes,
+/// VRAM transfers and the composite frame are handled at the start of VBlank.
+pub(crate) struct Sgb {
+    // packet receiver
+    receiving: bool,
+    bits: usize,
+    packet: [u8; PACKET_SIZE],
+    command: Vec<u8>,
+    prev_select: u8,
+
+    palettes: [[u16; 4]; 4],
+    attributes: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+    mask: Mask,
+    transfer: Transfer,
+
+    // MLT_REQ
+    players: u8,
+    player: u8,
+
+    border_tiles: Box<[u8; 0x2000]>,
+    border_map: Box<[u8; 0x800]>,
+    border_palettes: [[u16; 16]; 4],
+
+    pub(crate) frame: Box<[u16; SGB_WIDTH * SGB_HEIGHT]>,
+}
+
+impl Sgb {
+    pub(crate) fn new() -> Self {
+        Self {
+            receiving: false,
+            bits: 0,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            prev_select: 0x30,
+            palettes: [DEFAULT_PALETTE; 4],
+            attributes: [0; ATTR_WIDTH * ATTR_HEIGHT],
+            mask: Mask::Off,
+            transfer: Transfer::None,
+            players: 1,
+            player: 0,
+            border_tiles: Box::new([0; 0x2000]),
+            border_map: Box::new([0; 0x800]),
+            border_palettes: [[0; 16]; 4],
+            frame: Box::new([0; SGB_WIDTH * SGB_HEIGHT]),
+        }
+    }
+
+    /// Joypad ID returned while both select lines are high and MLT_REQ has more than one player
+    pub(crate) fn joypad_id(&self) -> Option<u8> {
+        (self.players > 1).then_some(0x0F - self.player)
+    }
+
+    /// Only the first player is connected, the others never hold a button
+    pub(crate) fn reading_player1(&self) -> bool {
+        self.player == 0
+    }
+
+    /// P1 writes, a pulse with both lines low starts a packet, then P15 low sends a 1 and P14 low a 0
+    pub(crate) fn write(&mut self, select: u8) {
+        let prev = self.prev_select;
+        self.prev_select = select;
+
+        match select {
+            0x00 => {
+                self.receiving = true;
+                self.bits = 0;
+                self.packet = [0; PACKET_SIZE];
+            }
+            // P15 going back high moves on to the next controller
+            0x30 if (prev & 0x20) == 0 && self.players > 1 => {
+                self.player = (self.player + 1) % self.players;
+            }
+            0x10 | 0x20 if prev == 0x30 && self.receiving => {
+                let bit = select == 0x10;
+                self.receive_bit(bit);
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_bit(&mut self, bit: bool) {
+        if self.bits < PACKET_SIZE * 8 {
+            if bit {
+                self.packet[self.bits / 8] |= 1 << (self.bits % 8);
+            }
+            self.bits += 1;
+            return;
+        }
+
+        // the 129th bit is the stop bit, which has to be 0
+        self.receiving = false;
+        if bit {
+            self.command.clear();
+            return;
+        }
+
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() >= packets * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.run_command(&command);
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            CMD_PAL01 => self.set_palettes(data, 0, 1),
+            CMD_PAL23 => self.set_palettes(data, 2, 3),
+            CMD_PAL03 => self.set_palettes(data, 0, 3),
+            CMD_PAL12 => self.set_palettes(data, 1, 2),
+            CMD_ATTR_BLK => self.attr_blk(data),
+            CMD_ATTR_LIN => self.attr_lin(data),
+            CMD_ATTR_DIV => self.attr_div(data),
+            CMD_ATTR_CHR => self.attr_chr(data),
+            CMD_MLT_REQ => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            CMD_CHR_TRN => self.transfer = Transfer::Chr(data[1] & 0x01),
+            CMD_PCT_TRN => self.transfer = Transfer::Pct,
+            CMD_MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    0 => Mask::Off,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                };
+            }
+            // sound, SNES code uploads and the like have nothing to act on
+            _ => {}
+        }
+    }
+
+    fn set_palettes(&mut self, data: &[u8], first: usize, second: usize) {
+        let color = |index: usize| u16::from_le_bytes([data[1 + index * 2], data[2 + index * 2]]) & 0x7FFF;
+
+        // color 0 is shared by every palette
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets = (data[1] & 0x1F) as usize;
+
+        for set in data[2..].chunks_exact(6).take(sets) {
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            // setting only the inside or only the outside gives the border the same palette
+            let border = match control {
+                0x01 => inside,
+                0x04 => outside,
+                _ => (set[1] >> 2) & 0x03,
+            };
+
+            let (x1, y1) = ((set[2] & 0x1F) as usize, (set[3] & 0x1F) as usize);
+            let (x2, y2) = ((set[4] & 0x1F) as usize, (set[5] & 0x1F) as usize);
+
+            for y in 0..ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    let inside_x = x > x1 && x < x2;
+                    let inside_y = y > y1 && y < y2;
+                    let on_x = x >= x1 && x <= x2;
+                    let on_y = y >= y1 && y <= y2;
+
+                    let palette = if inside_x && inside_y {
+                        (control & 0x01 != 0).then_some(inside)
+                    } else if on_x && on_y {
+                        (control & 0x03 != 0).then_some(border)
+                    } else {
+                        (control & 0x04 != 0).then_some(outside)
+                    };
+
+                    if let Some(palette) = palette {
+                        self.attributes[y * ATTR_WIDTH + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let sets = data[1] as usize;
+
+        for &line in data[2..].iter().take(sets) {
+            let index = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+
+            if (line & 0x80) != 0 {
+                if index < ATTR_HEIGHT {
+                    self.attributes[index * ATTR_WIDTH..(index + 1) * ATTR_WIDTH].fill(palette);
+                }
+            } else if index < ATTR_WIDTH {
+                for y in 0..ATTR_HEIGHT {
+                    self.attributes[y * ATTR_WIDTH + index] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = (data[1] & 0x40) != 0;
+        let split = (data[2] & 0x1F) as usize;
+
+        for y in 0..ATTR_HEIGHT {
+            for x in 0..ATTR_WIDTH {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * ATTR_WIDTH + x] = match position.cmp(&split) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = ((data[1] as usize).min(ATTR_WIDTH - 1), (data[2] as usize).min(ATTR_HEIGHT - 1));
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(ATTR_WIDTH * ATTR_HEIGHT);
+        let vertical = data[5] != 0;
+
+        for i in 0..count {
+            let Some(&byte) = data.get(6 + i / 4) else { break };
+            self.attributes[y * ATTR_WIDTH + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+
+            if vertical {
+                y += 1;
+                if y == ATTR_HEIGHT {
+                    y = 0;
+                    x = (x + 1) % ATTR_WIDTH;
+                }
+            } else {
+                x += 1;
+                if x == ATTR_WIDTH {
+                    x = 0;
+                    y = (y + 1) % ATTR_HEIGHT;
+                }
+            }
+        }
+    }
+
+    /// Runs at the start of VBlank with the tile data the LCD is showing and the finished LCD image
+    /// in shades. Transfers copy the tile data, which is where games put the 4KB they send, instead
+    /// of reading it back off the screen.
+    pub(crate) fn vblank(&mut self, tile_data: &[u8], shades: &[u8; SCREEN_WIDTH * SCREEN_HEIGHT]) {
+        match std::mem::replace(&mut self.transfer, Transfer::None) {
+            Transfer::None => {}
+            Transfer::Chr(half) => {
+                let start = half as usize * 0x1000;
+                self.border_tiles[start..start + 0x1000].copy_from_slice(&tile_data[..0x1000]);
+            }
+            Transfer::Pct => {
+                self.border_map.copy_from_slice(&tile_data[..0x800]);
+                for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (j, color) in palette.iter_mut().enumerate() {
+                        let index = 0x800 + (i * 16 + j) * 2;
+                        *color = u16::from_le_bytes([tile_data[index], tile_data[index + 1]]) & 0x7FFF;
+                    }
+                }
+            }
+        }
+
+        self.compose(shades);
+    }
+
+    fn compose(&mut self, shades: &[u8; SCREEN_WIDTH * SCREEN_HEIGHT]) {
+        let backdrop = self.palettes[0][0];
+
+        for tile_y in 0..SGB_HEIGHT / 8 {
+            for tile_x in 0..SGB_WIDTH / 8 {
+                let index = (tile_y * 32 + tile_x) * 2;
+                let entry = u16::from_le_bytes([self.border_map[index], self.border_map[index + 1]]);
+
+                let tile = &self.border_tiles[(entry & 0xFF) as usize * 32..][..32];
+                // border tiles can only use palettes 4-7
+                let palette = ((entry >> 10) & 0x07).saturating_sub(4).min(3) as usize;
+                let x_flip = (entry & 0x4000) != 0;
+                let y_flip = (entry & 0x8000) != 0;
+
+                for row in 0..8 {
+                    let line = if y_flip { 7 - row } else { row };
+                    let planes = [tile[line * 2], tile[line * 2 + 1], tile[16 + line * 2], tile[17 + line * 2]];
+
+                    for column in 0..8 {
+                        let bit = if x_flip { column } else { 7 - column };
+                        let color = planes
+                            .iter()
+                            .enumerate()
+                            .fold(0, |color, (plane, &byte)| color | (((byte >> bit) & 0x01) << plane));
+
+                        self.frame[(tile_y * 8 + row) * SGB_WIDTH + tile_x * 8 + column] = match color {
+                            0 => backdrop,
+                            _ => self.border_palettes[palette][color as usize],
+                        };
+                    }
+                }
+            }
+        }
+
+        // while frozen the screen area keeps what it showed when MASK_EN came in
+        if self.mask == Mask::Freeze {
+            return;
+        }
+
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let pixel = &mut self.frame[(SCREEN_Y + y) * SGB_WIDTH + SCREEN_X + x];
+                *pixel = match self.mask {
+                    Mask::Off | Mask::Freeze => {
+                        let palette = self.attributes[(y / 8) * ATTR_WIDTH + x / 8] as usize;
+                        self.palettes[palette][(shades[y * SCREEN_WIDTH + x] & 0x03) as usize]
+                    }
+                    Mask::Black => 0x0000,
+                    Mask::Color0 => backdrop,
+                };
+            }
+        }
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.receiving);
+        w.u16(self.bits as u16);
+        w.bytes(&self.packet);
+        w.u32(self.command.len() as u32);
+        w.bytes(&self.command);
+        w.u8(self.prev_select);
+
+        for color in self.palettes.iter().flatten() {
+            w.u16(*color);
+        }
+        w.bytes(&self.attributes);
+        w.u8(self.mask as u8);
+        w.u8(match self.transfer {
+            Transfer::None => 0,
+            Transfer::Chr(half) => 1 + half,
+            Transfer::Pct => 3,
+        });
+
+        w.u8(self.players);
+        w.u8(self.player);
+
+        w.bytes(&self.border_tiles[..]);
+        w.bytes(&self.border_map[..]);
+        for color in self.border_palettes.iter().flatten() {
+            w.u16(*color);
+        }
+        for &color in self.frame.iter() {
+            w.u16(color);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.receiving = r.bool()?;
+        self.bits = (r.u16()? as usize).min(PACKET_SIZE * 8);
+        r.bytes(&mut self.packet)?;
+        // a command is at most 7 packets, checked before allocating anything for it
+        let len = r.u32()? as usize;
+        if len > MAX_PACKETS * PACKET_SIZE {
+            return Err(NemuError::InvalidState(format!("SGB command of {} bytes is too long", len)));
+        }
+        self.command = vec![0; len];
+        r.bytes(&mut self.command)?;
+        self.prev_select = r.u8()?;
+
+        for color in self.palettes.iter_mut().flatten() {
+            *color = r.u16()?;
+        }
+        r.bytes(&mut self.attributes)?;
+        self.mask = match r.u8()? {
+            0 => Mask::Off,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            mask => return Err(NemuError::InvalidState(format!("Invalid SGB mask: {}", mask))),
+        };
+        self.transfer = match r.u8()? {
+            0 => Transfer::None,
+            1 => Transfer::Chr(0),
+            2 => Transfer::Chr(1),
+            3 => Transfer::Pct,
+            transfer => return Err(NemuError::InvalidState(format!("Invalid SGB transfer: {}", transfer))),
+        };
+
+        self.players = r.u8()?.clamp(1, 4);
+        self.player = r.u8()? % self.players;
+
+        r.bytes(&mut self.border_tiles[..])?;
+        r.bytes(&mut self.border_map[..])?;
+        for color in self.border_palettes.iter_mut().flatten() {
+            *color = r.u16()?;
+        }
+        for color in self.frame.iter_mut() {
+            *color = r.u16()?;
+        }
+        Ok(())
+    }
+}
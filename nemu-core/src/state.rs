@@ -0,0 +1,113 @@
+use crate::NemuError;
+
+pub(crate) const STATE_MAGIC: [u8; 4] = *b"NEMS";
+/// Bump whenever the layout changes, and teach the `load_state` methods to read the older one.
+/// 1: initial layout, 2: CGB banks, palettes, KEY1 and HDMA, 3: SGB, 4: pixel FIFO,
+/// 5: STAT interrupt line, 6: OAM DMA progress, 7: HALT bug, 8: PPU lockouts
+pub(crate) const STATE_VERSION: u16 = 8;
+
+/// Little endian, append only serializer for save states. Byte arrays carry a length prefix so
+/// a layout mismatch is caught instead of silently shifting every field after it.
+pub(crate) struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub(crate) fn bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn i32(&mut self, value: i32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value);
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    version: u16,
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(data: &'a [u8], version: u16) -> Self {
+        Self { data, pos: 0, version }
+    }
+
+    /// Format version of the state being read, for fields that only exist in newer versions
+    pub(crate) fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], NemuError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| NemuError::InvalidState("Save state is truncated".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, NemuError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, NemuError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, NemuError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, NemuError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn i32(&mut self) -> Result<i32, NemuError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Fills `out` with a length prefixed byte array, which has to be exactly as long
+    pub(crate) fn bytes(&mut self, out: &mut [u8]) -> Result<(), NemuError> {
+        let len = self.u32()? as usize;
+        if len != out.len() {
+            return Err(NemuError::InvalidState(format!(
+                "Expected a block of {} bytes, found {}",
+                out.len(),
+                len
+            )));
+        }
+
+        out.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
+use crate::NemuError;
 use crate::interrupts::INT_TIMER;
+use crate::state::{StateReader, StateWriter};
 
 pub struct Timer {
     tima: u8,
@@ -27,6 +29,23 @@ impl Timer {
         self.overflow_cycles = 0;
     }
 
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u16(self.div);
+        w.u8(self.overflow_cycles);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), NemuError> {
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()?;
+        self.div = r.u16()?;
+        self.overflow_cycles = r.u8()?;
+        Ok(())
+    }
+
     pub(crate) fn update(&mut self, cycles: u8) -> u8 {
         let mut irq_mask = 0;
 
@@ -54,6 +73,11 @@ impl Timer {
         irq_mask
     }
 
+    #[inline(always)]
+    pub(crate) fn div(&self) -> u16 {
+        self.div
+    }
+
     fn increment_tima(&mut self) {
         if self.tima == 0xFF {
             self.tima = 0;